@@ -3,7 +3,269 @@ use bevy::{
     prelude::{AddAsset, App},
     reflect::{TypePath, TypeUuid},
 };
+use glam::IVec2;
 use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use crate::{Attitude, LightLevel};
+
+/// Dice expression such as `2d6+3`, written as a string in statblocks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DiceExpr {
+    pub count: u32,
+    pub sides: u32,
+    pub bonus: i32,
+}
+
+impl FromStr for DiceExpr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+        // a leading sign belongs to a flat number, not to the bonus
+        let split = s
+            .char_indices()
+            .skip(1)
+            .find(|(_, c)| *c == '+' || *c == '-')
+            .map(|(i, _)| i);
+        let (dice, bonus) = match split {
+            Some(i) => (&s[..i], &s[i..]),
+            None => (s.as_str(), ""),
+        };
+        let bonus = match bonus {
+            "" => 0,
+            bonus => bonus
+                .trim_start_matches('+')
+                .parse::<i32>()
+                .map_err(|err| format!("invalid dice bonus in '{}': {}", s, err))?,
+        };
+        let Some((count, sides)) = dice.split_once('d') else {
+            // a flat number such as "1"
+            let bonus = dice.parse::<i32>().map_err(|err| format!("invalid dice '{}': {}", s, err))?;
+            return Ok(Self { count: 0, sides: 0, bonus });
+        };
+        let count = match count {
+            "" => 1,
+            count => count.parse::<u32>().map_err(|err| format!("invalid dice count in '{}': {}", s, err))?,
+        };
+        let sides = sides.parse::<u32>().map_err(|err| format!("invalid dice sides in '{}': {}", s, err))?;
+        Ok(Self { count, sides, bonus })
+    }
+}
+
+impl Display for DiceExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.count == 0 {
+            return write!(f, "{}", self.bonus);
+        }
+        write!(f, "{}d{}", self.count, self.sides)?;
+        if self.bonus != 0 {
+            write!(f, "{:+}", self.bonus)?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for DiceExpr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<DiceExpr> for String {
+    fn from(value: DiceExpr) -> Self {
+        value.to_string()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Ability {
+    Strength,
+    Dexterity,
+    Constitution,
+    Intelligence,
+    Wisdom,
+    Charisma,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Skill {
+    Acrobatics,
+    AnimalHandling,
+    Arcana,
+    Athletics,
+    Deception,
+    History,
+    Insight,
+    Intimidation,
+    Investigation,
+    Medicine,
+    Nature,
+    Perception,
+    Performance,
+    Persuasion,
+    Religion,
+    SleightOfHand,
+    Stealth,
+    Survival,
+}
+
+impl Skill {
+    pub fn ability(&self) -> Ability {
+        match self {
+            Skill::Athletics => Ability::Strength,
+            Skill::Acrobatics | Skill::SleightOfHand | Skill::Stealth => Ability::Dexterity,
+            Skill::Arcana
+            | Skill::History
+            | Skill::Investigation
+            | Skill::Nature
+            | Skill::Religion => Ability::Intelligence,
+            Skill::AnimalHandling
+            | Skill::Insight
+            | Skill::Medicine
+            | Skill::Perception
+            | Skill::Survival => Ability::Wisdom,
+            Skill::Deception | Skill::Intimidation | Skill::Performance | Skill::Persuasion => {
+                Ability::Charisma
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Proficiency {
+    #[default]
+    None,
+    Proficient,
+    Expertise,
+}
+
+impl Proficiency {
+    pub fn bonus(&self, proficiency_bonus: i32) -> i32 {
+        match self {
+            Proficiency::None => 0,
+            Proficiency::Proficient => proficiency_bonus,
+            Proficiency::Expertise => proficiency_bonus * 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tool {
+    ThievesTools,
+}
+
+impl Tool {
+    pub fn ability(&self) -> Ability {
+        match self {
+            Tool::ThievesTools => Ability::Dexterity,
+        }
+    }
+}
+
+/// An ability check, made with a skill or tool when one applies.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    Ability(Ability),
+    Skill(Skill),
+    Tool(Tool),
+}
+
+impl Check {
+    pub fn ability(&self) -> Ability {
+        match self {
+            Check::Ability(ability) => *ability,
+            Check::Skill(skill) => skill.ability(),
+            Check::Tool(tool) => tool.ability(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Abilities {
+    pub strength: i32,
+    pub dexterity: i32,
+    pub constitution: i32,
+    pub intelligence: i32,
+    pub wisdom: i32,
+    pub charisma: i32,
+}
+
+impl Abilities {
+    pub fn get(&self, ability: Ability) -> i32 {
+        match ability {
+            Ability::Strength => self.strength,
+            Ability::Dexterity => self.dexterity,
+            Ability::Constitution => self.constitution,
+            Ability::Intelligence => self.intelligence,
+            Ability::Wisdom => self.wisdom,
+            Ability::Charisma => self.charisma,
+        }
+    }
+}
+
+impl Default for Abilities {
+    fn default() -> Self {
+        Self {
+            strength: 10,
+            dexterity: 10,
+            constitution: 10,
+            intelligence: 10,
+            wisdom: 10,
+            charisma: 10,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Recharge {
+    ShortRest,
+    LongRest,
+}
+
+/// A resource with a limited number of uses, such as a class feature or a magic item charge.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LimitedUse {
+    pub name: String,
+    pub uses: u32,
+    pub recharge: Recharge,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Attack {
+    pub name: String,
+    pub to_hit: i32,
+    pub damage: DiceExpr,
+    #[serde(default = "default_reach_ft")]
+    pub reach_ft: u32,
+    /// Range of a ranged attack, which needs line of sight. Melee attacks have none.
+    #[serde(default)]
+    pub range_ft: u32,
+    /// Item used up by each attack, such as an arrow or the thrown weapon itself.
+    #[serde(default)]
+    pub uses_item: Option<String>,
+    /// Properties of the weapon the attack is made with.
+    #[serde(default)]
+    pub properties: Vec<crate::WeaponProperty>,
+}
+
+/// Sheds bright light out to `bright_ft` and dim light for another `dim_ft` beyond that.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct LightSource {
+    pub bright_ft: u32,
+    pub dim_ft: u32,
+}
+
 #[derive(TypeUuid, TypePath, Serialize, Deserialize)]
 #[uuid = "f175d5c6-4275-4e40-9105-016d4d0001c1"]
 pub struct Statblock {
@@ -12,7 +274,282 @@ pub struct Statblock {
     #[serde(default)]
     pub speed: u32,
     #[serde(default)]
-    pub hit_points:u32
+    pub hit_points:u32,
+    #[serde(default)]
+    pub abilities: Abilities,
+    /// Number of sides of the hit die, e.g. 8 for a d8.
+    #[serde(default = "default_hit_die")]
+    pub hit_die: u32,
+    /// Total number of hit dice, usually equal to the level.
+    #[serde(default)]
+    pub hit_dice: u32,
+    /// Number of spell slots per spell level, starting at 1st level.
+    #[serde(default)]
+    pub spell_slots: Vec<u32>,
+    #[serde(default)]
+    pub limited_uses: Vec<LimitedUse>,
+    #[serde(default = "default_proficiency_bonus")]
+    pub proficiency_bonus: i32,
+    /// Saving throws the proficiency bonus is added to.
+    #[serde(default)]
+    pub saving_throws: Vec<Ability>,
+    #[serde(default)]
+    pub skills: HashMap<Skill, Proficiency>,
+    #[serde(default)]
+    pub tools: HashMap<Tool, Proficiency>,
+    /// AC when wearing neither armor nor a shield, e.g. natural armor.
+    #[serde(default = "default_armor_class")]
+    pub armor_class: i32,
+    #[serde(default = "default_actions")]
+    pub actions: u32,
+    #[serde(default = "default_actions")]
+    pub bonus_actions: u32,
+    #[serde(default)]
+    pub attacks: Vec<Attack>,
+    /// Ids of the features in `features/`, e.g. class features.
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// How far the creature sees, in feet.
+    #[serde(default = "default_vision_ft")]
+    pub vision_ft: u32,
+    /// Range within which darkness counts as dim light and dim light as bright.
+    #[serde(default)]
+    pub darkvision_ft: u32,
+    /// Light the creature carries, e.g. a torch.
+    #[serde(default)]
+    pub light: Option<LightSource>,
+    /// Gear the creature starts with. Its AC and weapon attacks come from what is equipped.
+    #[serde(default)]
+    pub inventory: Vec<crate::ItemStack>,
+    /// Written as a decimal, e.g. 0.25 for CR 1/4. Decides the XP the creature is worth,
+    /// player characters have none and are worth no XP.
+    #[serde(default)]
+    pub challenge_rating: Option<f32>,
+}
+
+impl Statblock {
+    pub fn skill_proficiency(&self, skill: Skill) -> Proficiency {
+        self.skills.get(&skill).copied().unwrap_or_default()
+    }
+
+    pub fn tool_proficiency(&self, tool: Tool) -> Proficiency {
+        self.tools.get(&tool).copied().unwrap_or_default()
+    }
+}
+
+fn default_hit_die() -> u32 {
+    8
+}
+
+fn default_proficiency_bonus() -> i32 {
+    2
+}
+
+fn default_armor_class() -> i32 {
+    10
+}
+
+fn default_actions() -> u32 {
+    1
+}
+
+fn default_reach_ft() -> u32 {
+    5
+}
+
+fn default_vision_ft() -> u32 {
+    60
+}
+
+/// Preset chains of generators for procedural maps.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MapStyle {
+    /// Rooms joined by corridors.
+    #[default]
+    Dungeon,
+    /// Random noise smoothed into caverns.
+    Cave,
+}
+
+/// One step of a procedural map generator, applied in order to the map so far.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MapStep {
+    /// Non-overlapping rooms, more of them the higher the density.
+    Rooms,
+    BspRooms,
+    BspInterior,
+    NearestCorridors,
+    /// Opens random cells, as many as the density.
+    Noise,
+    CellularAutomata,
+    DrunkardsWalk,
+    Maze,
+    Voronoi,
+}
+
+fn default_map_width() -> usize {
+    64
+}
+
+fn default_map_height() -> usize {
+    64
+}
+
+fn default_map_density() -> f32 {
+    0.5
+}
+
+fn default_map_floors() -> usize {
+    1
+}
+
+/// How to generate a map. Every generated map starts in its top left corner, with the cells
+/// that can't be reached from there walled off.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MapgenConfig {
+    /// Picked at random if not given. The seed that was used is kept in `GeneratedMap`.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default = "default_map_width")]
+    pub width: usize,
+    #[serde(default = "default_map_height")]
+    pub height: usize,
+    #[serde(default)]
+    pub style: MapStyle,
+    /// How open the map is, from 0 to 1. At 0.5 a 64 by 64 dungeon tries to fit 32 rooms, and
+    /// caves with half of their cells open before smoothing.
+    #[serde(default = "default_map_density")]
+    pub density: f32,
+    /// Replaces the chain of the style when given.
+    #[serde(default)]
+    pub steps: Vec<MapStep>,
+    /// Each floor is generated from the next seed, with stairs down at the cell farthest from
+    /// its start leading to the start of the next one.
+    #[serde(default = "default_map_floors")]
+    pub floors: usize,
+}
+
+impl MapgenConfig {
+    pub fn steps(&self) -> Vec<MapStep> {
+        if !self.steps.is_empty() {
+            return self.steps.clone();
+        }
+        match self.style {
+            MapStyle::Dungeon => vec![MapStep::Rooms, MapStep::NearestCorridors],
+            MapStyle::Cave => vec![MapStep::Noise, MapStep::CellularAutomata],
+        }
+    }
+}
+
+/// Where the map of an encounter comes from.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MapSource {
+    /// Generated with mapgen.
+    Mapgen(MapgenConfig),
+    /// A Tiled `.tmj` or `.tmx` file or an ASCII `.txt` map in the assets folder, starting at
+    /// the spawn named `start`.
+    File { path: String },
+}
+
+/// Where a creature is placed. A taken cell falls back to the closest free one.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Spawn {
+    Cell(IVec2),
+    /// Offset from the starting point of the map.
+    Start(IVec2),
+    /// Any free cell in the rectangle, corners included.
+    Zone { min: IVec2, max: IVec2 },
+    /// Any free spawn point of the map with the given name.
+    Marker(String),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FactionRelation {
+    pub a: String,
+    pub b: String,
+    pub attitude: Attitude,
+}
+
+/// Same as `Objective`, with creatures referred to by name.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ObjectiveDef {
+    DefeatAllHostiles,
+    SurviveRounds { rounds: u64 },
+    ReachCell { cell: IVec2 },
+    Protect { creature: String },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CreatureDef {
+    pub name: String,
+    #[serde(default)]
+    pub statblock: String,
+    /// Player character whose sheet in `characters/` gives the statblock instead.
+    #[serde(default)]
+    pub character: Option<String>,
+    /// Defaults to `token_<statblock>`, or `token_<character>`.
+    #[serde(default)]
+    pub image: String,
+    pub faction: String,
+    /// Controlled by the local player rather than the AI.
+    #[serde(default)]
+    pub player: bool,
+    #[serde(default)]
+    pub sneaking: bool,
+    pub spawn: Spawn,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Difficulty {
+    Easy,
+    #[default]
+    Medium,
+    Hard,
+    Deadly,
+}
+
+/// Monsters drawn at random and placed in the rooms of the map, as many as the XP budget of
+/// the party allows for the difficulty.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PopulationDef {
+    #[serde(default)]
+    pub difficulty: Difficulty,
+    pub faction: String,
+    /// Statblocks to draw from, every one in `statblocks/` that is worth XP if empty.
+    #[serde(default)]
+    pub statblocks: Vec<String>,
+    #[serde(default)]
+    pub sneaking: bool,
+}
+
+#[derive(TypeUuid, TypePath, Clone, Serialize, Deserialize)]
+#[uuid = "3b0f5a0e-8c4d-4f6e-9a51-2d7c1e9b6a42"]
+pub struct EncounterDef {
+    #[serde(default)]
+    pub name: String,
+    pub map: MapSource,
+    /// Seed for placing creatures in spawn zones.
+    #[serde(default)]
+    pub seed: u64,
+    /// The side the objectives are seen from.
+    pub faction: String,
+    #[serde(default)]
+    pub factions: Vec<FactionRelation>,
+    #[serde(default)]
+    pub objectives: Vec<ObjectiveDef>,
+    #[serde(default)]
+    pub creatures: Vec<CreatureDef>,
+    #[serde(default)]
+    pub population: Option<PopulationDef>,
+    /// Light where no light source reaches, dark for dungeons.
+    #[serde(default)]
+    pub light: LightLevel,
 }
 
 #[derive(Default)]
@@ -37,6 +574,56 @@ impl AssetLoader for TomlLoader {
                                 return Err(bevy::asset::Error::msg(err.to_string()));
                             }
                         }
+                    } else if load_context.path().starts_with("characters") {
+                        match toml::from_str::<crate::CharacterSheet>(utf8) {
+                            Ok(sheet) => {
+                                load_context.set_default_asset(LoadedAsset::new(sheet));
+                                return Ok(());
+                            }
+                            Err(err) => {
+                                return Err(bevy::asset::Error::msg(err.to_string()));
+                            }
+                        }
+                    } else if load_context.path().starts_with("features") {
+                        match toml::from_str::<crate::FeatureDef>(utf8) {
+                            Ok(feature) => {
+                                load_context.set_default_asset(LoadedAsset::new(feature));
+                                return Ok(());
+                            }
+                            Err(err) => {
+                                return Err(bevy::asset::Error::msg(err.to_string()));
+                            }
+                        }
+                    } else if load_context.path().starts_with("classes") {
+                        match toml::from_str::<crate::ClassDef>(utf8) {
+                            Ok(class) => {
+                                load_context.set_default_asset(LoadedAsset::new(class));
+                                return Ok(());
+                            }
+                            Err(err) => {
+                                return Err(bevy::asset::Error::msg(err.to_string()));
+                            }
+                        }
+                    } else if load_context.path().starts_with("items") {
+                        match toml::from_str::<crate::Item>(utf8) {
+                            Ok(item) => {
+                                load_context.set_default_asset(LoadedAsset::new(item));
+                                return Ok(());
+                            }
+                            Err(err) => {
+                                return Err(bevy::asset::Error::msg(err.to_string()));
+                            }
+                        }
+                    } else if load_context.path().starts_with("encounters") {
+                        match toml::from_str::<EncounterDef>(utf8) {
+                            Ok(encounter) => {
+                                load_context.set_default_asset(LoadedAsset::new(encounter));
+                                return Ok(());
+                            }
+                            Err(err) => {
+                                return Err(bevy::asset::Error::msg(err.to_string()));
+                            }
+                        }
                     } else {
                         return Err(bevy::asset::Error::msg("unknown asset"));
                     }
@@ -54,5 +641,12 @@ impl AssetLoader for TomlLoader {
 
 pub fn build(app: &mut App) {
     app.add_asset::<Statblock>();
+    app.add_asset::<EncounterDef>();
+    app.add_asset::<crate::Item>();
+    app.add_asset::<crate::CharacterSheet>();
+    app.add_asset::<crate::ClassDef>();
+    app.add_asset::<crate::FeatureDef>();
+    app.add_asset::<crate::AuthoredMap>();
     app.init_asset_loader::<TomlLoader>();
+    app.init_asset_loader::<crate::MapLoader>();
 }
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use bevy::{
+    prelude::*,
+    reflect::{TypePath, TypeUuid},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{Abilities, Ability, LightSource, LimitedUse, Proficiency, Skill, Tool};
+
+/// Folder of the character sheets in the assets.
+pub const CHARACTERS_FOLDER: &str = "characters";
+
+/// A player character, loaded from `characters/`. The rules resolve it into the statblock
+/// used in play, and it is written back to disk as the character progresses.
+#[derive(TypeUuid, TypePath, Clone, Serialize, Deserialize)]
+#[uuid = "8e3d2c71-4b6a-4f0e-b2d9-6a1f7c5e3b90"]
+pub struct CharacterSheet {
+    pub name: String,
+    /// Id of the class in `classes/`.
+    pub class: String,
+    #[serde(default = "default_level")]
+    pub level: u32,
+    #[serde(default)]
+    pub race: String,
+    #[serde(default)]
+    pub background: String,
+    /// Traits of the race, e.g. "Darkvision". Those that matter in play are also given by the
+    /// matching fields, such as `darkvision_ft`.
+    #[serde(default)]
+    pub traits: Vec<String>,
+    #[serde(default = "default_speed")]
+    pub speed: u32,
+    #[serde(default)]
+    pub abilities: Abilities,
+    /// Number of sides of the class hit die, e.g. 10 for a fighter.
+    pub hit_die: u32,
+    /// Hit die results of every level after the first, rolled or the average.
+    #[serde(default)]
+    pub hit_die_rolls: Vec<u32>,
+    /// Rolls the hit die for hit points on level up instead of taking the average.
+    #[serde(default)]
+    pub roll_hit_points: bool,
+    #[serde(default)]
+    pub saving_throws: Vec<Ability>,
+    #[serde(default)]
+    pub skills: HashMap<Skill, Proficiency>,
+    #[serde(default)]
+    pub tools: HashMap<Tool, Proficiency>,
+    /// Class features by id in `features/`, e.g. "second_wind".
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub limited_uses: Vec<LimitedUse>,
+    #[serde(default)]
+    pub spell_slots: Vec<u32>,
+    #[serde(default)]
+    pub darkvision_ft: u32,
+    #[serde(default)]
+    pub light: Option<LightSource>,
+    #[serde(default)]
+    pub inventory: Vec<crate::ItemStack>,
+    #[serde(default)]
+    pub xp: u32,
+}
+
+impl CharacterSheet {
+    pub fn to_toml(&self) -> Result<String, String> {
+        toml::to_string_pretty(self).map_err(|err| err.to_string())
+    }
+}
+
+/// What a class grants at one level.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ClassLevel {
+    /// Ids of the features gained in `features/`.
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub limited_uses: Vec<LimitedUse>,
+    /// Spell slots per spell level from this level on, none for classes without spells.
+    #[serde(default)]
+    pub spell_slots: Vec<u32>,
+}
+
+/// A class, loaded from `classes/`, with its table of what each level grants starting at 1st.
+#[derive(TypeUuid, TypePath, Clone, Serialize, Deserialize)]
+#[uuid = "c4a7e0d2-9b15-4e83-8f6a-3d2b1c0e7f54"]
+pub struct ClassDef {
+    pub name: String,
+    pub hit_die: u32,
+    #[serde(default)]
+    pub saving_throws: Vec<Ability>,
+    #[serde(default)]
+    pub levels: Vec<ClassLevel>,
+}
+
+impl ClassDef {
+    pub fn level(&self, level: u32) -> Option<&ClassLevel> {
+        self.levels.get(level.checked_sub(1)? as usize)
+    }
+}
+
+/// Handles of every class a character in play has, by id.
+#[derive(Resource, Default)]
+pub struct ClassCatalog {
+    pub handles: HashMap<String, Handle<ClassDef>>,
+}
+
+impl ClassCatalog {
+    pub fn get<'a>(&self, id: &str, classes: &'a Assets<ClassDef>) -> Option<&'a ClassDef> {
+        self.handles.get(id).and_then(|handle| classes.get(handle))
+    }
+}
+
+fn default_level() -> u32 {
+    1
+}
+
+fn default_speed() -> u32 {
+    30
+}
@@ -1,21 +1,96 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::HashMap};
 use glam::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(Component, Default)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// Fights for the given faction for as long as the charm lasts.
+    Charmed { faction: String },
+    /// Disadvantage on attack rolls and ability checks.
+    Poisoned,
+    /// Advantage on the next ability check, from someone taking the Help action.
+    Helped,
+    /// Adds 1d4 to attack rolls and saving throws.
+    Blessed,
+    /// Takes 1d4 off attack rolls and saving throws.
+    Baned,
+}
+
+/// A creature that hid, and the observers it is hidden from. They need a Perception result
+/// that meets its Stealth to find it.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct Hidden {
+    pub stealth: i32,
+    pub from: Vec<Entity>,
+}
+
+#[derive(Component, Default, Clone, Serialize, Deserialize)]
 pub struct Token {
     pub color:Color,
     pub image:String,
     pub statblock:String,
+    /// Character sheet in `characters/` the statblock is resolved from, for player characters.
+    #[serde(default)]
+    pub character:Option<String>,
     pub grid_pos:IVec2, 
     pub name:String,
+    /// Controlling player, tokens without one are controlled by the AI.
     pub player:Option<Entity>,
+    pub faction:String,
+    pub conditions:Vec<Condition>,
     pub movement_ft:f32,
+    pub actions:u32,
+    pub bonus_actions:u32,
+    pub reaction:bool,
+    pub hit_points:i32,
+    /// Hit dice left to spend on short rests.
+    pub hit_dice:u32,
+    /// Spell slots left per spell level.
+    pub spell_slots:Vec<u32>,
+    /// Uses left of each limited use from the statblock.
+    pub limited_uses:HashMap<String, u32>,
+    pub exhaustion:u8,
+    /// Moving stealthily, so combat starting may surprise the other side.
+    pub sneaking:bool,
+    #[serde(default)]
+    pub hidden:Option<Hidden>,
+    /// The one free object interaction of the turn, e.g. opening a door, is still available.
+    #[serde(default)]
+    pub object_interaction:bool,
+    /// Features active for the given number of rounds, e.g. a rage.
+    #[serde(default)]
+    pub active_features:HashMap<String, u32>,
+    /// Once-per-turn features already used this turn.
+    #[serde(default)]
+    pub features_used:Vec<String>,
+    /// Set once the runtime state has been initialized from the statblock.
+    pub statblock_applied:bool,
 }
 
 impl Token {
     pub fn pos(grid_pos:IVec2) -> Vec3 {
         Vec3::new(grid_pos.x as f32 + 0.5,  grid_pos.y as f32 + 0.5, 0.0)
     }
+
+    pub fn is_hidden_from(&self, observer: Entity) -> bool {
+        self.hidden.as_ref().is_some_and(|hidden| hidden.from.contains(&observer))
+    }
+
+    pub fn remap_entities(&mut self, map: &HashMap<Entity, Entity>) {
+        self.player = self.player.and_then(|player| crate::remap(player, map));
+        if let Some(hidden) = self.hidden.as_mut() {
+            hidden.from.retain_mut(|observer| crate::remap_in_place(observer, map));
+        }
+    }
+}
+
+/// A creature on a floor other than the current one, turned back into its `Token` when the
+/// party arrives there.
+#[derive(Component, Clone, Serialize, Deserialize)]
+pub struct StoredToken {
+    pub floor: usize,
+    pub token: Token,
 }
 
 #[derive(Default, Component)]
@@ -29,7 +104,7 @@ pub struct Selection {
 }
 
 
-#[derive(Component, Default)]
+#[derive(Component, Default, Clone, Serialize, Deserialize)]
 pub struct Player {
     pub name:String
 }
\ No newline at end of file
@@ -1,10 +1,56 @@
-use bevy::prelude::{App, Entity, Event};
+use bevy::prelude::{App, Entity, Event, IVec2};
+
+use crate::{Check, Outcome, Roll};
 
 #[derive(Event)]
 pub enum GameEvent {
     NextActiveEntity { entity: Entity },
+    /// A round command is done, sent before its effects are applied in the same frame.
+    CommandFinished,
+    /// `damage` is what the target took, after its resistances.
+    Attacked {
+        attacker: Entity,
+        target: Entity,
+        hit: bool,
+        damage: i32,
+        roll: Roll,
+        damage_roll: Option<Roll>,
+    },
+    EncounterEnded { outcome: Outcome },
+    /// The party took the stairs and should be moved to the cell on the other floor.
+    TookStairs { who: Entity, stairs: crate::Stairs },
+    TrapSprung { who: Entity, cell: IVec2, damage: i32 },
+    /// `who` hid from the given number of observers.
+    Hid { who: Entity, stealth: i32, observers: usize },
+    /// `by` found the hidden `who`, by searching or by seeing it out in the open.
+    Found { who: Entity, by: Entity },
+    /// The result of an ability check, for whatever depends on it to react to.
+    Checked {
+        who: Entity,
+        check: Check,
+        dc: i32,
+        success: bool,
+        roll: Roll,
+        cell: Option<IVec2>,
+    },
+    UsedItem { who: Entity, item: String, healed: i32 },
+    /// A player character's share of the XP for winning the encounter.
+    GainedXp { who: Entity, xp: u32 },
+    LeveledUp { who: Entity, level: u32 },
+    UsedFeature { who: Entity, feature: String, healed: i32 },
+}
+
+#[derive(Event)]
+pub enum PersistenceEvent {
+    Save { path: String },
+    Load { path: String },
+    SaveReplay { path: String },
+    LoadReplay { path: String },
+    /// Writes the player characters' sheets back to their folder in the assets.
+    SaveCharacters,
 }
 
 pub fn build(app: &mut App) {
     app.add_event::<GameEvent>();
+    app.add_event::<PersistenceEvent>();
 }
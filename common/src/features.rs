@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use bevy::{
+    prelude::*,
+    reflect::{TypePath, TypeUuid},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{Ability, Condition, DiceExpr, RollKind, Statblock};
+
+/// When a feature takes effect.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    /// Always in effect, e.g. Unarmored Defense.
+    #[default]
+    Passive,
+    /// Used on purpose, e.g. Second Wind.
+    Activated,
+    /// When one of the creature's attacks hits, e.g. Sneak Attack.
+    OnHit,
+    /// At the start of each of the creature's turns.
+    OnTurnStart,
+}
+
+/// What using an activated feature takes from the turn.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Cost {
+    #[default]
+    Free,
+    Action,
+    BonusAction,
+}
+
+/// Has to hold for an on-hit feature to apply to the attack, or for a passive one to change
+/// its damage roll.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Requirement {
+    /// The attack is made with a finesse or a ranged weapon.
+    FinesseOrRanged,
+    /// The attack roll has advantage, or an ally of the attacker is next to the target and
+    /// the roll doesn't have disadvantage.
+    AdvantageOrAllyAdjacent,
+    Melee,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Effect {
+    /// Regains hit points, plus the creature's level with `add_level`.
+    Heal {
+        dice: DiceExpr,
+        #[serde(default)]
+        add_level: bool,
+    },
+    /// Extra damage on a hit. With `every_levels` the dice count grows by the given count
+    /// every that many levels, starting at 1st, e.g. 1d6 every 2 levels for Sneak Attack.
+    ExtraDamage {
+        dice: DiceExpr,
+        #[serde(default)]
+        every_levels: u32,
+    },
+    /// Takes one more action on this turn.
+    GainActions { actions: u32 },
+    /// Added to the damage of hits, of melee attacks only with `melee`.
+    DamageBonus {
+        bonus: i32,
+        #[serde(default)]
+        melee: bool,
+    },
+    /// Halves the damage taken from attacks.
+    Resistance,
+    /// Advantage on the kinds of roll, only those with the ability if given.
+    Advantage {
+        rolls: Vec<RollKind>,
+        #[serde(default)]
+        ability: Option<Ability>,
+    },
+    Disadvantage {
+        rolls: Vec<RollKind>,
+        #[serde(default)]
+        ability: Option<Ability>,
+    },
+    /// Added to the kinds of roll.
+    RollBonus { bonus: i32, rolls: Vec<RollKind> },
+    /// Dice added to the kinds of roll, or taken off them with `penalty`.
+    RollDice {
+        dice: DiceExpr,
+        #[serde(default)]
+        penalty: bool,
+        rolls: Vec<RollKind>,
+    },
+    /// Dice of the kinds of roll showing `at_most` or lower are rolled again once, e.g. a
+    /// natural 1 for a halfling's luck.
+    Reroll { at_most: i32, rolls: Vec<RollKind> },
+    /// Without armor, AC is 10 plus the Dexterity modifier and that of the ability, e.g.
+    /// Constitution for a barbarian. A shield can still be used.
+    UnarmoredDefense { ability: Ability },
+    /// Gives the condition to the creature and its allies within range when used, or to the
+    /// hostile creatures with `hostile`, until the fight ends, e.g. Bless and Bane.
+    Condition {
+        condition: Condition,
+        range_ft: u32,
+        #[serde(default)]
+        hostile: bool,
+    },
+}
+
+/// A class feature or other ability, loaded from `features/`. The effects of passive features
+/// always apply. Activated features with a duration stay active for that many rounds and their
+/// effects apply while they do. Others take effect once each time they trigger.
+#[derive(TypeUuid, TypePath, Clone, Serialize, Deserialize)]
+#[uuid = "7a2f9c84-1e3b-4d6a-b05f-8c9e2d4a6b13"]
+pub struct FeatureDef {
+    pub name: String,
+    #[serde(default)]
+    pub trigger: Trigger,
+    #[serde(default)]
+    pub cost: Cost,
+    /// Limited use of the statblock that each use spends, e.g. "Second Wind".
+    #[serde(default)]
+    pub uses: Option<String>,
+    #[serde(default)]
+    pub once_per_turn: bool,
+    #[serde(default)]
+    pub requires: Vec<Requirement>,
+    #[serde(default)]
+    pub duration_rounds: Option<u32>,
+    #[serde(default)]
+    pub effects: Vec<Effect>,
+}
+
+/// Handles of every feature of someone in play, by id.
+#[derive(Resource, Default)]
+pub struct FeatureCatalog {
+    pub handles: HashMap<String, Handle<FeatureDef>>,
+}
+
+impl FeatureCatalog {
+    pub fn get<'a>(&self, id: &str, features: &'a Assets<FeatureDef>) -> Option<&'a FeatureDef> {
+        self.handles.get(id).and_then(|handle| features.get(handle))
+    }
+
+    /// The loaded features of the statblock, with their ids.
+    pub fn of<'s, 'a>(
+        &self,
+        statblock: &'s Statblock,
+        features: &'a Assets<FeatureDef>,
+    ) -> Vec<(&'s str, &'a FeatureDef)> {
+        statblock
+            .features
+            .iter()
+            .filter_map(|id| Some((id.as_str(), self.get(id, features)?)))
+            .collect()
+    }
+
+    pub fn is_loaded(&self, statblock: &Statblock, features: &Assets<FeatureDef>) -> bool {
+        statblock
+            .features
+            .iter()
+            .all(|id| self.get(id, features).is_some())
+    }
+}
@@ -0,0 +1,166 @@
+use bevy::{
+    prelude::*,
+    reflect::{TypePath, TypeUuid},
+    utils::HashMap,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::DiceExpr;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WeaponProperty {
+    /// Attacks with the better of Strength and Dexterity.
+    Finesse,
+    /// Deals `versatile_damage` when the other hand is free.
+    Versatile,
+    /// Adds 5 ft to the reach.
+    Reach,
+    /// Can be thrown out to its range, and is used up when thrown.
+    Thrown,
+    /// Shoots the `ammunition` item out to its range, using one per attack.
+    Ammunition,
+    Light,
+    Heavy,
+    TwoHanded,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Weapon {
+    pub damage: DiceExpr,
+    #[serde(default)]
+    pub properties: Vec<WeaponProperty>,
+    #[serde(default)]
+    pub versatile_damage: Option<DiceExpr>,
+    /// Normal range for thrown and ammunition weapons.
+    #[serde(default)]
+    pub range_ft: u32,
+    /// The item a weapon with the ammunition property shoots.
+    #[serde(default)]
+    pub ammunition: Option<String>,
+}
+
+impl Weapon {
+    pub fn has(&self, property: WeaponProperty) -> bool {
+        self.properties.contains(&property)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArmorCategory {
+    /// Base AC plus the Dexterity modifier.
+    Light,
+    /// Base AC plus the Dexterity modifier, up to +2.
+    Medium,
+    /// Base AC only.
+    Heavy,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Armor {
+    pub base: i32,
+    pub category: ArmorCategory,
+}
+
+/// Used up as an action, e.g. a potion.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Consumable {
+    #[serde(default)]
+    pub healing: Option<DiceExpr>,
+}
+
+/// A piece of gear, loaded from `items/`. At most one of the kinds is set.
+#[derive(TypeUuid, TypePath, Clone, Debug, Serialize, Deserialize)]
+#[uuid = "5b0c4a3e-7f0d-4a8e-9f5c-1d6c2e8b9a41"]
+pub struct Item {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub weapon: Option<Weapon>,
+    #[serde(default)]
+    pub armor: Option<Armor>,
+    /// AC bonus of a shield.
+    #[serde(default)]
+    pub shield: Option<i32>,
+    #[serde(default)]
+    pub consumable: Option<Consumable>,
+}
+
+/// Some number of the item of the given id, i.e. its file name in `items/`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ItemStack {
+    pub item: String,
+    #[serde(default = "default_quantity")]
+    pub quantity: u32,
+    #[serde(default)]
+    pub equipped: bool,
+}
+
+fn default_quantity() -> u32 {
+    1
+}
+
+/// The gear a creature carries, given by its statblock when it spawns.
+#[derive(Component, Clone, Default, Debug, Serialize, Deserialize)]
+pub struct Inventory {
+    pub items: Vec<ItemStack>,
+}
+
+impl Inventory {
+    pub fn count(&self, item: &str) -> u32 {
+        self.items
+            .iter()
+            .filter(|stack| stack.item == item)
+            .map(|stack| stack.quantity)
+            .sum()
+    }
+
+    /// Removes one of the item, returning false if there is none.
+    pub fn take(&mut self, item: &str) -> bool {
+        let Some(i) = self
+            .items
+            .iter()
+            .position(|stack| stack.item == item && stack.quantity > 0)
+        else {
+            return false;
+        };
+        self.items[i].quantity -= 1;
+        if self.items[i].quantity == 0 {
+            self.items.remove(i);
+        }
+        true
+    }
+
+    /// The equipped items that have been loaded, with their ids.
+    pub fn equipped<'s, 'a>(
+        &'s self,
+        catalog: &ItemCatalog,
+        items: &'a Assets<Item>,
+    ) -> Vec<(&'s str, &'a Item)> {
+        self.items
+            .iter()
+            .filter(|stack| stack.equipped)
+            .filter_map(|stack| Some((stack.item.as_str(), catalog.get(&stack.item, items)?)))
+            .collect()
+    }
+}
+
+/// Handles of every item carried by someone, by id.
+#[derive(Resource, Default)]
+pub struct ItemCatalog {
+    pub handles: HashMap<String, Handle<Item>>,
+}
+
+impl ItemCatalog {
+    pub fn get<'a>(&self, id: &str, items: &'a Assets<Item>) -> Option<&'a Item> {
+        self.handles.get(id).and_then(|handle| items.get(handle))
+    }
+
+    pub fn is_loaded(&self, inventory: &Inventory, items: &Assets<Item>) -> bool {
+        inventory
+            .items
+            .iter()
+            .all(|stack| self.get(&stack.item, items).is_some())
+    }
+}
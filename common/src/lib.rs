@@ -1,22 +1,43 @@
 pub mod math;
 mod components;
 pub use components::*;
+// explicit so it wins over the bevy prelude `Condition` trait
+pub use components::Condition;
 mod events;
 pub use events::*;
 mod resources;
 pub use resources::*;
+mod states;
+pub use states::*;
 use bevy::prelude::*;
 mod systems;
 mod assets;
 pub use assets::*;
 mod bundles;
 pub use bundles::*;
+mod save;
+pub use save::*;
+mod replay;
+pub use replay::*;
+mod maps;
+pub use maps::*;
+mod objects;
+pub use objects::*;
+mod items;
+pub use items::*;
+mod characters;
+pub use characters::*;
+mod features;
+pub use features::*;
+mod rolls;
+pub use rolls::*;
 pub struct CommonPlugin;
 
 impl Plugin for CommonPlugin {
     fn build(&self, app: &mut App) {
         events::build(app);
         resources::build(app);
+        states::build(app);
         systems::build(app);
         assets::build(app);
     }
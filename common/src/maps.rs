@@ -0,0 +1,438 @@
+use bevy::{
+    asset::{AssetLoader, LoadedAsset},
+    reflect::{TypePath, TypeUuid},
+};
+use glam::IVec2;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{Grid, Interactable, Terrain};
+
+/// Something placed on a map besides its cells, such as a spawn point, a door or a light.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MapObject {
+    /// Class of the object in Tiled, e.g. `spawn`, `door` or `light`.
+    pub kind: String,
+    pub name: String,
+    pub cell: IVec2,
+    /// Custom properties set in Tiled, e.g. the `state` of a door.
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+}
+
+/// A hand-authored map, made in Tiled or sketched as text.
+///
+/// In Tiled maps any tile on a layer named `floor`, `walls`, `difficult` or `water` marks its
+/// cell accordingly, other tile layers are only decoration.
+///
+/// ASCII maps use one character per cell: `#` wall, `.` floor, `~` water, `^` difficult
+/// terrain, a space for nothing and `@` for the starting point. Any letter or digit is a floor
+/// cell with a spawn point named after it, e.g. `G` for goblins or `1` for the party. `+` and
+/// `/` are closed and open doors, `%` a hidden trap, `$` a chest and `*` a lit torch.
+///
+/// Doors, traps, levers, chests and torches are also put on their cell as an `Interactable`.
+#[derive(TypeUuid, TypePath, Clone)]
+#[uuid = "9d3e6a71-52c4-4b8f-a0e2-7f1b4c6d8e35"]
+pub struct AuthoredMap {
+    pub grid: Grid,
+    pub objects: Vec<MapObject>,
+}
+
+struct RawObject {
+    kind: String,
+    name: String,
+    x: f32,
+    y: f32,
+    /// Tile objects are anchored at their bottom left corner instead of the top left.
+    tile: bool,
+    properties: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct TmjMap {
+    width: usize,
+    height: usize,
+    tilewidth: f32,
+    tileheight: f32,
+    #[serde(default)]
+    layers: Vec<TmjLayer>,
+}
+
+#[derive(Deserialize)]
+struct TmjLayer {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    name: String,
+    /// Only uncompressed layers are supported, which Tiled writes as a plain array.
+    #[serde(default)]
+    data: Vec<u32>,
+    #[serde(default)]
+    objects: Vec<TmjObject>,
+    /// Layers nested in a group layer.
+    #[serde(default)]
+    layers: Vec<TmjLayer>,
+}
+
+#[derive(Deserialize)]
+struct TmjObject {
+    #[serde(default)]
+    name: String,
+    /// Called `type` before Tiled 1.9.
+    #[serde(default, rename = "type")]
+    kind: String,
+    #[serde(default)]
+    class: String,
+    x: f32,
+    y: f32,
+    #[serde(default)]
+    gid: Option<u32>,
+    #[serde(default)]
+    properties: Vec<TmjProperty>,
+}
+
+#[derive(Deserialize)]
+struct TmjProperty {
+    name: String,
+    value: serde_json::Value,
+}
+
+fn flatten_tmj_layers(
+    layers: Vec<TmjLayer>,
+    tiles: &mut Vec<(String, Vec<u32>)>,
+    objects: &mut Vec<RawObject>,
+) {
+    for layer in layers {
+        match layer.kind.as_str() {
+            "tilelayer" => tiles.push((layer.name, layer.data)),
+            "objectgroup" => {
+                for object in layer.objects {
+                    objects.push(RawObject {
+                        kind: if object.class.is_empty() { object.kind } else { object.class },
+                        name: object.name,
+                        x: object.x,
+                        y: object.y,
+                        tile: object.gid.is_some(),
+                        properties: object
+                            .properties
+                            .into_iter()
+                            .map(|property| {
+                                let value = match property.value {
+                                    serde_json::Value::String(value) => value,
+                                    value => value.to_string(),
+                                };
+                                (property.name, value)
+                            })
+                            .collect(),
+                    });
+                }
+            }
+            "group" => flatten_tmj_layers(layer.layers, tiles, objects),
+            _ => {}
+        }
+    }
+}
+
+fn tmx_attribute(node: roxmltree::Node, name: &str) -> Result<f32, String> {
+    let value = node
+        .attribute(name)
+        .ok_or_else(|| format!("missing attribute {} on <{}>", name, node.tag_name().name()))?;
+    value
+        .parse::<f32>()
+        .map_err(|err| format!("invalid attribute {}: {}", name, err))
+}
+
+/// Only walks the layers of the map and its groups, tilesets have `<tile>` and `<object>`
+/// elements of their own.
+fn flatten_tmx_layers(
+    parent: roxmltree::Node,
+    tiles: &mut Vec<(String, Vec<u32>)>,
+    objects: &mut Vec<RawObject>,
+) -> Result<(), String> {
+    for node in parent.children() {
+        match node.tag_name().name() {
+            "layer" => {
+                let name = node.attribute("name").unwrap_or_default().to_string();
+                let Some(data) = node.children().find(|n| n.has_tag_name("data")) else {
+                    return Err(format!("layer {} has no data", name));
+                };
+                let gids = match data.attribute("encoding") {
+                    Some("csv") => data
+                        .text()
+                        .unwrap_or_default()
+                        .split(',')
+                        .map(|gid| gid.trim().parse::<u32>())
+                        .collect::<Result<Vec<_>, _>>(),
+                    None => data
+                        .children()
+                        .filter(|n| n.has_tag_name("tile"))
+                        .map(|n| n.attribute("gid").unwrap_or("0").parse::<u32>())
+                        .collect::<Result<Vec<_>, _>>(),
+                    Some(encoding) => {
+                        return Err(format!("layer {} uses unsupported encoding {}", name, encoding))
+                    }
+                };
+                let gids = gids.map_err(|err| format!("invalid tile in layer {}: {}", name, err))?;
+                tiles.push((name, gids));
+            }
+            "objectgroup" => {
+                for node in node.children().filter(|n| n.has_tag_name("object")) {
+                    let kind = node.attribute("class").or(node.attribute("type"));
+                    objects.push(RawObject {
+                        kind: kind.unwrap_or_default().to_string(),
+                        name: node.attribute("name").unwrap_or_default().to_string(),
+                        x: tmx_attribute(node, "x")?,
+                        y: tmx_attribute(node, "y")?,
+                        tile: node.attribute("gid").is_some(),
+                        properties: node
+                            .children()
+                            .filter(|n| n.has_tag_name("properties"))
+                            .flat_map(|n| n.children())
+                            .filter(|n| n.has_tag_name("property"))
+                            .filter_map(|n| {
+                                let value = n.attribute("value").or(n.text()).unwrap_or_default();
+                                Some((n.attribute("name")?.to_string(), value.to_string()))
+                            })
+                            .collect(),
+                    });
+                }
+            }
+            "group" => flatten_tmx_layers(node, tiles, objects)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Puts the interactable objects on their cells. A lever operates the door named by its `door`
+/// property.
+fn place_interactables(grid: &mut Grid, objects: &[MapObject]) {
+    for object in objects {
+        let Some(mut interactable) = Interactable::from_map_object(object) else {
+            continue;
+        };
+        if let Interactable::Lever { door, .. } = &mut interactable {
+            let name = object.properties.get("door");
+            *door = objects
+                .iter()
+                .find(|other| other.kind == "door" && Some(&other.name) == name)
+                .map(|other| other.cell);
+        }
+        if let Some(cell) = grid.get_mut(object.cell) {
+            cell.object = Some(interactable);
+        }
+    }
+}
+
+impl AuthoredMap {
+    /// Parses a map saved in Tiled's JSON format.
+    pub fn from_tmj(json: &str) -> Result<Self, String> {
+        let map = serde_json::from_str::<TmjMap>(json).map_err(|err| err.to_string())?;
+        let mut tiles = Vec::new();
+        let mut objects = Vec::new();
+        flatten_tmj_layers(map.layers, &mut tiles, &mut objects);
+        Self::build(map.width, map.height, map.tilewidth, map.tileheight, tiles, objects)
+    }
+
+    /// Parses a map saved in Tiled's XML format, with layers encoded as CSV or XML.
+    pub fn from_tmx(xml: &str) -> Result<Self, String> {
+        let doc = roxmltree::Document::parse(xml).map_err(|err| err.to_string())?;
+        let map = doc.root_element();
+        let width = tmx_attribute(map, "width")? as usize;
+        let height = tmx_attribute(map, "height")? as usize;
+        let tile_width = tmx_attribute(map, "tilewidth")?;
+        let tile_height = tmx_attribute(map, "tileheight")?;
+
+        let mut tiles = Vec::new();
+        let mut objects = Vec::new();
+        flatten_tmx_layers(map, &mut tiles, &mut objects)?;
+        Self::build(width, height, tile_width, tile_height, tiles, objects)
+    }
+
+    /// Parses an ASCII map. Blank lines around the map and indentation shared by all of its
+    /// lines are ignored, so maps can be written inline as indented strings.
+    pub fn from_ascii(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines().map(str::trim_end).collect::<Vec<_>>();
+        while lines.first().is_some_and(|line| line.is_empty()) {
+            lines.remove(0);
+        }
+        while lines.last().is_some_and(|line| line.is_empty()) {
+            lines.pop();
+        }
+        let indent = lines
+            .iter()
+            .filter(|line| !line.is_empty())
+            .map(|line| line.len() - line.trim_start().len())
+            .min()
+            .unwrap_or_default();
+
+        let height = lines.len();
+        let width = lines
+            .iter()
+            .map(|line| line.chars().count().saturating_sub(indent))
+            .max()
+            .unwrap_or_default();
+        let mut grid = Grid::new(width, height);
+        let mut objects = Vec::new();
+        for (y, line) in lines.iter().enumerate() {
+            for (x, c) in line.chars().skip(indent).enumerate() {
+                let p = IVec2::new(x as i32, y as i32);
+                let Some(cell) = grid.get_mut(p) else {
+                    continue;
+                };
+                match c {
+                    ' ' => {}
+                    '#' => cell.blocked = true,
+                    '.' => cell.walkable = true,
+                    '~' => {
+                        cell.walkable = true;
+                        cell.terrain = Terrain::Water;
+                    }
+                    '^' => {
+                        cell.walkable = true;
+                        cell.terrain = Terrain::Difficult;
+                    }
+                    '@' | 'a'..='z' | 'A'..='Z' | '0'..='9' => {
+                        cell.walkable = true;
+                        objects.push(MapObject {
+                            kind: "spawn".into(),
+                            name: if c == '@' { "start".into() } else { c.to_string() },
+                            cell: p,
+                            properties: HashMap::new(),
+                        });
+                    }
+                    '+' | '/' | '%' | '$' | '*' => {
+                        cell.walkable = true;
+                        let (kind, state) = match c {
+                            '+' => ("door", "closed"),
+                            '/' => ("door", "open"),
+                            '%' => ("trap", ""),
+                            '$' => ("chest", ""),
+                            _ => ("torch", ""),
+                        };
+                        let mut properties = HashMap::new();
+                        if !state.is_empty() {
+                            properties.insert("state".to_string(), state.to_string());
+                        }
+                        objects.push(MapObject {
+                            kind: kind.into(),
+                            name: String::new(),
+                            cell: p,
+                            properties,
+                        });
+                    }
+                    _ => {
+                        return Err(format!(
+                            "unknown character {:?} at line {}, column {}",
+                            c,
+                            y + 1,
+                            x + indent + 1
+                        ))
+                    }
+                }
+            }
+        }
+        place_interactables(&mut grid, &objects);
+        Ok(Self { grid, objects })
+    }
+
+    fn build(
+        width: usize,
+        height: usize,
+        tile_width: f32,
+        tile_height: f32,
+        tiles: Vec<(String, Vec<u32>)>,
+        objects: Vec<RawObject>,
+    ) -> Result<Self, String> {
+        let mut grid = Grid::new(width, height);
+        for (name, gids) in tiles {
+            if gids.len() != width * height {
+                return Err(format!(
+                    "layer {} has {} tiles, expected {}",
+                    name,
+                    gids.len(),
+                    width * height
+                ));
+            }
+            let name = name.to_lowercase();
+            for (i, gid) in gids.into_iter().enumerate() {
+                if gid == 0 {
+                    continue;
+                }
+                let p = IVec2::new((i % width) as i32, (i / width) as i32);
+                let Some(cell) = grid.get_mut(p) else {
+                    continue;
+                };
+                match name.as_str() {
+                    "floor" => cell.walkable = !cell.blocked,
+                    "walls" => {
+                        cell.blocked = true;
+                        cell.walkable = false;
+                    }
+                    "difficult" => {
+                        cell.walkable = !cell.blocked;
+                        cell.terrain = Terrain::Difficult;
+                    }
+                    "water" => {
+                        cell.walkable = !cell.blocked;
+                        cell.terrain = Terrain::Water;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let objects = objects
+            .into_iter()
+            .map(|object| {
+                let y = if object.tile {
+                    object.y - tile_height * 0.5
+                } else {
+                    object.y
+                };
+                MapObject {
+                    kind: object.kind,
+                    name: object.name,
+                    cell: IVec2::new(
+                        (object.x / tile_width).floor() as i32,
+                        (y / tile_height).floor() as i32,
+                    ),
+                    properties: object.properties,
+                }
+            })
+            .collect::<Vec<_>>();
+        place_interactables(&mut grid, &objects);
+        Ok(Self { grid, objects })
+    }
+}
+
+#[derive(Default)]
+pub struct MapLoader;
+
+impl AssetLoader for MapLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut bevy::asset::LoadContext,
+    ) -> bevy::utils::BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let text = std::str::from_utf8(bytes)?;
+            let map = match load_context.path().extension().and_then(|e| e.to_str()) {
+                Some("tmx") => AuthoredMap::from_tmx(text),
+                Some("txt") => AuthoredMap::from_ascii(text),
+                _ => AuthoredMap::from_tmj(text),
+            };
+            match map {
+                Ok(map) => {
+                    load_context.set_default_asset(LoadedAsset::new(map));
+                    Ok(())
+                }
+                Err(err) => Err(bevy::asset::Error::msg(err)),
+            }
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["tmj", "tmx", "txt"]
+    }
+}
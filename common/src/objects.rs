@@ -0,0 +1,100 @@
+use glam::IVec2;
+use serde::{Deserialize, Serialize};
+
+use crate::{Ability, DiceExpr, MapObject};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DoorState {
+    Open,
+    Closed,
+    /// Picked with thieves' tools against the DC.
+    Locked { dc: i32 },
+}
+
+/// Fires when stepped on while armed. Creatures who pass next to it find it if their passive
+/// Perception meets the find DC, after which it can be disarmed with thieves' tools.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Trap {
+    pub hidden: bool,
+    pub armed: bool,
+    pub find_dc: i32,
+    pub disarm_dc: i32,
+    pub save: Ability,
+    pub save_dc: i32,
+    /// Halved on a successful save.
+    pub damage: DiceExpr,
+}
+
+/// Something on a cell that creatures can interact with.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Interactable {
+    /// Closed and locked doors block movement and line of sight.
+    Door(DoorState),
+    Trap(Trap),
+    /// Opens and closes the door on the given cell.
+    Lever { on: bool, door: Option<IVec2> },
+    Chest { open: bool },
+    Torch { lit: bool },
+}
+
+impl Interactable {
+    pub fn is_closed_door(&self) -> bool {
+        matches!(self, Interactable::Door(DoorState::Closed | DoorState::Locked { .. }))
+    }
+
+    /// Builds the object a map object of kind `door`, `trap`, `lever`, `chest` or `torch`
+    /// stands for, configured by its properties. Levers are linked to their door by name
+    /// separately, as that needs the other objects.
+    pub fn from_map_object(object: &MapObject) -> Option<Self> {
+        let property = |name: &str| object.properties.get(name).map(String::as_str);
+        let number = |name: &str, default: i32| {
+            property(name)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        let flag = |name: &str, default: bool| {
+            property(name)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        match object.kind.as_str() {
+            "door" => Some(Interactable::Door(match property("state") {
+                Some("open") => DoorState::Open,
+                Some("locked") => DoorState::Locked {
+                    dc: number("dc", 15),
+                },
+                _ => DoorState::Closed,
+            })),
+            "trap" => Some(Interactable::Trap(Trap {
+                hidden: flag("hidden", true),
+                armed: true,
+                find_dc: number("find_dc", 15),
+                disarm_dc: number("disarm_dc", 15),
+                save: property("save")
+                    .and_then(|value| serde_json::from_value(value.into()).ok())
+                    .unwrap_or(Ability::Dexterity),
+                save_dc: number("save_dc", 13),
+                damage: property("damage")
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(DiceExpr {
+                        count: 2,
+                        sides: 10,
+                        bonus: 0,
+                    }),
+            })),
+            "lever" => Some(Interactable::Lever {
+                on: flag("on", false),
+                door: None,
+            }),
+            "chest" => Some(Interactable::Chest {
+                open: flag("open", false),
+            }),
+            "torch" => Some(Interactable::Torch {
+                lit: flag("lit", true),
+            }),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{FileVersion, GameState, RoundCommand, SaveGame};
+
+pub const REPLAY_VERSION: u32 = 1;
+
+/// An executed command together with the dice rolled while executing it and the commands
+/// it expanded into.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReplayEntry {
+    pub state: GameState,
+    pub command: RoundCommand,
+    pub draws: Vec<i32>,
+}
+
+/// The state a game started from and every command executed since.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReplayLog {
+    pub version: u32,
+    pub initial: SaveGame,
+    pub entries: Vec<ReplayEntry>,
+}
+
+impl ReplayLog {
+    pub fn new(initial: SaveGame) -> Self {
+        Self {
+            version: REPLAY_VERSION,
+            initial,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|err| err.to_string())
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let version = serde_json::from_str::<FileVersion>(json).map_err(|err| err.to_string())?;
+        if version.version != REPLAY_VERSION {
+            return Err(format!(
+                "unsupported replay version {}, expected {}",
+                version.version, REPLAY_VERSION
+            ));
+        }
+        let log = serde_json::from_str::<Self>(json).map_err(|err| err.to_string())?;
+        if log.initial.version != crate::SAVE_VERSION {
+            return Err(format!(
+                "unsupported save version {}, expected {}",
+                log.initial.version,
+                crate::SAVE_VERSION
+            ));
+        }
+        Ok(log)
+    }
+
+    pub fn remap_entities(&mut self, map: &HashMap<Entity, Entity>) {
+        self.initial.remap_entities(map);
+        self.entries
+            .retain_mut(|entry| entry.command.variant.remap_entities(map));
+    }
+}
+
+/// Records the commands executed since the game was started or loaded. The log is started
+/// from a snapshot of the game when it is empty.
+#[derive(Resource, Default)]
+pub struct Recorder {
+    pub log: Option<ReplayLog>,
+}
+
+impl Recorder {
+    pub fn push(&mut self, state: GameState, command: &RoundCommand) {
+        let Some(log) = self.log.as_mut() else {
+            return;
+        };
+        if command.derived {
+            return;
+        }
+        log.entries.push(ReplayEntry {
+            state,
+            command: command.clone(),
+            draws: Vec::new(),
+        });
+    }
+
+    pub fn push_draws(&mut self, draws: &[i32]) {
+        let Some(entry) = self.log.as_mut().and_then(|log| log.entries.last_mut()) else {
+            return;
+        };
+        entry.draws.extend_from_slice(draws);
+    }
+}
+
+/// Present while a replay is played back. Its entries are fed to the round one at a time,
+/// each once the previous one has finished executing.
+#[derive(Resource)]
+pub struct Replay {
+    pub entries: Vec<ReplayEntry>,
+    /// Index of the next entry to feed.
+    pub cursor: usize,
+    pub paused: bool,
+    /// Feeds a single entry while paused.
+    pub step: bool,
+    pub speed: f32,
+    /// Dice rolled since the last entry was fed.
+    pub draws: Vec<i32>,
+    pub desynced: bool,
+}
+
+impl Replay {
+    pub fn new(entries: Vec<ReplayEntry>) -> Self {
+        Self {
+            entries,
+            cursor: 0,
+            paused: false,
+            step: false,
+            speed: 1.0,
+            draws: Vec::new(),
+            desynced: false,
+        }
+    }
+
+    /// Compares the dice rolled since the last entry was fed with the recorded ones.
+    pub fn verify_last(&mut self) -> Result<(), String> {
+        let draws = std::mem::take(&mut self.draws);
+        let Some(entry) = self.cursor.checked_sub(1).and_then(|i| self.entries.get(i)) else {
+            return Ok(());
+        };
+        if entry.draws != draws {
+            self.desynced = true;
+            return Err(format!(
+                "command {} rolled {:?}, recorded {:?}",
+                self.cursor - 1,
+                draws,
+                entry.draws
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Dice, Grid, SAVE_VERSION};
+
+    fn initial(seed: u64) -> SaveGame {
+        SaveGame {
+            version: SAVE_VERSION,
+            state: GameState::Exploration,
+            players: Vec::new(),
+            tokens: Vec::new(),
+            grid: Grid::new(2, 2),
+            map_objects: default(),
+            generated_map: default(),
+            floors: default(),
+            vision: default(),
+            round: default(),
+            dice: Dice::new(seed),
+            factions: default(),
+            encounter: default(),
+        }
+    }
+
+    /// Stands in for executing an attack, which rolls to hit and for damage.
+    fn execute(dice: &mut Dice) -> Vec<i32> {
+        dice.d20();
+        dice.roll(8);
+        dice.take_draws()
+    }
+
+    fn record(seed: u64) -> ReplayLog {
+        let save = initial(seed);
+        let mut dice = save.dice.clone();
+        let mut recorder = Recorder {
+            log: Some(ReplayLog::new(save)),
+        };
+        let (fighter, goblin) = (Entity::from_raw(1), Entity::from_raw(2));
+        for command in [
+            RoundCommand::attack(fighter, goblin, 0),
+            RoundCommand::attack(goblin, fighter, 0),
+            RoundCommand::attack(fighter, goblin, 0),
+        ] {
+            recorder.push(GameState::Combat, &command);
+            recorder.push_draws(&execute(&mut dice));
+        }
+        let json = recorder.log.unwrap().to_json().unwrap();
+        ReplayLog::from_json(&json).unwrap()
+    }
+
+    /// Plays the log back from its initial dice, checking the draws after every command.
+    fn play(log: &ReplayLog) -> Replay {
+        let mut dice = log.initial.dice.clone();
+        let mut replay = Replay::new(log.entries.clone());
+        while replay.cursor < replay.entries.len() && !replay.desynced {
+            replay.cursor += 1;
+            replay.draws.extend(execute(&mut dice));
+            let _ = replay.verify_last();
+        }
+        replay
+    }
+
+    #[test]
+    fn replaying_with_the_same_seed_rolls_the_recorded_dice() {
+        let log = record(42);
+        assert_eq!(log.entries.len(), 3);
+        assert!(log.entries.iter().all(|entry| entry.draws.len() == 2));
+
+        let replay = play(&log);
+        assert_eq!(replay.cursor, 3);
+        assert!(!replay.desynced);
+
+        // but not with another seed
+        let mut reseeded = log.clone();
+        reseeded.initial.dice = Dice::new(7);
+        assert!(play(&reseeded).desynced);
+    }
+
+    #[test]
+    fn verify_last_reports_a_tampered_draw() {
+        let mut log = record(42);
+        let recorded = log.entries[1].draws.clone();
+        log.entries[1].draws[0] = recorded[0] % 20 + 1;
+        let mut replay = play(&log);
+        assert!(replay.desynced);
+        assert_eq!(replay.cursor, 2);
+
+        replay.draws = recorded.clone();
+        let error = replay.verify_last().err();
+        assert_eq!(
+            error,
+            Some(format!(
+                "command 1 rolled {:?}, recorded {:?}",
+                recorded, log.entries[1].draws
+            ))
+        );
+    }
+}
@@ -1,7 +1,13 @@
-use bevy::{prelude::*, utils::HashMap};
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
 use glam::IVec2;
+use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 
+use crate::{DiceExpr, Recorder};
+
 #[derive(Resource)]
 pub struct Settings {
     pub pan_speed: f32,
@@ -14,6 +20,28 @@ pub struct Settings {
     pub rotate_right: KeyCode,
     pub rotate_speed: f32,
     pub auto_pan_speed: f32,
+    /// Short rest for the selected creature spending one hit die, or for the whole party
+    /// spending none. Pressing it again spends another hit die.
+    pub short_rest: KeyCode,
+    pub long_rest: KeyCode,
+    pub sneak: KeyCode,
+    pub hide: KeyCode,
+    pub search: KeyCode,
+    pub interact: KeyCode,
+    pub check: KeyCode,
+    pub use_item: KeyCode,
+    /// Use the activated features of the selected creature, in the order of its statblock.
+    pub feature_keys: Vec<KeyCode>,
+    pub quick_save: KeyCode,
+    pub quick_load: KeyCode,
+    pub save_characters: KeyCode,
+    pub undo: KeyCode,
+    pub save_replay: KeyCode,
+    pub load_replay: KeyCode,
+    pub replay_pause: KeyCode,
+    pub replay_step: KeyCode,
+    pub replay_faster: KeyCode,
+    pub replay_slower: KeyCode,
 }
 
 impl Default for Settings {
@@ -29,17 +57,94 @@ impl Default for Settings {
             pan_down: KeyCode::S,
             rotate_left: KeyCode::Q,
             rotate_right: KeyCode::E,
+            short_rest: KeyCode::R,
+            long_rest: KeyCode::L,
+            sneak: KeyCode::Z,
+            hide: KeyCode::H,
+            search: KeyCode::X,
+            interact: KeyCode::F,
+            check: KeyCode::C,
+            use_item: KeyCode::U,
+            feature_keys: vec![KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4],
+            quick_save: KeyCode::F5,
+            quick_load: KeyCode::F9,
+            save_characters: KeyCode::F6,
+            undo: KeyCode::Back,
+            save_replay: KeyCode::F7,
+            load_replay: KeyCode::F8,
+            replay_pause: KeyCode::P,
+            replay_step: KeyCode::N,
+            replay_faster: KeyCode::Equals,
+            replay_slower: KeyCode::Minus,
         }
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Variant {
     Nop,
     MoveTo { who: Entity, to: IVec2 },
     MoveFar { who: Entity, to: IVec2 },
+    Attack { who: Entity, target: Entity, attack: usize, reaction: bool },
     EndTurn { who: Entity },
     RecvTurn { who: Entity },
     EndRound {},
+    BeginCombat {},
+    EndCombat {},
+    /// Spends up to `hit_dice` hit dice, stopping once back to full health.
+    ShortRest { who: Entity, hit_dice: u32 },
+    LongRest { who: Entity },
+    Sneak { who: Entity, sneaking: bool },
+    Hide { who: Entity },
+    Search { who: Entity },
+    Undo {},
+    Interact { who: Entity, cell: IVec2 },
+    /// An ability check against a DC, about whatever is on `cell` if given.
+    Check { who: Entity, check: crate::Check, dc: i32, cell: Option<IVec2> },
+    /// Uses up one of a consumable item, such as drinking a potion.
+    UseItem { who: Entity, item: String },
+    /// Uses an activated feature from `features/`, such as Second Wind.
+    UseFeature { who: Entity, feature: String },
+}
+
+impl Variant {
+    /// Returns false if an entity it refers to wasn't respawned, so the command is dropped.
+    pub fn remap_entities(&mut self, map: &HashMap<Entity, Entity>) -> bool {
+        match self {
+            Variant::Nop
+            | Variant::EndRound {}
+            | Variant::BeginCombat {}
+            | Variant::EndCombat {}
+            | Variant::Undo {} => true,
+            Variant::MoveTo { who, .. }
+            | Variant::MoveFar { who, .. }
+            | Variant::EndTurn { who }
+            | Variant::RecvTurn { who }
+            | Variant::ShortRest { who, .. }
+            | Variant::LongRest { who }
+            | Variant::Sneak { who, .. }
+            | Variant::Hide { who }
+            | Variant::Search { who }
+            | Variant::Interact { who, .. }
+            | Variant::Check { who, .. }
+            | Variant::UseItem { who, .. }
+            | Variant::UseFeature { who, .. } => remap_in_place(who, map),
+            Variant::Attack { who, target, .. } => {
+                remap_in_place(who, map) && remap_in_place(target, map)
+            }
+        }
+    }
+}
+
+impl Variant {
+    /// Commands that only move tokens around can be taken back. Anything else, such as an
+    /// attack or a roll, commits the moves made so far.
+    pub fn is_reversible(&self) -> bool {
+        matches!(
+            self,
+            Variant::Nop | Variant::MoveTo { .. } | Variant::MoveFar { .. } | Variant::Undo {}
+        )
+    }
 }
 
 impl Default for Variant {
@@ -48,12 +153,15 @@ impl Default for Variant {
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct RoundCommand {
     pub timer: f32,
     pub timer_elapsed_sec: f32,
     pub parallel: bool,
     pub variant: Variant,
+    /// Pushed while executing another command, so it isn't recorded on its own.
+    #[serde(default)]
+    pub derived: bool,
 }
 
 impl RoundCommand {
@@ -80,6 +188,22 @@ impl RoundCommand {
         }
     }
 
+    pub fn attack(who: Entity, target: Entity, attack: usize) -> Self {
+        Self {
+            timer: 0.5,
+            variant: Variant::Attack { who, target, attack, reaction: false },
+            ..Default::default()
+        }
+    }
+
+    pub fn opportunity_attack(who: Entity, target: Entity, attack: usize) -> Self {
+        Self {
+            timer: 0.5,
+            variant: Variant::Attack { who, target, attack, reaction: true },
+            ..Default::default()
+        }
+    }
+
     pub fn end_turn(who: Entity) -> Self {
         Self {
             variant: Variant::EndTurn { who },
@@ -103,6 +227,112 @@ impl RoundCommand {
             ..Default::default()
         }
     }
+
+    pub fn begin_combat() -> Self {
+        Self {
+            timer: 0.25,
+            variant: Variant::BeginCombat {},
+            ..Default::default()
+        }
+    }
+
+    pub fn end_combat() -> Self {
+        Self {
+            timer: 0.25,
+            variant: Variant::EndCombat {},
+            ..Default::default()
+        }
+    }
+
+    pub fn short_rest(who: Entity, hit_dice: u32) -> Self {
+        Self {
+            timer: 0.5,
+            variant: Variant::ShortRest { who, hit_dice },
+            ..Default::default()
+        }
+    }
+
+    pub fn long_rest(who: Entity) -> Self {
+        Self {
+            timer: 0.5,
+            variant: Variant::LongRest { who },
+            ..Default::default()
+        }
+    }
+
+    pub fn sneak(who: Entity, sneaking: bool) -> Self {
+        Self {
+            variant: Variant::Sneak { who, sneaking },
+            ..Default::default()
+        }
+    }
+
+    pub fn hide(who: Entity) -> Self {
+        Self {
+            timer: 0.3,
+            variant: Variant::Hide { who },
+            ..Default::default()
+        }
+    }
+
+    pub fn search(who: Entity) -> Self {
+        Self {
+            timer: 0.3,
+            variant: Variant::Search { who },
+            ..Default::default()
+        }
+    }
+
+    pub fn interact(who: Entity, cell: IVec2) -> Self {
+        Self {
+            timer: 0.3,
+            variant: Variant::Interact { who, cell },
+            ..Default::default()
+        }
+    }
+
+    pub fn check(who: Entity, check: crate::Check, dc: i32, cell: Option<IVec2>) -> Self {
+        Self {
+            timer: 0.3,
+            variant: Variant::Check {
+                who,
+                check,
+                dc,
+                cell,
+            },
+            ..Default::default()
+        }
+    }
+
+    pub fn use_item(who: Entity, item: String) -> Self {
+        Self {
+            timer: 0.3,
+            variant: Variant::UseItem { who, item },
+            ..Default::default()
+        }
+    }
+
+    pub fn use_feature(who: Entity, feature: String) -> Self {
+        Self {
+            timer: 0.3,
+            variant: Variant::UseFeature { who, feature },
+            ..Default::default()
+        }
+    }
+
+    pub fn undo() -> Self {
+        Self {
+            timer: 0.1,
+            variant: Variant::Undo {},
+            ..Default::default()
+        }
+    }
+
+    pub fn derived(mut self) -> Self {
+        self.derived = true;
+        self
+    }
+
     pub fn alpha(&self) -> f32 {
         if self.timer == 0.0 {
             return 1.0;
@@ -118,16 +348,34 @@ impl RoundCommand {
     }
 }
 
-#[derive(Resource, Default)]
+/// What a reversible command changed, restored when it is undone.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UndoSnapshot {
+    pub who: Entity,
+    pub grid_pos: IVec2,
+    pub movement_ft: f32,
+}
+
+#[derive(Resource, Default, Clone, Serialize, Deserialize)]
 pub struct Round {
     commands: VecDeque<RoundCommand>,
     pub active_entity: Option<Entity>,
     pub initiative_order: Vec<Entity>,
+    pub initiative: HashMap<Entity, i32>,
     pub has_taken_turn: HashMap<Entity, ()>,
+    /// Skip their first turn and can't react until that turn ends.
+    pub surprised: HashMap<Entity, ()>,
     pub round_num: u64,
+    /// Moves that can still be undone, cleared by any irreversible command.
+    #[serde(default)]
+    pub undo: Vec<UndoSnapshot>,
 }
 
 impl Round {
+    pub fn front(&self) -> Option<&RoundCommand> {
+        self.commands.front()
+    }
+
     pub fn front_mut(&mut self) -> Option<&mut RoundCommand> {
         self.commands.front_mut()
     }
@@ -140,6 +388,10 @@ impl Round {
         self.commands.push_back(command);
     }
 
+    pub fn clear_commands(&mut self) {
+        self.commands.clear();
+    }
+
     pub fn pop_front(&mut self) -> Option<RoundCommand> {
         self.commands.pop_front()
     }
@@ -147,28 +399,376 @@ impl Round {
     pub fn is_executing(&self) -> bool {
         !self.commands.is_empty()
     }
+
+    pub fn can_react(&self, entity: Entity) -> bool {
+        !self.surprised.contains_key(&entity)
+    }
+
+    pub fn remap_entities(&mut self, map: &HashMap<Entity, Entity>) {
+        self.commands.retain_mut(|command| command.variant.remap_entities(map));
+        self.active_entity = self.active_entity.and_then(|e| remap(e, map));
+        self.initiative_order.retain_mut(|e| remap_in_place(e, map));
+        self.initiative = self
+            .initiative
+            .drain()
+            .filter_map(|(e, v)| Some((remap(e, map)?, v)))
+            .collect();
+        self.has_taken_turn = self
+            .has_taken_turn
+            .drain()
+            .filter_map(|(e, v)| Some((remap(e, map)?, v)))
+            .collect();
+        self.surprised = self
+            .surprised
+            .drain()
+            .filter_map(|(e, v)| Some((remap(e, map)?, v)))
+            .collect();
+        self.undo.retain_mut(|snapshot| remap_in_place(&mut snapshot.who, map));
+    }
 }
 
+/// Deterministic dice roller using splitmix64, so the state is a single number.
+#[derive(Resource, Clone, Serialize, Deserialize)]
+pub struct Dice {
+    state: u64,
+    /// Results rolled since the last `take_draws`, kept for the replay log.
+    #[serde(skip)]
+    draws: Vec<i32>,
+}
+
+impl Default for Dice {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl Dice {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: seed,
+            draws: Vec::new(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Rolls a single die with the given number of sides, returning 1..=sides.
+    pub fn roll(&mut self, sides: u32) -> i32 {
+        if sides == 0 {
+            return 0;
+        }
+        let result = (self.next_u64() % sides as u64) as i32 + 1;
+        self.draws.push(result);
+        result
+    }
+
+    pub fn take_draws(&mut self) -> Vec<i32> {
+        std::mem::take(&mut self.draws)
+    }
+
+    pub fn d20(&mut self) -> i32 {
+        self.roll(20)
+    }
+
+    pub fn roll_expr(&mut self, expr: &DiceExpr) -> i32 {
+        let mut total = expr.bonus;
+        for _ in 0..expr.count {
+            total += self.roll(expr.sides);
+        }
+        total
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum Attitude {
+    Ally,
+    #[default]
+    Neutral,
+    Hostile,
+}
+
+/// Relationship matrix between factions. Members of the same faction are allies,
+/// unknown pairs are neutral.
+#[derive(Resource, Default, Clone, Serialize, Deserialize)]
+#[serde(from = "Vec<(String, String, Attitude)>", into = "Vec<(String, String, Attitude)>")]
+pub struct Factions {
+    relations: HashMap<(String, String), Attitude>,
+}
+
+impl From<Vec<(String, String, Attitude)>> for Factions {
+    fn from(value: Vec<(String, String, Attitude)>) -> Self {
+        let mut factions = Self::default();
+        for (a, b, attitude) in value {
+            factions.set(&a, &b, attitude);
+        }
+        factions
+    }
+}
+
+impl From<Factions> for Vec<(String, String, Attitude)> {
+    fn from(value: Factions) -> Self {
+        value
+            .relations
+            .into_iter()
+            .filter(|((a, b), _)| a < b)
+            .map(|((a, b), attitude)| (a, b, attitude))
+            .collect()
+    }
+}
+
+impl Factions {
+    pub fn set(&mut self, a: &str, b: &str, attitude: Attitude) {
+        self.relations.insert((a.into(), b.into()), attitude);
+        self.relations.insert((b.into(), a.into()), attitude);
+    }
+
+    pub fn attitude(&self, a: &str, b: &str) -> Attitude {
+        if a == b {
+            return Attitude::Ally;
+        }
+        self.relations
+            .get(&(a.to_string(), b.to_string()))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Objective {
+    /// Won once no creature hostile to the encounter faction is left standing.
+    DefeatAllHostiles,
+    /// Won once the given number of combat rounds has passed.
+    SurviveRounds { rounds: u64 },
+    /// Won once a member of the encounter faction stands on the cell.
+    ReachCell { cell: IVec2 },
+    /// Lost if the token is defeated.
+    Protect { who: Entity },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Outcome {
+    Victory,
+    Defeat,
+}
+
+/// Objectives of the current encounter as seen from `faction`. Any completed goal wins
+/// the encounter, while losing a protected token or the whole faction loses it.
+#[derive(Resource, Default, Clone, Serialize, Deserialize)]
+pub struct Encounter {
+    pub faction: String,
+    pub objectives: Vec<Objective>,
+    pub outcome: Option<Outcome>,
+    /// Light everywhere on the map before light sources are added.
+    #[serde(default)]
+    pub ambient_light: LightLevel,
+    /// Set once the XP for winning has gone to the player characters.
+    #[serde(default)]
+    pub xp_awarded: bool,
+}
+
+impl Encounter {
+    pub fn remap_entities(&mut self, map: &HashMap<Entity, Entity>) {
+        self.objectives.retain_mut(|objective| match objective {
+            Objective::Protect { who } => remap_in_place(who, map),
+            _ => true,
+        });
+    }
+}
+
+/// Which file in `encounters/` to play, picked on the command line.
+#[derive(Resource)]
+pub struct SelectedEncounter {
+    pub id: String,
+    /// Overrides the seed of a generated map, to play a dungeon again.
+    pub map_seed: Option<u64>,
+}
+
+impl Default for SelectedEncounter {
+    fn default() -> Self {
+        Self {
+            id: "goblin_ambush".into(),
+            map_seed: None,
+        }
+    }
+}
+
+/// A floor other than the current one, kept as it was left. Its creatures are kept as
+/// `StoredToken`s.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Floor {
+    pub grid: Grid,
+    pub map_objects: MapObjects,
+    #[serde(default)]
+    pub vision: Vision,
+}
+
+/// The floors of a dungeon. Only the current one is in `Grid` and `MapObjects`.
+#[derive(Resource, Default, Clone, Serialize, Deserialize)]
+pub struct Floors {
+    pub current: usize,
+    pub stored: HashMap<usize, Floor>,
+}
+
+impl Floors {
+    pub fn remap_entities(&mut self, map: &HashMap<Entity, Entity>) {
+        for floor in self.stored.values_mut() {
+            floor.grid.remap_entities(map);
+        }
+    }
+}
+
+/// What a faction sees right now and what it has seen of the map before.
+#[derive(Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FactionVision {
+    pub visible: HashSet<IVec2>,
+    pub explored: HashSet<IVec2>,
+    /// Tokens hidden from every creature of the faction, recomputed every frame.
+    #[serde(skip)]
+    pub hidden: HashSet<Entity>,
+}
+
+/// Vision of the current map per faction, shared by all tokens fighting for it.
+#[derive(Resource, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Vision {
+    pub factions: HashMap<String, FactionVision>,
+}
+
+impl Vision {
+    pub fn sees(&self, faction: &str, cell: IVec2) -> bool {
+        self.factions
+            .get(faction)
+            .is_some_and(|vision| vision.visible.contains(&cell))
+    }
+
+    /// Whether the faction sees the token on `cell`, which it doesn't if the token hid from it.
+    pub fn sees_token(&self, faction: &str, entity: Entity, cell: IVec2) -> bool {
+        self.factions
+            .get(faction)
+            .is_some_and(|vision| vision.visible.contains(&cell) && !vision.hidden.contains(&entity))
+    }
+
+    pub fn has_explored(&self, faction: &str, cell: IVec2) -> bool {
+        self.factions
+            .get(faction)
+            .is_some_and(|vision| vision.explored.contains(&cell))
+    }
+}
+
+/// How the current map was generated, with the seed that was used, if it was generated.
+#[derive(Resource, Default, Clone, Serialize, Deserialize)]
+pub struct GeneratedMap(pub Option<crate::MapgenConfig>);
+
+/// Doors, lights and other objects placed on the current map.
+#[derive(Resource, Default, Clone, Serialize, Deserialize)]
+pub struct MapObjects(pub Vec<crate::MapObject>);
+
 use array2d::Array2D;
 
-#[derive(Default, Clone)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum Terrain {
+    #[default]
+    Normal,
+    Difficult,
+    Water,
+}
+
+/// How well a cell is lit. Dim light lightly obscures it, darkness heavily obscures it.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LightLevel {
+    Dark,
+    Dim,
+    #[default]
+    Bright,
+}
+
+/// Where a staircase leads.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Stairs {
+    pub floor: usize,
+    pub cell: IVec2,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct GridCell {
     pub blocked: bool,
     pub walkable: bool,
     pub entity: Option<Entity>,
+    #[serde(default)]
+    pub terrain: Terrain,
+    #[serde(default)]
+    pub stairs: Option<Stairs>,
+    #[serde(default)]
+    pub object: Option<crate::Interactable>,
+    #[serde(default)]
+    pub light: LightLevel,
 }
 
-#[derive(Resource)]
+#[derive(Resource, Clone, Serialize, Deserialize)]
+#[serde(into = "GridData", try_from = "GridData")]
 pub struct Grid {
-    size: usize,
+    width: usize,
+    height: usize,
     cells: Array2D<GridCell>,
 }
 
+/// Flat representation of the grid used for serialization.
+#[derive(Serialize, Deserialize)]
+struct GridData {
+    width: usize,
+    height: usize,
+    cells: Vec<GridCell>,
+}
+
+impl From<Grid> for GridData {
+    fn from(grid: Grid) -> Self {
+        let mut cells = Vec::with_capacity(grid.width * grid.height);
+        for x in 0..grid.width {
+            for y in 0..grid.height {
+                cells.push(grid.cells.get(x, y).cloned().unwrap_or_default());
+            }
+        }
+        Self {
+            width: grid.width,
+            height: grid.height,
+            cells,
+        }
+    }
+}
+
+impl TryFrom<GridData> for Grid {
+    type Error = String;
+
+    fn try_from(data: GridData) -> Result<Self, Self::Error> {
+        if data.cells.len() != data.width * data.height {
+            return Err(format!(
+                "expected {} cells, found {}",
+                data.width * data.height,
+                data.cells.len()
+            ));
+        }
+        let mut grid = Grid::new(data.width, data.height);
+        for (i, cell) in data.cells.into_iter().enumerate() {
+            if let Some(c) = grid.cells.get_mut(i / data.height, i % data.height) {
+                *c = cell;
+            }
+        }
+        Ok(grid)
+    }
+}
+
 impl Grid {
-    pub fn new(size: usize) -> Self {
+    pub fn new(width: usize, height: usize) -> Self {
         Self {
-            size,
-            cells: Array2D::filled_with(GridCell::default(), size, size),
+            width,
+            height,
+            cells: Array2D::filled_with(GridCell::default(), width, height),
         }
     }
 
@@ -188,8 +788,12 @@ impl Grid {
         None
     }
 
-    pub fn size(&self) -> usize {
-        self.size
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
     }
 
     pub fn is_walkable(&self, i: IVec2) -> bool {
@@ -205,6 +809,61 @@ impl Grid {
         }
         false
     }
+
+    pub fn terrain(&self, i: IVec2) -> Terrain {
+        if let Some(cell) = self.get(i) {
+            return cell.terrain;
+        }
+        Terrain::Normal
+    }
+
+    /// Cells outside the grid are dark.
+    pub fn light(&self, i: IVec2) -> LightLevel {
+        if let Some(cell) = self.get(i) {
+            return cell.light;
+        }
+        LightLevel::Dark
+    }
+
+    pub fn stairs(&self, i: IVec2) -> Option<Stairs> {
+        self.get(i).and_then(|cell| cell.stairs)
+    }
+
+    pub fn object(&self, i: IVec2) -> Option<crate::Interactable> {
+        self.get(i).and_then(|cell| cell.object)
+    }
+
+    /// Closed and locked doors stop movement and sight.
+    pub fn is_closed_door(&self, i: IVec2) -> bool {
+        self.object(i).is_some_and(|object| object.is_closed_door())
+    }
+
+    pub fn remap_entities(&mut self, map: &HashMap<Entity, Entity>) {
+        for x in 0..self.width {
+            for y in 0..self.height {
+                if let Some(cell) = self.cells.get_mut(x, y) {
+                    cell.entity = cell.entity.and_then(|e| remap(e, map));
+                }
+            }
+        }
+    }
+}
+
+/// Looks up the entity an old entity was respawned as. References to entities that weren't
+/// respawned are dropped, the old entity could be anything by now.
+pub(crate) fn remap(entity: Entity, map: &HashMap<Entity, Entity>) -> Option<Entity> {
+    map.get(&entity).copied()
+}
+
+/// Remaps the entity, returning false if it has to be dropped.
+pub(crate) fn remap_in_place(entity: &mut Entity, map: &HashMap<Entity, Entity>) -> bool {
+    match remap(*entity, map) {
+        Some(remapped) => {
+            *entity = remapped;
+            true
+        }
+        None => false,
+    }
 }
 
 #[derive(Resource, Default)]
@@ -247,8 +906,20 @@ impl CommonAssets {
 
 
 pub fn build(app: &mut App) {
-    app.insert_resource(Grid::new(0));
+    app.insert_resource(Grid::new(0, 0));
     app.insert_resource(CommonAssets::default());
     app.insert_resource(Round::default());
     app.insert_resource(Settings::default());
+    app.insert_resource(Dice::default());
+    app.insert_resource(Factions::default());
+    app.insert_resource(Encounter::default());
+    app.insert_resource(Recorder::default());
+    app.insert_resource(SelectedEncounter::default());
+    app.insert_resource(MapObjects::default());
+    app.insert_resource(GeneratedMap::default());
+    app.insert_resource(Floors::default());
+    app.insert_resource(Vision::default());
+    app.insert_resource(crate::ItemCatalog::default());
+    app.insert_resource(crate::ClassCatalog::default());
+    app.insert_resource(crate::FeatureCatalog::default());
 }
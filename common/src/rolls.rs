@@ -0,0 +1,344 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Ability, Dice, DiceExpr};
+
+/// Kind of roll, for the effects that only change some of them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RollKind {
+    Attack,
+    Damage,
+    /// Ability checks, including skills, tools and initiative.
+    Check,
+    SavingThrow,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum RollMode {
+    #[default]
+    Normal,
+    Advantage,
+    Disadvantage,
+}
+
+impl RollMode {
+    /// Advantage and disadvantage cancel each other out.
+    pub fn from_sources(advantage: bool, disadvantage: bool) -> Self {
+        match (advantage, disadvantage) {
+            (true, false) => RollMode::Advantage,
+            (false, true) => RollMode::Disadvantage,
+            _ => RollMode::Normal,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ModifierKind {
+    /// Added to the roll, e.g. an ability modifier or proficiency.
+    Bonus(i32),
+    /// Dice added to the roll, or taken off it with `penalty`, e.g. 1d4 from Bless.
+    Dice { dice: DiceExpr, penalty: bool },
+    Advantage,
+    Disadvantage,
+    /// Rolled dice showing this or lower are rolled again once, keeping the new result.
+    Reroll { at_most: i32 },
+    /// Added to the number the roll has to meet, e.g. +2 AC from half cover.
+    Target(i32),
+}
+
+/// Something that changes a roll, with what it comes from.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Modifier {
+    /// E.g. "Bless" or "half cover".
+    pub source: String,
+    pub kind: ModifierKind,
+    /// What it came to once rolled: the bonus, the dice rolled (negative for a penalty) or
+    /// the first result that was rerolled, 0 if none was.
+    pub value: i32,
+}
+
+impl Display for Modifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            ModifierKind::Bonus(bonus) | ModifierKind::Target(bonus) => {
+                write!(f, "{:+} {}", bonus, self.source)
+            }
+            ModifierKind::Dice { dice, penalty } => {
+                let sign = if penalty { '-' } else { '+' };
+                write!(f, "{}{} {} ({:+})", sign, dice, self.source, self.value)
+            }
+            ModifierKind::Advantage => write!(f, "advantage ({})", self.source),
+            ModifierKind::Disadvantage => write!(f, "disadvantage ({})", self.source),
+            ModifierKind::Reroll { .. } => write!(f, "rerolled {} ({})", self.value, self.source),
+        }
+    }
+}
+
+/// A roll put together from the modifiers of everyone and everything involved, e.g. a
+/// creature's conditions and features or the cover of its target. Once rolled it tells how
+/// the total came about, modifier by modifier.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Roll {
+    pub kind: RollKind,
+    /// The ability the roll is made with, if any.
+    pub ability: Option<Ability>,
+    /// What is rolled before any modifier, a d20 for all but damage.
+    pub dice: DiceExpr,
+    pub modifiers: Vec<Modifier>,
+    /// The result of `dice` after rerolls, and keeping the higher or lower d20.
+    pub natural: i32,
+    pub total: i32,
+}
+
+impl Roll {
+    /// A d20 roll.
+    pub fn new(kind: RollKind, ability: Option<Ability>) -> Self {
+        Self {
+            kind,
+            ability,
+            dice: DiceExpr {
+                count: 1,
+                sides: 20,
+                bonus: 0,
+            },
+            modifiers: Vec::new(),
+            natural: 0,
+            total: 0,
+        }
+    }
+
+    pub fn damage(dice: DiceExpr) -> Self {
+        Self {
+            dice,
+            ..Self::new(RollKind::Damage, None)
+        }
+    }
+
+    pub fn add(&mut self, source: impl Into<String>, kind: ModifierKind) {
+        self.modifiers.push(Modifier {
+            source: source.into(),
+            kind,
+            value: 0,
+        });
+    }
+
+    /// Adds a flat bonus, leaving out those that add nothing.
+    pub fn bonus(&mut self, source: impl Into<String>, bonus: i32) {
+        if bonus != 0 {
+            self.add(source, ModifierKind::Bonus(bonus));
+        }
+    }
+
+    /// Whether an effect on the kinds of roll, and only with the ability if given, applies.
+    pub fn is_one_of(&self, kinds: &[RollKind], ability: Option<Ability>) -> bool {
+        kinds.contains(&self.kind) && (ability.is_none() || ability == self.ability)
+    }
+
+    pub fn mode(&self) -> RollMode {
+        let has = |kind| self.modifiers.iter().any(|modifier| modifier.kind == kind);
+        RollMode::from_sources(has(ModifierKind::Advantage), has(ModifierKind::Disadvantage))
+    }
+
+    /// What the target number of the roll is raised by.
+    pub fn target_bonus(&self) -> i32 {
+        self.modifiers
+            .iter()
+            .map(|modifier| match modifier.kind {
+                ModifierKind::Target(bonus) => bonus,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Whether the total meets the target number, e.g. an armor class or DC, with the
+    /// modifiers to it.
+    pub fn meets(&self, target: i32) -> bool {
+        self.total >= target + self.target_bonus()
+    }
+
+    /// Rolls the dice, two d20s with advantage or disadvantage, then rerolls and the other
+    /// modifiers in the order they were added. Of several rerolls the one with the highest
+    /// `at_most` applies, the others record nothing. Returns the total.
+    pub fn roll(&mut self, dice: &mut Dice) -> i32 {
+        let (at_most, reroll) = self
+            .modifiers
+            .iter()
+            .enumerate()
+            .filter_map(|(i, modifier)| match modifier.kind {
+                ModifierKind::Reroll { at_most } => Some((at_most, Some(i))),
+                _ => None,
+            })
+            .max()
+            .unwrap_or((0, None));
+        let sides = self.dice.sides;
+        let mut rerolled = 0;
+        let mut roll_die = |dice: &mut Dice| {
+            let result = dice.roll(sides);
+            if result <= at_most {
+                if rerolled == 0 {
+                    rerolled = result;
+                }
+                return dice.roll(sides);
+            }
+            result
+        };
+
+        let mode = self.mode();
+        self.natural = if self.kind == RollKind::Damage || mode == RollMode::Normal {
+            (0..self.dice.count).map(|_| roll_die(dice)).sum()
+        } else {
+            let (a, b) = (roll_die(dice), roll_die(dice));
+            if mode == RollMode::Advantage {
+                a.max(b)
+            } else {
+                a.min(b)
+            }
+        };
+        if let Some(i) = reroll {
+            self.modifiers[i].value = rerolled;
+        }
+
+        self.total = self.natural + self.dice.bonus;
+        for modifier in self.modifiers.iter_mut() {
+            match modifier.kind {
+                ModifierKind::Bonus(bonus) | ModifierKind::Target(bonus) => modifier.value = bonus,
+                ModifierKind::Dice { dice: extra, penalty } => {
+                    let rolled = dice.roll_expr(&extra);
+                    modifier.value = if penalty { -rolled } else { rolled };
+                }
+                _ => continue,
+            }
+            if !matches!(modifier.kind, ModifierKind::Target(_)) {
+                self.total += modifier.value;
+            }
+        }
+        self.total
+    }
+}
+
+/// E.g. "19 (1d20: 14, +5 Longsword, +1d4 Bless (+2), advantage (unseen), +2 half cover)".
+impl Display for Roll {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}: {}", self.total, self.dice, self.natural)?;
+        for modifier in self.modifiers.iter() {
+            // rerolls that didn't come up are left out
+            if matches!(modifier.kind, ModifierKind::Reroll { .. }) && modifier.value == 0 {
+                continue;
+            }
+            write!(f, ", {}", modifier)?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A seed whose first d20 comes up with the result.
+    fn seed_rolling(result: i32) -> u64 {
+        (0..)
+            .find(|seed| Dice::new(*seed).roll(20) == result)
+            .unwrap()
+    }
+
+    #[test]
+    fn advantage_keeps_the_higher_d20_and_disadvantage_the_lower() {
+        for seed in 0..20 {
+            let mut expected = Dice::new(seed);
+            let (a, b) = (expected.roll(20), expected.roll(20));
+
+            let mut roll = Roll::new(RollKind::Attack, None);
+            roll.add("hidden", ModifierKind::Advantage);
+            roll.roll(&mut Dice::new(seed));
+            assert_eq!(roll.natural, a.max(b));
+
+            let mut roll = Roll::new(RollKind::Attack, None);
+            roll.add("target unseen", ModifierKind::Disadvantage);
+            roll.roll(&mut Dice::new(seed));
+            assert_eq!(roll.natural, a.min(b));
+
+            // both cancel out to a single d20
+            roll.add("hidden", ModifierKind::Advantage);
+            roll.roll(&mut Dice::new(seed));
+            assert_eq!(roll.natural, a);
+        }
+    }
+
+    #[test]
+    fn modifiers_add_to_the_total_and_target_modifiers_to_the_target() {
+        let mut expected = Dice::new(3);
+        let natural = expected.roll(20);
+        let bane = expected.roll(4);
+
+        let mut roll = Roll::new(RollKind::Attack, None);
+        roll.bonus("Longsword", 5);
+        roll.bonus("nothing", 0);
+        roll.add(
+            "Bane",
+            ModifierKind::Dice {
+                dice: "1d4".parse().unwrap(),
+                penalty: true,
+            },
+        );
+        roll.add("half cover", ModifierKind::Target(2));
+        let total = roll.roll(&mut Dice::new(3));
+
+        assert_eq!(roll.modifiers.len(), 3);
+        assert_eq!(roll.modifiers[1].value, -bane);
+        assert_eq!(total, natural + 5 - bane);
+        assert!(roll.meets(total - 2));
+        assert!(!roll.meets(total - 1));
+    }
+
+    #[test]
+    fn reroll_rolls_low_dice_again_once() {
+        let seed = seed_rolling(1);
+        let mut expected = Dice::new(seed);
+        expected.roll(20);
+        let second = expected.roll(20);
+
+        let mut roll = Roll::new(RollKind::Check, None);
+        roll.add("Lucky", ModifierKind::Reroll { at_most: 1 });
+        roll.roll(&mut Dice::new(seed));
+        assert_eq!(roll.natural, second);
+        assert_eq!(roll.modifiers[0].value, 1);
+
+        // a 2 is kept when only 1s are rerolled
+        let mut roll = Roll::new(RollKind::Check, None);
+        roll.add("Lucky", ModifierKind::Reroll { at_most: 1 });
+        roll.roll(&mut Dice::new(seed_rolling(2)));
+        assert_eq!(roll.natural, 2);
+        assert_eq!(roll.to_string(), "2 (1d20: 2)");
+    }
+
+    #[test]
+    fn several_rerolls_use_the_highest_at_most() {
+        let seed = seed_rolling(2);
+        let mut expected = Dice::new(seed);
+        expected.roll(20);
+        let second = expected.roll(20);
+
+        let mut roll = Roll::new(RollKind::Damage, None);
+        roll.add("Lucky", ModifierKind::Reroll { at_most: 1 });
+        roll.add("Great Weapon Fighting", ModifierKind::Reroll { at_most: 2 });
+        roll.roll(&mut Dice::new(seed));
+        assert_eq!(roll.natural, second);
+        assert_eq!(roll.modifiers[0].value, 0);
+        assert_eq!(roll.modifiers[1].value, 2);
+    }
+
+    #[test]
+    fn damage_rolls_every_die_even_with_advantage() {
+        let mut expected = Dice::new(11);
+        let natural = expected.roll(6) + expected.roll(6);
+
+        let mut roll = Roll::damage("2d6+3".parse().unwrap());
+        roll.add("hidden", ModifierKind::Advantage);
+        let total = roll.roll(&mut Dice::new(11));
+        assert_eq!(roll.natural, natural);
+        assert_eq!(total, natural + 3);
+    }
+}
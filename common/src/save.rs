@@ -0,0 +1,253 @@
+use bevy::{prelude::Entity, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Dice, Encounter, Factions, Floors, GameState, GeneratedMap, Grid, MapObjects, Player, Round,
+    Token, Vision,
+};
+
+pub const SAVE_VERSION: u32 = 2;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedPlayer {
+    pub id: Entity,
+    pub player: Player,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedToken {
+    pub id: Entity,
+    pub token: Token,
+    /// Set for creatures on a floor other than the current one.
+    #[serde(default)]
+    pub floor: Option<usize>,
+    /// Missing for creatures whose statblock hadn't been applied yet.
+    #[serde(default)]
+    pub inventory: Option<crate::Inventory>,
+}
+
+/// Everything needed to rebuild a game in progress. Entities are stored by the id they had
+/// when saved and must be remapped to the respawned entities when loading.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SaveGame {
+    pub version: u32,
+    pub state: GameState,
+    pub players: Vec<SavedPlayer>,
+    pub tokens: Vec<SavedToken>,
+    pub grid: Grid,
+    #[serde(default)]
+    pub map_objects: MapObjects,
+    #[serde(default)]
+    pub generated_map: GeneratedMap,
+    #[serde(default)]
+    pub floors: Floors,
+    #[serde(default)]
+    pub vision: Vision,
+    pub round: Round,
+    pub dice: Dice,
+    pub factions: Factions,
+    pub encounter: Encounter,
+}
+
+/// Reads only the version of a file, so it can be checked before the rest is parsed.
+#[derive(Deserialize)]
+pub(crate) struct FileVersion {
+    pub version: u32,
+}
+
+impl SaveGame {
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|err| err.to_string())
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let version = serde_json::from_str::<FileVersion>(json).map_err(|err| err.to_string())?;
+        if version.version != SAVE_VERSION {
+            return Err(format!(
+                "unsupported save version {}, expected {}",
+                version.version, SAVE_VERSION
+            ));
+        }
+        serde_json::from_str(json).map_err(|err| err.to_string())
+    }
+
+    pub fn remap_entities(&mut self, map: &HashMap<Entity, Entity>) {
+        // every saved player and token is in the map, it's built from them
+        for player in self.players.iter_mut() {
+            crate::remap_in_place(&mut player.id, map);
+        }
+        for token in self.tokens.iter_mut() {
+            crate::remap_in_place(&mut token.id, map);
+            token.token.remap_entities(map);
+        }
+        self.grid.remap_entities(map);
+        self.round.remap_entities(map);
+        self.encounter.remap_entities(map);
+        self.floors.remap_entities(map);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::{default, IVec2};
+
+    use super::*;
+    use crate::{Hidden, Objective, RoundCommand, UndoSnapshot, Variant};
+
+    fn entity(index: u32) -> Entity {
+        Entity::from_raw(index)
+    }
+
+    /// Two tokens that refer to each other, the player and an entity that wasn't saved, from
+    /// everywhere a save can refer to entities.
+    fn save_game() -> SaveGame {
+        let (fighter, goblin, player, gone) = (entity(1), entity(2), entity(7), entity(9));
+        let mut grid = Grid::new(3, 3);
+        grid.get_mut(IVec2::new(0, 0)).unwrap().entity = Some(fighter);
+        grid.get_mut(IVec2::new(1, 0)).unwrap().entity = Some(goblin);
+        let mut stored = Grid::new(2, 2);
+        stored.get_mut(IVec2::new(1, 1)).unwrap().entity = Some(gone);
+
+        let mut round = Round::default();
+        round.active_entity = Some(goblin);
+        round.initiative_order = vec![goblin, fighter, gone];
+        round.initiative.insert(fighter, 12);
+        round.initiative.insert(goblin, 15);
+        round.surprised.insert(fighter, ());
+        round.undo.push(UndoSnapshot {
+            who: goblin,
+            grid_pos: IVec2::new(2, 0),
+            movement_ft: 30.0,
+        });
+        round.push_back(RoundCommand::attack(goblin, fighter, 0));
+        round.push_back(RoundCommand::end_turn(gone));
+
+        SaveGame {
+            version: SAVE_VERSION,
+            state: GameState::Combat,
+            players: vec![SavedPlayer {
+                id: player,
+                player: Player {
+                    name: "Player 1".into(),
+                },
+            }],
+            tokens: vec![
+                SavedToken {
+                    id: fighter,
+                    token: Token {
+                        name: "Fighter".into(),
+                        player: Some(player),
+                        ..default()
+                    },
+                    floor: None,
+                    inventory: None,
+                },
+                SavedToken {
+                    id: goblin,
+                    token: Token {
+                        name: "Goblin".into(),
+                        hidden: Some(Hidden {
+                            stealth: 14,
+                            from: vec![fighter, gone],
+                        }),
+                        ..default()
+                    },
+                    floor: None,
+                    inventory: None,
+                },
+            ],
+            grid,
+            map_objects: default(),
+            generated_map: default(),
+            floors: Floors {
+                current: 0,
+                stored: [(
+                    1,
+                    crate::Floor {
+                        grid: stored,
+                        map_objects: default(),
+                        vision: default(),
+                    },
+                )]
+                .into_iter()
+                .collect(),
+            },
+            vision: default(),
+            round,
+            dice: Dice::new(3),
+            factions: default(),
+            encounter: Encounter {
+                faction: "players".into(),
+                objectives: vec![
+                    Objective::Protect { who: fighter },
+                    Objective::Protect { who: gone },
+                ],
+                ..default()
+            },
+        }
+    }
+
+    #[test]
+    fn remapped_saves_refer_to_the_respawned_entities_only() {
+        let mut save = save_game();
+        let map = [(1, 101), (2, 102), (7, 107)]
+            .into_iter()
+            .map(|(old, new)| (entity(old), entity(new)))
+            .collect::<HashMap<_, _>>();
+        save.remap_entities(&map);
+        let save = SaveGame::from_json(&save.to_json().unwrap()).unwrap();
+        let (fighter, goblin) = (entity(101), entity(102));
+
+        assert_eq!(save.players[0].id, entity(107));
+        assert_eq!(save.tokens[0].id, fighter);
+        assert_eq!(save.tokens[0].token.player, Some(entity(107)));
+        assert_eq!(save.tokens[1].id, goblin);
+        assert_eq!(
+            save.tokens[1].token.hidden.as_ref().unwrap().from,
+            vec![fighter]
+        );
+        assert_eq!(
+            save.grid.get(IVec2::new(0, 0)).unwrap().entity,
+            Some(fighter)
+        );
+        assert_eq!(
+            save.grid.get(IVec2::new(1, 0)).unwrap().entity,
+            Some(goblin)
+        );
+        let stored = &save.floors.stored[&1].grid;
+        assert_eq!(stored.get(IVec2::new(1, 1)).unwrap().entity, None);
+
+        let mut round = save.round;
+        assert_eq!(round.active_entity, Some(goblin));
+        assert_eq!(round.initiative_order, vec![goblin, fighter]);
+        assert_eq!(round.initiative.get(&fighter), Some(&12));
+        assert_eq!(round.initiative.get(&goblin), Some(&15));
+        assert!(round.surprised.contains_key(&fighter));
+        assert_eq!(round.undo[0].who, goblin);
+        // the end of the turn of the entity that is gone is dropped
+        assert!(matches!(
+            round.pop_front().unwrap().variant,
+            Variant::Attack { who, target, .. } if who == goblin && target == fighter
+        ));
+        assert!(round.pop_front().is_none());
+        assert!(matches!(
+            save.encounter.objectives[..],
+            [Objective::Protect { who }] if who == fighter
+        ));
+    }
+
+    #[test]
+    fn saves_of_another_version_are_rejected() {
+        let mut save = save_game();
+        save.version = SAVE_VERSION - 1;
+        let error = SaveGame::from_json(&save.to_json().unwrap()).err();
+        assert_eq!(
+            error,
+            Some(format!(
+                "unsupported save version {}, expected {}",
+                SAVE_VERSION - 1,
+                SAVE_VERSION
+            ))
+        );
+    }
+}
@@ -0,0 +1,17 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(States, Clone, Copy, PartialEq, Eq, Hash, Debug, Default, Serialize, Deserialize)]
+pub enum GameState {
+    /// Party tokens move freely, no initiative is tracked.
+    #[default]
+    Exploration,
+    /// Turns are handed out in initiative order.
+    Combat,
+    /// Rest commands are being executed.
+    Resting,
+}
+
+pub fn build(app: &mut App) {
+    app.add_state::<GameState>();
+}
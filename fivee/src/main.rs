@@ -29,6 +29,20 @@ fn main() {
         .add_plugins(plugin_assets::PluginAssets)
         .add_plugins(plugin_ui::PluginUI)
         .add_plugins(plugin_game::PluginGame)
+        .add_plugins(plugin_game::PluginGamePresentation)
         .add_plugins(plugin_ai::PluginAI)
+        .insert_resource(selected_encounter())
         .run();
 }
+
+/// The encounter to play is the first argument and the seed of its map the optional second,
+/// e.g. `fivee goblin_ambush 1234`.
+fn selected_encounter() -> common::SelectedEncounter {
+    let mut args = std::env::args().skip(1);
+    let mut selected = common::SelectedEncounter::default();
+    if let Some(id) = args.next() {
+        selected.id = id;
+    }
+    selected.map_seed = args.next().and_then(|seed| seed.parse().ok());
+    selected
+}
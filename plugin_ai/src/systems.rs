@@ -1,7 +1,11 @@
 use crate::components::AI;
 use bevy::prelude::*;
-use common::{Round, RoundCommand, Token};
+use common::{
+    Effect, Factions, FeatureCatalog, FeatureDef, Grid, Inventory, Item, ItemCatalog, Replay,
+    Round, RoundCommand, Statblock, Token, Vision,
+};
 
+/// Every token not controlled by a player is controlled by the AI, whatever its faction.
 fn add_remove_ai_system(mut commands: Commands, tokens: Query<(Entity, &Token)>, ais: Query<&AI>) {
     for (token_entity, token) in tokens.iter() {
         if token.player.is_none() {
@@ -38,7 +42,17 @@ fn timeout_system(mut round: ResMut<Round>, mut ais: Query<&mut AI, With<Token>>
 fn think_system(
     mut round: ResMut<Round>,
     mut ais: Query<&mut AI, With<Token>>,
-    tokens: Query<&Token>,
+    tokens: Query<(Entity, &Token)>,
+    statblock_handles: Query<&Handle<Statblock>>,
+    statblocks: Res<Assets<Statblock>>,
+    grid: Res<Grid>,
+    factions: Res<Factions>,
+    vision: Res<Vision>,
+    inventories: Query<&Inventory>,
+    catalog: Res<ItemCatalog>,
+    items: Res<Assets<Item>>,
+    feature_catalog: Res<FeatureCatalog>,
+    features: Res<Assets<FeatureDef>>,
 ) {
     if round.is_executing() {
         return;
@@ -52,15 +66,111 @@ fn think_system(
         return;
     };
 
-    let Ok(token) = tokens.get(entity) else {
+    let Ok((_, token)) = tokens.get(entity) else {
         return;
     };
 
-    let new_pos = token.grid_pos + IVec2::new(1, 0);
-    round.push_back(RoundCommand::move_far(entity, new_pos));
-    round.push_back(RoundCommand::end_turn(entity));
+    let Some(statblock) = statblock_handles.get(entity).ok().and_then(|h| statblocks.get(h)) else {
+        round.push_back(RoundCommand::end_turn(entity));
+        return;
+    };
+
+    // go for the closest enemy any of its allies can see
+    let faction = rules::faction(token);
+    let target = tokens
+        .iter()
+        .filter(|(other, other_token)| {
+            *other != entity
+                && !rules::is_defeated(other_token)
+                && rules::is_hostile(&factions, token, other_token)
+                && vision.sees_token(faction, *other, other_token.grid_pos)
+        })
+        .min_by(|(_, a), (_, b)| {
+            let a = rules::distance_ft(token.grid_pos, a.grid_pos);
+            let b = rules::distance_ft(token.grid_pos, b.grid_pos);
+            a.total_cmp(&b)
+        });
+    let Some((target, target_token)) = target else {
+        round.push_back(RoundCommand::end_turn(entity));
+        return;
+    };
+
+    let feature = feature_catalog
+        .of(statblock, &features)
+        .into_iter()
+        .find(|(id, feature)| {
+            rules::can_use_feature(token, id, feature, true)
+                && worth_using(token, statblock, feature, target_token)
+        });
+    if let Some((id, _)) = feature {
+        round.push_back(RoundCommand::use_feature(entity, id.to_string()));
+        return;
+    }
+
+    if token.actions > 0 {
+        // the first attack that can hit the target and has something to shoot or throw
+        let inventory = inventories.get(entity).ok();
+        let equipped = inventory
+            .map(|inventory| inventory.equipped(&catalog, &items))
+            .unwrap_or_default();
+        let attack = rules::attacks(statblock, &equipped).iter().position(|attack| {
+            let supplied = match attack.uses_item.as_ref() {
+                Some(item) => inventory.is_some_and(|inventory| inventory.count(item) > 0),
+                None => true,
+            };
+            supplied && rules::can_attack(&grid, token.grid_pos, attack, target_token.grid_pos)
+        });
+        if let Some(attack) = attack {
+            round.push_back(RoundCommand::attack(entity, target, attack));
+            return;
+        }
+    }
+
+    // otherwise move to the free reachable cell closest to the target
+    let occupied = tokens
+        .iter()
+        .filter(|(other, other_token)| *other != entity && !rules::is_defeated(other_token))
+        .map(|(_, other_token)| other_token.grid_pos)
+        .collect::<Vec<_>>();
+    let distance = |p: IVec2| rules::distance_ft(p, target_token.grid_pos);
+    let best = rules::get_reachable_cells(token, &grid)
+        .values()
+        .filter(|cell| !occupied.contains(&cell.to))
+        .min_by(|a, b| distance(a.to).total_cmp(&distance(b.to)))
+        .map(|cell| cell.to);
+    match best {
+        Some(to) if distance(to) < distance(token.grid_pos) => {
+            round.push_back(RoundCommand::move_far(entity, to));
+        }
+        _ => round.push_back(RoundCommand::end_turn(entity)),
+    }
+}
+
+/// Whether an activated feature helps now: healing when down to half hit points, extra
+/// actions once the turn's are spent, lasting features such as Rage and Bless as soon as a
+/// fight starts, and Bane once the target is in range.
+fn worth_using(token: &Token, statblock: &Statblock, feature: &FeatureDef, target: &Token) -> bool {
+    if feature.duration_rounds.is_some() {
+        return true;
+    }
+    feature.effects.iter().any(|effect| match effect {
+        Effect::Heal { .. } => token.hit_points <= statblock.hit_points as i32 / 2,
+        Effect::GainActions { .. } => token.actions == 0,
+        Effect::Condition {
+            range_ft, hostile, ..
+        } => !hostile || rules::distance_ft(token.grid_pos, target.grid_pos) <= *range_ft as f32,
+        _ => false,
+    })
 }
 
 pub fn add_systems(app: &mut App) {
-    app.add_systems(Update, (add_remove_ai_system, timeout_system, think_system));
+    app.add_systems(
+        Update,
+        (
+            add_remove_ai_system,
+            // replays feed the recorded decisions instead
+            timeout_system.run_if(not(resource_exists::<Replay>())),
+            think_system.run_if(not(resource_exists::<Replay>())),
+        ),
+    );
 }
@@ -55,6 +55,78 @@ fn startup(
             ..Default::default()
         }),
     );
+    ca.material_insert(
+        "fog",
+        materials.add(StandardMaterial {
+            base_color: Color::rgba(0.0, 0.0, 0.0, 0.6),
+            unlit: true,
+            alpha_mode:AlphaMode::Blend,
+            ..Default::default()
+        }),
+    );
+    ca.material_insert(
+        "stairs",
+        materials.add(StandardMaterial {
+            base_color: Color::Rgba { red: 1.0, green: 0.6, blue: 0.0, alpha: 0.5 },
+            unlit: true,
+            alpha_mode:AlphaMode::Add,
+            ..Default::default()
+        }),
+    );
+    ca.material_insert(
+        "door_open",
+        materials.add(StandardMaterial {
+            base_color: Color::Rgba { red: 0.5, green: 0.3, blue: 0.1, alpha: 0.5 },
+            unlit: true,
+            alpha_mode:AlphaMode::Add,
+            ..Default::default()
+        }),
+    );
+    ca.material_insert(
+        "door_closed",
+        materials.add(StandardMaterial {
+            base_color: Color::Rgba { red: 0.8, green: 0.4, blue: 0.1, alpha: 0.5 },
+            unlit: true,
+            alpha_mode:AlphaMode::Add,
+            ..Default::default()
+        }),
+    );
+    ca.material_insert(
+        "trap",
+        materials.add(StandardMaterial {
+            base_color: Color::Rgba { red: 1.0, green: 0.0, blue: 0.0, alpha: 0.5 },
+            unlit: true,
+            alpha_mode:AlphaMode::Add,
+            ..Default::default()
+        }),
+    );
+    ca.material_insert(
+        "lever",
+        materials.add(StandardMaterial {
+            base_color: Color::Rgba { red: 0.6, green: 0.6, blue: 0.6, alpha: 0.5 },
+            unlit: true,
+            alpha_mode:AlphaMode::Add,
+            ..Default::default()
+        }),
+    );
+    ca.material_insert(
+        "chest",
+        materials.add(StandardMaterial {
+            base_color: Color::Rgba { red: 1.0, green: 0.85, blue: 0.0, alpha: 0.5 },
+            unlit: true,
+            alpha_mode:AlphaMode::Add,
+            ..Default::default()
+        }),
+    );
+    ca.material_insert(
+        "torch",
+        materials.add(StandardMaterial {
+            base_color: Color::Rgba { red: 1.0, green: 0.5, blue: 0.2, alpha: 0.5 },
+            unlit: true,
+            alpha_mode:AlphaMode::Add,
+            ..Default::default()
+        }),
+    );
     ca.material_insert(
         "highlight_blue",
         materials.add(StandardMaterial {
@@ -0,0 +1,166 @@
+use bevy::{asset::FileAssetIo, prelude::*};
+use common::{
+    Attitude, CHARACTERS_FOLDER, CharacterSheet, ClassCatalog, ClassDef, Dice, Encounter,
+    Factions, GameEvent, Inventory, Outcome, PersistenceEvent, Statblock, StoredToken, Token,
+};
+
+/// Gives player characters the statblock resolved from their sheet, and resolves it again
+/// whenever the sheet changes, on disk or by leveling up. Loads their classes too.
+fn resolve_character_system(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<CharacterSheet>>,
+    tokens: Query<(Entity, &Handle<CharacterSheet>)>,
+    statblock_handles: Query<&Handle<Statblock>>,
+    sheets: Res<Assets<CharacterSheet>>,
+    mut statblocks: ResMut<Assets<Statblock>>,
+    mut classes: ResMut<ClassCatalog>,
+    ass: Res<AssetServer>,
+) {
+    let modified = events
+        .iter()
+        .filter_map(|ev| match ev {
+            AssetEvent::Modified { handle } => Some(handle.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    for (e, sheet_handle) in tokens.iter() {
+        let Some(sheet) = sheets.get(sheet_handle) else {
+            continue;
+        };
+        if !classes.handles.contains_key(&sheet.class) {
+            let handle: Handle<ClassDef> = ass.load(format!("classes/{}.toml", sheet.class));
+            classes.handles.insert(sheet.class.clone(), handle);
+        }
+        match statblock_handles.get(e) {
+            Err(_) => {
+                let handle = statblocks.add(rules::character_statblock(sheet));
+                commands.entity(e).insert(handle);
+            }
+            Ok(handle) if modified.contains(sheet_handle) => {
+                statblocks.set_untracked(handle, rules::character_statblock(sheet));
+            }
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Splits the XP of the defeated hostile monsters between the player characters once the encounter
+/// is won, and levels up those who reach the next level.
+pub(crate) fn award_xp_system(
+    mut encounter: ResMut<Encounter>,
+    mut tokens: Query<(Entity, &mut Token)>,
+    stored_tokens: Query<(&StoredToken, &Handle<Statblock>)>,
+    statblock_handles: Query<&Handle<Statblock>>,
+    sheet_handles: Query<&Handle<CharacterSheet>>,
+    statblocks: Res<Assets<Statblock>>,
+    mut sheets: ResMut<Assets<CharacterSheet>>,
+    catalog: Res<ClassCatalog>,
+    classes: Res<Assets<ClassDef>>,
+    factions: Res<Factions>,
+    mut dice: ResMut<Dice>,
+    mut ge: EventWriter<GameEvent>,
+) {
+    if encounter.xp_awarded || encounter.outcome != Some(Outcome::Victory) {
+        return;
+    }
+    encounter.xp_awarded = true;
+
+    // monsters left on other floors count if they were defeated there, neutral creatures
+    // caught in the fight never do
+    let hostile = |token: &Token| {
+        factions.attitude(&encounter.faction, &token.faction) == Attitude::Hostile
+    };
+    let mut monster_xp = Vec::new();
+    let mut characters = Vec::new();
+    // the XP is split between the whole party, even those without a sheet to gain it
+    let mut party_size = 0;
+    for (e, token) in tokens.iter() {
+        if token.faction == encounter.faction {
+            party_size += 1;
+            if sheet_handles.contains(e) {
+                characters.push(e);
+            }
+            continue;
+        }
+        let Some(statblock) = statblock_handles.get(e).ok().and_then(|h| statblocks.get(h))
+        else {
+            continue;
+        };
+        if hostile(token) && rules::is_defeated(token) {
+            monster_xp.push(rules::monster_xp(statblock));
+        }
+    }
+    for (stored, statblock_handle) in stored_tokens.iter() {
+        let Some(statblock) = statblocks.get(statblock_handle) else {
+            continue;
+        };
+        if hostile(&stored.token) && rules::is_defeated(&stored.token) {
+            monster_xp.push(rules::monster_xp(statblock));
+        }
+    }
+    // the same order every time, for the hit die rolls
+    characters.sort_by_key(|e| tokens.get(*e).map(|(_, token)| token.name.clone()).ok());
+
+    let xp = rules::xp_share(&monster_xp, party_size);
+    for e in characters {
+        let (Ok(sheet_handle), Ok((_, mut token))) = (sheet_handles.get(e), tokens.get_mut(e))
+        else {
+            continue;
+        };
+        let Some(sheet) = sheets.get_mut(sheet_handle) else {
+            continue;
+        };
+        sheet.xp += xp;
+        ge.send(GameEvent::GainedXp { who: e, xp });
+        while rules::level_for_xp(sheet.xp) > sheet.level {
+            let Some(class) = catalog.get(&sheet.class, &classes) else {
+                warn!("class {} of {} isn't loaded", sheet.class, sheet.name);
+                break;
+            };
+            rules::level_up(sheet, class, &mut token, &mut dice);
+            info!("{} reached level {}", sheet.name, sheet.level);
+            ge.send(GameEvent::LeveledUp {
+                who: e,
+                level: sheet.level,
+            });
+        }
+    }
+}
+
+/// Writes the sheets of the player characters back to disk, with the gear they carry now.
+fn save_characters_system(
+    mut reader: EventReader<PersistenceEvent>,
+    tokens: Query<(&Token, &Handle<CharacterSheet>, Option<&Inventory>)>,
+    sheets: Res<Assets<CharacterSheet>>,
+) {
+    if !reader
+        .iter()
+        .any(|ev| matches!(ev, PersistenceEvent::SaveCharacters))
+    {
+        return;
+    }
+    let folder = FileAssetIo::get_base_path().join("assets").join(CHARACTERS_FOLDER);
+    for (token, sheet_handle, inventory) in tokens.iter() {
+        let (Some(id), Some(sheet)) = (token.character.as_ref(), sheets.get(sheet_handle))
+        else {
+            continue;
+        };
+        let mut sheet = sheet.clone();
+        if let Some(inventory) = inventory {
+            sheet.inventory = inventory.items.clone();
+        }
+        let path = folder.join(format!("{}.toml", id));
+        let result = sheet
+            .to_toml()
+            .and_then(|toml| std::fs::write(&path, toml).map_err(|err| err.to_string()));
+        match result {
+            Ok(_) => info!("saved character to {}", path.display()),
+            Err(err) => error!("failed to save character to {}: {}", path.display(), err),
+        }
+    }
+}
+
+pub fn add_systems(app: &mut App) {
+    app.add_systems(PreUpdate, save_characters_system);
+    app.add_systems(PostUpdate, resolve_character_system);
+}
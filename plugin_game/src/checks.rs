@@ -0,0 +1,30 @@
+use bevy::prelude::*;
+use common::{GameEvent, Grid};
+
+/// Applies successful checks made about an object, e.g. forcing a locked door open. Only
+/// checks against the DC of the object count, not an easier one.
+pub(crate) fn check_outcome_system(mut reader: EventReader<GameEvent>, mut grid: ResMut<Grid>) {
+    for ev in reader.iter() {
+        let GameEvent::Checked {
+            check,
+            dc,
+            success: true,
+            cell: Some(cell),
+            ..
+        } = ev
+        else {
+            continue;
+        };
+        let Some(mut object) = grid.object(*cell) else {
+            continue;
+        };
+        if rules::object_check(&object).is_some_and(|(_, object_dc)| *dc < object_dc) {
+            continue;
+        }
+        if rules::apply_check(&mut object, *check) {
+            if let Some(c) = grid.get_mut(*cell) {
+                c.object = Some(object);
+            }
+        }
+    }
+}
@@ -0,0 +1,9 @@
+use bevy::prelude::Component;
+
+/// Meshes spawned for the cells of the grid.
+#[derive(Component)]
+pub struct GridMesh;
+
+/// Covers a cell the player's faction doesn't see, darker if it has never been seen.
+#[derive(Component)]
+pub struct FogCell(pub bevy::prelude::IVec2);
@@ -0,0 +1,64 @@
+use common::{MapStep, MapgenConfig};
+use mapgen::{
+    filter::rooms_corridors_nearest::NearestCorridors, geometry::Rect, AreaStartingPosition,
+    BspInterior, BspRooms, CellularAutomata, CullUnreachable, DistantExit, DrunkardsWalk,
+    MapBuffer, MapBuilder, MapFilter, MazeBuilder, NoiseGenerator, VoronoiHive, XStart, YStart,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Cells of map per room tried at a density of 1.
+const CELLS_PER_ROOM: f32 = 64.0;
+const MIN_ROOM_SIZE: usize = 4;
+const MAX_ROOM_SIZE: usize = 10;
+
+/// Like mapgen's `SimpleRooms`, but with the number of rooms tried set by the map size and
+/// density instead of being fixed.
+struct Rooms {
+    density: f32,
+}
+
+impl MapFilter for Rooms {
+    fn modify_map(&self, rng: &mut StdRng, map: &MapBuffer) -> MapBuffer {
+        let mut map = map.clone();
+        if map.width < MAX_ROOM_SIZE + 2 || map.height < MAX_ROOM_SIZE + 2 {
+            return map;
+        }
+        let tries = (self.density * (map.width * map.height) as f32 / CELLS_PER_ROOM) as usize;
+        for _ in 0..tries.max(1) {
+            let w = rng.gen_range(MIN_ROOM_SIZE..=MAX_ROOM_SIZE);
+            let h = rng.gen_range(MIN_ROOM_SIZE..=MAX_ROOM_SIZE);
+            let x = rng.gen_range(1..map.width - w);
+            let y = rng.gen_range(1..map.height - h);
+            let room = Rect::new(x, y, w, h);
+            if !map.rooms.iter().any(|r| room.intersect(r)) {
+                map.add_room(room);
+            }
+        }
+        map
+    }
+}
+
+/// Generates the map described by `config` from `seed`, ignoring the seed in the config.
+pub(crate) fn generate(config: &MapgenConfig, seed: u64) -> MapBuffer {
+    let mut rng: StdRng = SeedableRng::seed_from_u64(seed);
+    let density = config.density.clamp(0.0, 1.0);
+    let mut builder = MapBuilder::new(config.width, config.height);
+    for step in config.steps() {
+        match step {
+            MapStep::Rooms => builder.with(Box::new(Rooms { density })),
+            MapStep::BspRooms => builder.with(BspRooms::new()),
+            MapStep::BspInterior => builder.with(BspInterior::new()),
+            MapStep::NearestCorridors => builder.with(NearestCorridors::new()),
+            MapStep::Noise => builder.with(NoiseGenerator::new(density)),
+            MapStep::CellularAutomata => builder.with(CellularAutomata::new()),
+            MapStep::DrunkardsWalk => builder.with(DrunkardsWalk::open_area()),
+            MapStep::Maze => builder.with(MazeBuilder::new()),
+            MapStep::Voronoi => builder.with(VoronoiHive::new()),
+        };
+    }
+    builder
+        .with(AreaStartingPosition::new(XStart::LEFT, YStart::TOP))
+        .with(CullUnreachable::new())
+        .with(DistantExit::new())
+        .build_with_rng(&mut rng)
+}
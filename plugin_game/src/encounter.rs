@@ -0,0 +1,588 @@
+use bevy::{asset::LoadState, prelude::*, utils::HashMap};
+use common::{
+    AuthoredMap, CHARACTERS_FOLDER, CharacterSheet, Encounter, EncounterDef, Factions, Floor,
+    Floors, GameState, GeneratedMap, Grid, MapObject, MapObjects, MapSource, MapgenConfig,
+    Objective, ObjectiveDef, Player, PopulationDef, Recorder, Round, SelectedEncounter, Spawn,
+    Stairs, Statblock, StoredToken, Token, Vision,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::dungeon;
+
+/// Every token, on the current floor or stored away on another, despawned when a new game
+/// starts.
+pub(crate) type GameEntities<'w, 's> = Query<'w, 's, Entity, Or<(With<Token>, With<StoredToken>)>>;
+
+/// Most monsters a population adds, however large the XP budget.
+const MAX_MONSTERS: usize = 30;
+
+/// The encounter being loaded, spawned once its file, map and statblocks are in.
+#[derive(Resource)]
+struct PendingEncounter {
+    def: Handle<EncounterDef>,
+    map: Option<Handle<AuthoredMap>>,
+    statblocks: Option<PopulationStatblocks>,
+}
+
+/// Statblocks needed to populate an encounter.
+#[derive(Clone)]
+struct PopulationStatblocks {
+    /// Of the party, for their levels.
+    party: Vec<Handle<Statblock>>,
+    /// Of the player characters in the party, who have levels of their own.
+    characters: Vec<Handle<CharacterSheet>>,
+    /// To draw monsters from.
+    monsters: Vec<Handle<Statblock>>,
+}
+
+struct BuiltMap {
+    grid: Grid,
+    /// Where the party starts.
+    start: IVec2,
+    /// Where the stairs down go, if there is a floor below.
+    exit: Option<IVec2>,
+    objects: Vec<MapObject>,
+    /// Corners of the rooms, for placing monsters.
+    rooms: Vec<(IVec2, IVec2)>,
+}
+
+fn select_encounter_system(
+    mut commands: Commands,
+    selected: Res<SelectedEncounter>,
+    asset_server: Res<AssetServer>,
+) {
+    if !selected.is_changed() {
+        return;
+    }
+    commands.insert_resource(PendingEncounter {
+        def: asset_server.load(format!("encounters/{}.toml", selected.id)),
+        map: None,
+        statblocks: None,
+    });
+}
+
+fn build_mapgen(config: &MapgenConfig, seed: u64) -> BuiltMap {
+    let mapbuffer = dungeon::generate(config, seed);
+
+    let mut grid = Grid::new(config.width, config.height);
+    for y in 0..config.height {
+        for x in 0..config.width {
+            let i = IVec2 {
+                x: x as i32,
+                y: y as i32,
+            };
+            let blocked = mapbuffer.is_blocked(x, y);
+            let walkable = mapbuffer.is_walkable(x, y);
+            grid.get_mut(i).unwrap().blocked = blocked;
+            grid.get_mut(i).unwrap().walkable = walkable;
+        }
+    }
+    let start = mapbuffer
+        .starting_point
+        .map(|p| IVec2::new(p.x as i32, p.y as i32))
+        .unwrap_or_default();
+    let exit = mapbuffer
+        .exit_point
+        .map(|p| IVec2::new(p.x as i32, p.y as i32));
+    let rooms = mapbuffer
+        .rooms
+        .iter()
+        .map(|r| {
+            (
+                IVec2::new(r.x1 as i32, r.y1 as i32),
+                IVec2::new(r.x2 as i32 - 1, r.y2 as i32 - 1),
+            )
+        })
+        .collect();
+    BuiltMap {
+        grid,
+        start,
+        exit,
+        objects: Vec::new(),
+        rooms,
+    }
+}
+
+/// Generates every floor of a dungeon and links each to the next one with stairs.
+fn build_floors(config: &MapgenConfig, seed: u64) -> Vec<BuiltMap> {
+    let mut floors = (0..config.floors.max(1))
+        .map(|floor| build_mapgen(config, seed.wrapping_add(floor as u64)))
+        .collect::<Vec<_>>();
+    for floor in 1..floors.len() {
+        let (upper, lower) = floors.split_at_mut(floor);
+        let (upper, lower) = (&mut upper[floor - 1], &mut lower[0]);
+        let Some(exit) = upper.exit else {
+            warn!("floor {} has no room for stairs down", floor - 1);
+            continue;
+        };
+        if let Some(cell) = upper.grid.get_mut(exit) {
+            cell.stairs = Some(Stairs {
+                floor,
+                cell: lower.start,
+            });
+        }
+        if let Some(cell) = lower.grid.get_mut(lower.start) {
+            cell.stairs = Some(Stairs {
+                floor: floor - 1,
+                cell: exit,
+            });
+        }
+    }
+    floors
+}
+
+fn build_authored(map: &AuthoredMap) -> BuiltMap {
+    let start = map
+        .objects
+        .iter()
+        .find(|object| object.kind == "spawn" && object.name == "start")
+        .map(|object| object.cell)
+        .unwrap_or_default();
+    BuiltMap {
+        grid: map.grid.clone(),
+        start,
+        exit: None,
+        objects: map.objects.clone(),
+        rooms: Vec::new(),
+    }
+}
+
+fn is_free(grid: &Grid, occupied: &[IVec2], cell: IVec2) -> bool {
+    grid.is_walkable(cell) && !grid.is_blocked(cell) && !occupied.contains(&cell)
+}
+
+/// The free cell closest to `cell`, searching outwards ring by ring.
+pub(crate) fn nearest_free(grid: &Grid, occupied: &[IVec2], cell: IVec2) -> Option<IVec2> {
+    for r in 0..grid.width().max(grid.height()) as i32 {
+        for y in -r..=r {
+            for x in -r..=r {
+                if x.abs() != r && y.abs() != r {
+                    continue;
+                }
+                let p = cell + IVec2::new(x, y);
+                if is_free(grid, occupied, p) {
+                    return Some(p);
+                }
+            }
+        }
+    }
+    None
+}
+
+fn place(map: &BuiltMap, occupied: &[IVec2], spawn: &Spawn, rng: &mut StdRng) -> Option<IVec2> {
+    let grid = &map.grid;
+    match spawn {
+        Spawn::Cell(cell) => nearest_free(grid, occupied, *cell),
+        Spawn::Start(offset) => nearest_free(grid, occupied, map.start + *offset),
+        Spawn::Marker(name) => {
+            let mut markers = map
+                .objects
+                .iter()
+                .filter(|object| object.kind == "spawn" && object.name == *name)
+                .map(|object| object.cell);
+            let first = markers.clone().next()?;
+            markers
+                .find(|cell| is_free(grid, occupied, *cell))
+                .or_else(|| nearest_free(grid, occupied, first))
+        }
+        Spawn::Zone { min, max } => {
+            let mut cells = Vec::new();
+            for y in min.y..=max.y {
+                for x in min.x..=max.x {
+                    let p = IVec2::new(x, y);
+                    if is_free(grid, occupied, p) {
+                        cells.push(p);
+                    }
+                }
+            }
+            if cells.is_empty() {
+                return None;
+            }
+            Some(cells[rng.gen_range(0..cells.len())])
+        }
+    }
+}
+
+fn load_population_statblocks(
+    def: &EncounterDef,
+    population: &PopulationDef,
+    asset_server: &AssetServer,
+) -> PopulationStatblocks {
+    let load = |id: &String| asset_server.load(format!("statblocks/{}.toml", id));
+    let party = def
+        .creatures
+        .iter()
+        .filter(|creature| creature.faction == def.faction && creature.character.is_none())
+        .map(|creature| load(&creature.statblock))
+        .collect();
+    let characters = def
+        .creatures
+        .iter()
+        .filter(|creature| creature.faction == def.faction)
+        .filter_map(|creature| creature.character.as_ref())
+        .map(|id| asset_server.load(format!("{}/{}.toml", CHARACTERS_FOLDER, id)))
+        .collect();
+    let monsters = if population.statblocks.is_empty() {
+        match asset_server.load_folder("statblocks") {
+            Ok(handles) => handles.into_iter().map(|handle| handle.typed()).collect(),
+            Err(err) => {
+                error!("failed to load statblocks: {}", err);
+                Vec::new()
+            }
+        }
+    } else {
+        population.statblocks.iter().map(load).collect()
+    };
+    PopulationStatblocks {
+        party,
+        characters,
+        monsters,
+    }
+}
+
+/// Draws monsters, given by statblock id and XP, for as long as the party's budget allows.
+fn pick_monsters(
+    candidates: &[(String, u32)],
+    levels: &[u32],
+    population: &PopulationDef,
+    rng: &mut StdRng,
+) -> Vec<String> {
+    let budget = rules::xp_budget(levels, population.difficulty);
+    let mut xp = Vec::new();
+    let mut picked = Vec::new();
+    while picked.len() < MAX_MONSTERS {
+        let fitting = candidates
+            .iter()
+            .filter(|(_, monster_xp)| {
+                let mut with = xp.clone();
+                with.push(*monster_xp);
+                rules::adjusted_xp(&with, levels.len()) <= budget
+            })
+            .collect::<Vec<_>>();
+        if fitting.is_empty() {
+            break;
+        }
+        let (id, monster_xp) = fitting[rng.gen_range(0..fitting.len())];
+        xp.push(*monster_xp);
+        picked.push(id.clone());
+    }
+    picked
+}
+
+/// A free cell in a random room away from the party, or anywhere on maps without rooms.
+fn place_in_room(map: &BuiltMap, occupied: &[IVec2], rng: &mut StdRng) -> Option<IVec2> {
+    let contains = |(min, max): &(IVec2, IVec2), p: IVec2| {
+        p.x >= min.x && p.y >= min.y && p.x <= max.x && p.y <= max.y
+    };
+    let mut rooms = map
+        .rooms
+        .iter()
+        .filter(|room| !contains(room, map.start))
+        .copied()
+        .collect::<Vec<_>>();
+    if rooms.is_empty() {
+        let max = IVec2::new(map.grid.width() as i32 - 1, map.grid.height() as i32 - 1);
+        rooms.push((IVec2::ZERO, max));
+    }
+    while !rooms.is_empty() {
+        let (min, max) = rooms.swap_remove(rng.gen_range(0..rooms.len()));
+        if let Some(p) = place(map, occupied, &Spawn::Zone { min, max }, rng) {
+            return Some(p);
+        }
+    }
+    None
+}
+
+/// Replaces the current game with the pending encounter once its file has loaded.
+fn spawn_encounter_system(
+    mut commands: Commands,
+    pending: Option<ResMut<PendingEncounter>>,
+    defs: Res<Assets<EncounterDef>>,
+    maps: Res<Assets<AuthoredMap>>,
+    statblocks: Res<Assets<Statblock>>,
+    sheets: Res<Assets<CharacterSheet>>,
+    selected: Res<SelectedEncounter>,
+    asset_server: Res<AssetServer>,
+    players: Query<Entity, With<Player>>,
+    tokens: GameEntities,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    let Some(mut pending) = pending else {
+        return;
+    };
+    let Some(def) = defs.get(&pending.def) else {
+        if asset_server.get_load_state(&pending.def) == LoadState::Failed {
+            error!("failed to load encounter");
+            commands.remove_resource::<PendingEncounter>();
+        }
+        return;
+    };
+    if let Some(population) = &def.population {
+        let handles = pending
+            .statblocks
+            .get_or_insert_with(|| load_population_statblocks(def, population, &asset_server));
+        let ids = handles
+            .party
+            .iter()
+            .chain(handles.monsters.iter())
+            .map(|handle| handle.id())
+            .chain(handles.characters.iter().map(|handle| handle.id()));
+        match asset_server.get_group_load_state(ids) {
+            LoadState::Loaded => {}
+            LoadState::Failed => {
+                error!("failed to load statblocks of {}", def.name);
+                commands.remove_resource::<PendingEncounter>();
+                return;
+            }
+            _ => return,
+        }
+    }
+    let mut generated = None;
+    let mut floors = match &def.map {
+        MapSource::Mapgen(config) => {
+            let seed = selected
+                .map_seed
+                .or(config.seed)
+                .unwrap_or_else(rand::random);
+            info!("generating map with seed {}", seed);
+            generated = Some(MapgenConfig {
+                seed: Some(seed),
+                ..config.clone()
+            });
+            build_floors(config, seed)
+        }
+        MapSource::File { path } => {
+            let handle = pending
+                .map
+                .get_or_insert_with(|| asset_server.load(path.as_str()))
+                .clone();
+            let Some(map) = maps.get(&handle) else {
+                if asset_server.get_load_state(&handle) == LoadState::Failed {
+                    error!("failed to load map {}", path);
+                    commands.remove_resource::<PendingEncounter>();
+                }
+                return;
+            };
+            vec![build_authored(map)]
+        }
+    };
+    // the party starts on the first floor, the others are stored until they get there
+    let map = floors.remove(0);
+    commands.remove_resource::<PendingEncounter>();
+
+    for e in players.iter().chain(tokens.iter()) {
+        commands.entity(e).despawn_recursive();
+    }
+
+    let mut rng: StdRng = SeedableRng::seed_from_u64(def.seed);
+
+    let player = commands
+        .spawn(Player {
+            name: "Player One".into(),
+        })
+        .id();
+
+    let mut occupied = Vec::new();
+    let mut spawned = HashMap::new();
+    for creature in def.creatures.iter() {
+        let Some(grid_pos) = place(&map, &occupied, &creature.spawn, &mut rng) else {
+            warn!("no free cell to spawn {}", creature.name);
+            continue;
+        };
+        occupied.push(grid_pos);
+        let image = match (creature.image.as_str(), &creature.character) {
+            ("", Some(character)) => format!("token_{}", character),
+            ("", None) => format!("token_{}", creature.statblock),
+            (image, _) => image.to_string(),
+        };
+        let e = commands
+            .spawn(Token {
+                name: creature.name.clone(),
+                grid_pos,
+                image,
+                statblock: creature.statblock.clone(),
+                character: creature.character.clone(),
+                player: creature.player.then_some(player),
+                faction: creature.faction.clone(),
+                sneaking: creature.sneaking,
+                ..Default::default()
+            })
+            .id();
+        spawned.insert(creature.name.clone(), e);
+    }
+
+    if let (Some(population), Some(handles)) = (&def.population, &pending.statblocks) {
+        // party members without a sheet are as high level as they have hit dice
+        let levels = handles
+            .characters
+            .iter()
+            .filter_map(|handle| sheets.get(handle))
+            .map(|sheet| sheet.level.max(1))
+            .chain(
+                handles
+                    .party
+                    .iter()
+                    .filter_map(|handle| statblocks.get(handle))
+                    .map(|statblock| statblock.hit_dice.max(1)),
+            )
+            .collect::<Vec<_>>();
+        let mut names = HashMap::new();
+        let mut candidates = Vec::new();
+        for handle in handles.monsters.iter() {
+            let Some(statblock) = statblocks.get(handle) else {
+                continue;
+            };
+            let Some(path) = asset_server.get_handle_path(handle) else {
+                continue;
+            };
+            let Some(id) = path.path().file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let xp = rules::monster_xp(statblock);
+            if xp > 0 {
+                let name = match statblock.name.as_str() {
+                    "" => id.to_string(),
+                    name => name.to_string(),
+                };
+                names.insert(id.to_string(), name);
+                candidates.push((id.to_string(), xp));
+            }
+        }
+        candidates.sort();
+        candidates.dedup();
+
+        let mut counts = HashMap::<String, u32>::new();
+        for (floor, map) in std::iter::once(&map).chain(floors.iter()).enumerate() {
+            let mut occupied = match floor {
+                0 => occupied.clone(),
+                _ => Vec::new(),
+            };
+            let monsters = pick_monsters(&candidates, &levels, population, &mut rng);
+            info!(
+                "populating floor {} of {} with {} monsters for {} characters",
+                floor,
+                def.name,
+                monsters.len(),
+                levels.len()
+            );
+            for statblock in monsters {
+                let Some(grid_pos) = place_in_room(map, &occupied, &mut rng) else {
+                    warn!("no free cell to spawn {}", statblock);
+                    continue;
+                };
+                occupied.push(grid_pos);
+                let count = counts.entry(statblock.clone()).or_default();
+                *count += 1;
+                let name = names.get(&statblock).cloned().unwrap_or_default();
+                let token = Token {
+                    name: format!("{} {}", name, count),
+                    grid_pos,
+                    image: format!("token_{}", statblock),
+                    statblock,
+                    faction: population.faction.clone(),
+                    sneaking: population.sneaking,
+                    ..Default::default()
+                };
+                match floor {
+                    0 => commands.spawn(token),
+                    _ => commands.spawn(StoredToken { floor, token }),
+                };
+            }
+        }
+    }
+
+    let mut factions = Factions::default();
+    for relation in def.factions.iter() {
+        factions.set(&relation.a, &relation.b, relation.attitude);
+    }
+
+    let mut objectives = Vec::new();
+    for objective in def.objectives.iter() {
+        objectives.push(match objective {
+            ObjectiveDef::DefeatAllHostiles => Objective::DefeatAllHostiles,
+            ObjectiveDef::SurviveRounds { rounds } => Objective::SurviveRounds { rounds: *rounds },
+            ObjectiveDef::ReachCell { cell } => Objective::ReachCell { cell: *cell },
+            ObjectiveDef::Protect { creature } => {
+                let Some(who) = spawned.get(creature) else {
+                    warn!("objective protects unknown creature {}", creature);
+                    continue;
+                };
+                Objective::Protect { who: *who }
+            }
+        });
+    }
+
+    commands.insert_resource(map.grid);
+    commands.insert_resource(MapObjects(map.objects));
+    commands.insert_resource(GeneratedMap(generated));
+    commands.insert_resource(Floors {
+        current: 0,
+        stored: floors
+            .into_iter()
+            .enumerate()
+            .map(|(i, map)| {
+                let floor = Floor {
+                    grid: map.grid,
+                    map_objects: MapObjects(map.objects),
+                    vision: Vision::default(),
+                };
+                (i + 1, floor)
+            })
+            .collect(),
+    });
+    commands.insert_resource(Vision::default());
+    commands.insert_resource(factions);
+    commands.insert_resource(Encounter {
+        faction: def.faction.clone(),
+        objectives,
+        outcome: None,
+        ambient_light: def.light,
+        xp_awarded: false,
+    });
+    commands.insert_resource(Round::default());
+    // recording starts over from the new encounter
+    commands.insert_resource(Recorder::default());
+    next_state.set(GameState::Exploration);
+    info!("spawned encounter {}", def.name);
+}
+
+pub fn add_systems(app: &mut App) {
+    app.add_systems(PreUpdate, (select_encounter_system, spawn_encounter_system).chain());
+}
+
+#[cfg(test)]
+mod tests {
+    use common::Difficulty;
+
+    use super::*;
+
+    #[test]
+    fn pick_monsters_stays_within_the_budget() {
+        let candidates = [("kobold", 25), ("goblin", 50), ("orc", 100), ("ogre", 450)]
+            .map(|(id, xp)| (id.to_string(), xp));
+        let xp = candidates.iter().cloned().collect::<HashMap<_, _>>();
+        for difficulty in [
+            Difficulty::Easy,
+            Difficulty::Medium,
+            Difficulty::Hard,
+            Difficulty::Deadly,
+        ] {
+            for levels in [vec![1], vec![1, 1, 2, 2], vec![3; 6], vec![20; 8]] {
+                let population = PopulationDef {
+                    difficulty,
+                    faction: "monsters".into(),
+                    statblocks: Vec::new(),
+                    sneaking: false,
+                };
+                let budget = rules::xp_budget(&levels, difficulty);
+                for seed in 0..20 {
+                    let mut rng = StdRng::seed_from_u64(seed);
+                    let picked = pick_monsters(&candidates, &levels, &population, &mut rng);
+                    let picked_xp = picked.iter().map(|id| xp[id]).collect::<Vec<_>>();
+                    assert!(rules::adjusted_xp(&picked_xp, levels.len()) <= budget);
+                    assert!(picked.len() <= MAX_MONSTERS);
+                }
+            }
+        }
+    }
+}
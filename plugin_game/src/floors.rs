@@ -0,0 +1,86 @@
+use bevy::prelude::*;
+use common::{
+    Encounter, Floor, Floors, GameEvent, Grid, MapObjects, Round, StoredToken, Token, Vision,
+};
+
+use crate::encounter::nearest_free;
+
+/// Moves the party to the floor it took the stairs to. The floor it left is put away as it
+/// was, and the creatures on it become `StoredToken`s until the party comes back.
+pub(crate) fn change_floor_system(
+    mut commands: Commands,
+    mut reader: EventReader<GameEvent>,
+    mut tokens: Query<(Entity, &mut Token)>,
+    stored_tokens: Query<(Entity, &StoredToken)>,
+    mut grid: ResMut<Grid>,
+    mut map_objects: ResMut<MapObjects>,
+    mut floors: ResMut<Floors>,
+    mut vision: ResMut<Vision>,
+    encounter: Res<Encounter>,
+    mut round: ResMut<Round>,
+) {
+    // the party is gone after the first staircase it takes
+    let Some(stairs) = reader.iter().find_map(|ev| match ev {
+        GameEvent::TookStairs { stairs, .. } => Some(*stairs),
+        _ => None,
+    }) else {
+        return;
+    };
+    let Some(next) = floors.stored.remove(&stairs.floor) else {
+        warn!("there is no floor {}", stairs.floor);
+        return;
+    };
+
+    let left = floors.current;
+    let mut party = Vec::new();
+    for (e, token) in tokens.iter() {
+        if rules::faction(token) == encounter.faction {
+            party.push((token.name.clone(), e));
+            continue;
+        }
+        commands
+            .entity(e)
+            .remove::<Token>()
+            .insert(StoredToken {
+                floor: left,
+                token: token.clone(),
+            });
+    }
+    let mut occupied = Vec::new();
+    for (e, stored) in stored_tokens.iter() {
+        if stored.floor != stairs.floor {
+            continue;
+        }
+        occupied.push(stored.token.grid_pos);
+        commands
+            .entity(e)
+            .remove::<StoredToken>()
+            .insert(stored.token.clone());
+    }
+
+    floors.stored.insert(
+        left,
+        Floor {
+            grid: std::mem::replace(&mut *grid, next.grid),
+            map_objects: std::mem::replace(&mut *map_objects, next.map_objects),
+            vision: std::mem::replace(&mut *vision, next.vision),
+        },
+    );
+    floors.current = stairs.floor;
+
+    // the party gathers around the other end of the stairs
+    party.sort();
+    for (_, e) in party {
+        let Some(cell) = nearest_free(&grid, &occupied, stairs.cell) else {
+            continue;
+        };
+        occupied.push(cell);
+        if let Ok((_, mut token)) = tokens.get_mut(e) {
+            token.grid_pos = cell;
+        }
+    }
+
+    round.clear_commands();
+    round.undo.clear();
+    info!("took the stairs to floor {}", stairs.floor);
+}
@@ -1,10 +1,33 @@
 use bevy::prelude::*;
 
+mod characters;
+mod checks;
+mod components;
+mod dungeon;
+mod encounter;
+mod floors;
+mod presentation;
+mod replay;
+mod save;
 mod systems;
+mod vision;
 
+/// The game rules, round and commands. Runs without a window, e.g. under `MinimalPlugins`.
 pub struct PluginGame;
 impl Plugin for PluginGame {
     fn build(&self, app: &mut App) {
         systems::add_systems(app);
+        encounter::add_systems(app);
+        save::add_systems(app);
+        replay::add_systems(app);
+        characters::add_systems(app);
+    }
+}
+
+/// Meshes, lights and animations for the game, added on top of `PluginGame` when rendering.
+pub struct PluginGamePresentation;
+impl Plugin for PluginGamePresentation {
+    fn build(&self, app: &mut App) {
+        presentation::add_systems(app);
     }
 }
@@ -0,0 +1,265 @@
+use bevy::{prelude::*, transform::TransformSystem};
+use common::{
+    CommonAssets, DoorState, Encounter, Grid, Interactable, Round, StoredToken, Token, Vision,
+};
+
+use crate::components::{FogCell, GridMesh};
+
+fn startup_system(mut commands: Commands) {
+    // spawn ambient lighting
+    commands.insert_resource(AmbientLight {
+        color: Color::WHITE,
+        brightness: 0.00,
+    });
+}
+
+/// Rebuilds the map meshes whenever the grid is replaced, e.g. after loading a save.
+fn spawn_grid_system(
+    mut commands: Commands,
+    grid: Res<Grid>,
+    grid_meshes: Query<Entity, With<GridMesh>>,
+    sa: Res<CommonAssets>,
+) {
+    if !grid.is_changed() {
+        return;
+    }
+    for e in grid_meshes.iter() {
+        commands.entity(e).despawn_recursive();
+    }
+
+    for y in 0..grid.height() as i32 {
+        for x in 0..grid.width() as i32 {
+            let i = IVec2::new(x, y);
+            let blocked = grid.is_blocked(i);
+            let walkable = grid.is_walkable(i);
+
+            let x = x as f32 + 0.5;
+            let y = y as f32 + 0.5;
+
+            if blocked {
+                commands
+                    .spawn(PbrBundle {
+                        mesh: sa.mesh("cube"),
+                        material: sa.material("brick"),
+                        transform: Transform::from_xyz(x, y, 0.0),
+                        ..default()
+                    })
+                    .insert(GridMesh);
+                commands
+                    .spawn(PbrBundle {
+                        transform: Transform::from_xyz(x, y, 1.01),
+                        mesh: sa.mesh("cell"),
+                        material: sa.material("black"),
+                        ..Default::default()
+                    })
+                    .insert(GridMesh);
+            }
+            if walkable {
+                commands
+                    .spawn(PbrBundle {
+                        transform: Transform::from_xyz(x, y, 0.0),
+                        mesh: sa.mesh("cell"),
+                        material: sa.material("cell"),
+                        ..Default::default()
+                    })
+                    .insert(GridMesh);
+            }
+            if grid.stairs(i).is_some() {
+                commands
+                    .spawn(PbrBundle {
+                        transform: Transform::from_xyz(x, y, 0.01),
+                        mesh: sa.mesh("cell"),
+                        material: sa.material("stairs"),
+                        ..Default::default()
+                    })
+                    .insert(GridMesh);
+            }
+            // hidden traps stay hidden until someone finds them
+            let object = match grid.object(i) {
+                Some(Interactable::Door(DoorState::Open)) => Some("door_open"),
+                Some(Interactable::Door(_)) => Some("door_closed"),
+                Some(Interactable::Trap(trap)) if !trap.hidden => Some("trap"),
+                Some(Interactable::Lever { .. }) => Some("lever"),
+                Some(Interactable::Chest { .. }) => Some("chest"),
+                Some(Interactable::Torch { .. }) => Some("torch"),
+                _ => None,
+            };
+            if let Some(material) = object {
+                commands
+                    .spawn(PbrBundle {
+                        transform: Transform::from_xyz(x, y, 0.02),
+                        mesh: sa.mesh("cell"),
+                        material: sa.material(material),
+                        ..Default::default()
+                    })
+                    .insert(GridMesh);
+            }
+            commands
+                .spawn(PbrBundle {
+                    transform: Transform::from_xyz(x, y, 1.02),
+                    mesh: sa.mesh("cell"),
+                    material: sa.material("black"),
+                    ..Default::default()
+                })
+                .insert(FogCell(i))
+                .insert(GridMesh);
+            if let Some(Interactable::Torch { lit: true }) = grid.object(i) {
+                commands
+                    .spawn(PointLightBundle {
+                        point_light: PointLight {
+                            color: Color::rgb(1.0, 0.7, 0.4),
+                            intensity: 200.0,
+                            range: 8.0,
+                            ..default()
+                        },
+                        transform: Transform::from_xyz(x, y, 1.5),
+                        ..default()
+                    })
+                    .insert(GridMesh);
+            }
+        }
+    }
+}
+
+fn on_spawn_token_system(
+    mut commands: Commands,
+    q: Query<(Entity, &Token), Added<Token>>,
+    sa: Res<CommonAssets>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    asset_server: Res<AssetServer>,
+) {
+    for (e, token) in q.iter() {
+        commands
+            .entity(e)
+            .insert(PbrBundle {
+                transform: Transform::from_translation(Token::pos(token.grid_pos)),
+                mesh: sa.mesh("token"),
+                material: materials.add(StandardMaterial {
+                    base_color_texture: Some(
+                        asset_server.load(format!("images/{}.png", &token.image)),
+                    ),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .with_children(|child_builder| {
+                if token.player.is_some() {
+                    child_builder.spawn(PointLightBundle {
+                        point_light:PointLight {
+                            intensity:30.0,
+                            range:6.0,
+                            shadows_enabled:true,
+                            ..Default::default()
+                        },
+                        transform:Transform::from_translation(Vec3::new(0.0, 0.0, 1.0)),
+                        ..Default::default()
+                    });
+                }
+            });
+    }
+}
+
+/// Shows the map as the encounter faction sees it. Unexplored cells are black, explored ones
+/// out of sight are dimmed, and other factions' tokens are hidden while out of sight or hiding.
+fn fog_system(
+    vision: Res<Vision>,
+    encounter: Res<Encounter>,
+    sa: Res<CommonAssets>,
+    mut fog: Query<(&FogCell, &mut Visibility, &mut Handle<StandardMaterial>)>,
+    added: Query<(), Added<FogCell>>,
+    mut tokens: Query<(Entity, &Token, &mut Visibility), Without<FogCell>>,
+) {
+    let faction = encounter.faction.as_str();
+    // tokens get their visibility reset when respawned, so they are checked every frame
+    for (e, token, mut visibility) in tokens.iter_mut() {
+        visibility.set_if_neq(
+            if rules::faction(token) == faction || vision.sees_token(faction, e, token.grid_pos) {
+                Visibility::Inherited
+            } else {
+                Visibility::Hidden
+            },
+        );
+    }
+
+    if !vision.is_changed() && added.is_empty() {
+        return;
+    }
+    for (FogCell(cell), mut visibility, mut material) in fog.iter_mut() {
+        if vision.sees(faction, *cell) {
+            *visibility = Visibility::Hidden;
+            continue;
+        }
+        *visibility = Visibility::Inherited;
+        *material = if vision.has_explored(faction, *cell) {
+            sa.material("fog")
+        } else {
+            sa.material("black")
+        };
+    }
+}
+
+/// Creatures on other floors keep their entity, so they are hidden until the party gets there.
+fn hide_stored_tokens_system(mut stored: Query<&mut Visibility, Added<StoredToken>>) {
+    for mut visibility in stored.iter_mut() {
+        *visibility = Visibility::Hidden;
+    }
+}
+
+/// Animates the token being moved by the command at the front of the round.
+fn animate_round_command_system(
+    round: Res<Round>,
+    mut transforms: Query<&mut Transform>,
+    tokens: Query<&Token>,
+) {
+    let Some(command) = round.front() else {
+        return;
+    };
+    if let common::Variant::MoveTo { who, to } = command.variant {
+        let a = command.alpha();
+        if let Ok(token) = tokens.get(who) {
+            if let Ok(mut transform) = transforms.get_mut(who) {
+                let s = Token::pos(token.grid_pos);
+                let e = Token::pos(to);
+                let v = e - s;
+                let v = v * common::math::smootherstep(0.0, 1.0, a);
+                let z = if a <= 0.5 { a * 2.0 } else { 1.0 - (a - 0.5) * 2.0 };
+                let z = z * 0.2;
+                transform.translation = s + v + Vec3::new(0.0, 0.0, z);
+            }
+        }
+    }
+}
+
+/// Puts every token that isn't being animated on its cell, e.g. after a move finished or
+/// was undone.
+fn snap_tokens_system(round: Res<Round>, mut tokens: Query<(Entity, &Token, &mut Transform)>) {
+    let moving = match round.front().map(|command| &command.variant) {
+        Some(common::Variant::MoveTo { who, .. }) => Some(*who),
+        _ => None,
+    };
+    for (e, token, mut transform) in tokens.iter_mut() {
+        if Some(e) == moving {
+            continue;
+        }
+        let pos = Token::pos(token.grid_pos);
+        if transform.translation != pos {
+            transform.translation = pos;
+        }
+    }
+}
+
+pub fn add_systems(app: &mut App) {
+    app.add_systems(Startup, startup_system);
+    app.add_systems(
+        PostUpdate,
+        (
+            spawn_grid_system,
+            on_spawn_token_system,
+            hide_stored_tokens_system,
+            fog_system.after(spawn_grid_system).after(on_spawn_token_system),
+            (animate_round_command_system, snap_tokens_system)
+                .chain()
+                .before(TransformSystem::TransformPropagate),
+        ),
+    );
+}
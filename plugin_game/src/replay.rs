@@ -0,0 +1,151 @@
+use bevy::prelude::*;
+use common::{
+    Dice, FeatureCatalog, FeatureDef, GameState, Inventory, Item, ItemCatalog, PersistenceEvent,
+    Player, Recorder, Replay, ReplayLog, Round, Statblock, Token,
+};
+
+use crate::{
+    encounter::GameEntities,
+    save::{load_game, Snapshot},
+};
+
+/// Starts a new log from the current game whenever the recorder was reset.
+pub(crate) fn start_recording_system(mut recorder: ResMut<Recorder>, snapshot: Snapshot) {
+    if recorder.log.is_none() {
+        recorder.log = Some(ReplayLog::new(snapshot.save_game()));
+    }
+}
+
+/// Runs right after `finish_round_command_system`, attaching the dice rolled by the finished
+/// command to its entry, and checking them against the recorded ones while replaying.
+pub(crate) fn record_draws_system(
+    mut recorder: ResMut<Recorder>,
+    mut dice: ResMut<Dice>,
+    replay: Option<ResMut<Replay>>,
+) {
+    let draws = dice.take_draws();
+    if draws.is_empty() {
+        return;
+    }
+    if let Some(mut replay) = replay {
+        replay.draws.extend_from_slice(&draws);
+    }
+    recorder.push_draws(&draws);
+}
+
+/// Feeds the next recorded command once the previous one has finished executing and the game
+/// is in the state it was recorded in.
+pub(crate) fn replay_feed_system(
+    mut commands: Commands,
+    replay: Option<ResMut<Replay>>,
+    mut round: ResMut<Round>,
+    state: Res<State<GameState>>,
+    tokens: Query<Option<&Handle<Statblock>>, With<Token>>,
+    statblocks: Res<Assets<Statblock>>,
+    inventories: Query<Option<&Inventory>, With<Token>>,
+    catalog: Res<ItemCatalog>,
+    items: Res<Assets<Item>>,
+    feature_catalog: Res<FeatureCatalog>,
+    features: Res<Assets<FeatureDef>>,
+) {
+    let Some(mut replay) = replay else {
+        return;
+    };
+    if round.is_executing() {
+        return;
+    }
+    // commands would play out differently without the statblocks, features and gear
+    let statblocks_loaded = tokens.iter().all(|handle| {
+        handle
+            .and_then(|h| statblocks.get(h))
+            .is_some_and(|statblock| feature_catalog.is_loaded(statblock, &features))
+    });
+    if !statblocks_loaded
+        || !inventories
+            .iter()
+            .all(|inventory| inventory.is_some_and(|inventory| catalog.is_loaded(inventory, &items)))
+    {
+        return;
+    }
+
+    let Some(entry) = replay.entries.get(replay.cursor) else {
+        if let Err(err) = replay.verify_last() {
+            warn!("replay desynced: {}", err);
+        }
+        info!("replay finished");
+        commands.remove_resource::<Replay>();
+        return;
+    };
+    if replay.paused && !replay.step {
+        return;
+    }
+    if entry.state != *state.get() {
+        return;
+    }
+    let command = entry.command.clone();
+
+    replay.step = false;
+    if let Err(err) = replay.verify_last() {
+        warn!("replay desynced: {}", err);
+        replay.paused = true;
+        return;
+    }
+    replay.cursor += 1;
+    round.push_back(command);
+}
+
+fn save_replay_system(mut reader: EventReader<PersistenceEvent>, recorder: Res<Recorder>) {
+    for ev in reader.iter() {
+        let PersistenceEvent::SaveReplay { path } = ev else {
+            continue;
+        };
+        let result = recorder
+            .log
+            .as_ref()
+            .ok_or_else(|| "nothing recorded".to_string())
+            .and_then(|log| log.to_json())
+            .and_then(|json| std::fs::write(path, json).map_err(|err| err.to_string()));
+        match result {
+            Ok(_) => info!("saved replay to {}", path),
+            Err(err) => error!("failed to save replay to {}: {}", path, err),
+        }
+    }
+}
+
+fn load_replay_system(
+    mut commands: Commands,
+    mut reader: EventReader<PersistenceEvent>,
+    players: Query<Entity, With<Player>>,
+    tokens: GameEntities,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    for ev in reader.iter() {
+        let PersistenceEvent::LoadReplay { path } = ev else {
+            continue;
+        };
+        let log = std::fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|json| ReplayLog::from_json(&json));
+        let mut log = match log {
+            Ok(log) => log,
+            Err(err) => {
+                error!("failed to load replay from {}: {}", path, err);
+                continue;
+            }
+        };
+
+        let map = load_game(
+            &mut commands,
+            players.iter().chain(tokens.iter()),
+            &mut next_state,
+            log.initial.clone(),
+        );
+        log.remap_entities(&map);
+        commands.insert_resource(Replay::new(log.entries));
+        info!("loaded replay from {}", path);
+    }
+}
+
+pub fn add_systems(app: &mut App) {
+    app.add_systems(PreUpdate, (save_replay_system, load_replay_system));
+}
@@ -0,0 +1,174 @@
+use bevy::{ecs::system::SystemParam, prelude::*, utils::HashMap};
+use common::{
+    Dice, Encounter, Factions, Floors, GameState, GeneratedMap, Grid, Inventory, MapObjects,
+    PersistenceEvent, Player, Recorder, Replay, Round, SaveGame, SavedPlayer, SavedToken,
+    StoredToken, Token, Vision, SAVE_VERSION,
+};
+
+use crate::encounter::GameEntities;
+
+/// Read access to everything that goes into a save.
+#[derive(SystemParam)]
+pub(crate) struct Snapshot<'w, 's> {
+    players: Query<'w, 's, (Entity, &'static Player)>,
+    tokens: Query<'w, 's, (Entity, &'static Token)>,
+    stored_tokens: Query<'w, 's, (Entity, &'static StoredToken)>,
+    inventories: Query<'w, 's, &'static Inventory>,
+    grid: Res<'w, Grid>,
+    map_objects: Res<'w, MapObjects>,
+    generated_map: Res<'w, GeneratedMap>,
+    floors: Res<'w, Floors>,
+    vision: Res<'w, Vision>,
+    round: Res<'w, Round>,
+    dice: Res<'w, Dice>,
+    factions: Res<'w, Factions>,
+    encounter: Res<'w, Encounter>,
+    state: Res<'w, State<GameState>>,
+}
+
+impl<'w, 's> Snapshot<'w, 's> {
+    pub fn save_game(&self) -> SaveGame {
+        SaveGame {
+            version: SAVE_VERSION,
+            state: *self.state.get(),
+            players: self
+                .players
+                .iter()
+                .map(|(id, player)| SavedPlayer {
+                    id,
+                    player: player.clone(),
+                })
+                .collect(),
+            tokens: self
+                .tokens
+                .iter()
+                .map(|(id, token)| SavedToken {
+                    id,
+                    token: token.clone(),
+                    floor: None,
+                    inventory: self.inventories.get(id).ok().cloned(),
+                })
+                .chain(self.stored_tokens.iter().map(|(id, stored)| SavedToken {
+                    id,
+                    token: stored.token.clone(),
+                    floor: Some(stored.floor),
+                    inventory: self.inventories.get(id).ok().cloned(),
+                }))
+                .collect(),
+            grid: self.grid.clone(),
+            map_objects: self.map_objects.clone(),
+            generated_map: self.generated_map.clone(),
+            floors: self.floors.clone(),
+            vision: self.vision.clone(),
+            round: self.round.clone(),
+            dice: self.dice.clone(),
+            factions: self.factions.clone(),
+            encounter: self.encounter.clone(),
+        }
+    }
+}
+
+/// Replaces the current game with the saved one and returns how the saved entities were
+/// remapped to the respawned ones.
+pub(crate) fn load_game(
+    commands: &mut Commands,
+    existing: impl Iterator<Item = Entity>,
+    next_state: &mut NextState<GameState>,
+    mut save: SaveGame,
+) -> HashMap<Entity, Entity> {
+    for e in existing {
+        commands.entity(e).despawn_recursive();
+    }
+
+    // respawn every saved entity first, so references between them can be remapped
+    let mut map = HashMap::new();
+    for player in save.players.iter() {
+        map.insert(player.id, commands.spawn_empty().id());
+    }
+    for token in save.tokens.iter() {
+        map.insert(token.id, commands.spawn_empty().id());
+    }
+    save.remap_entities(&map);
+
+    for player in save.players {
+        commands.entity(player.id).insert(player.player);
+    }
+    for token in save.tokens {
+        if let Some(inventory) = token.inventory {
+            commands.entity(token.id).insert(inventory);
+        }
+        match token.floor {
+            Some(floor) => commands.entity(token.id).insert(StoredToken {
+                floor,
+                token: token.token,
+            }),
+            None => commands.entity(token.id).insert(token.token),
+        };
+    }
+    commands.insert_resource(save.grid);
+    commands.insert_resource(save.map_objects);
+    commands.insert_resource(save.generated_map);
+    commands.insert_resource(save.floors);
+    commands.insert_resource(save.vision);
+    commands.insert_resource(save.round);
+    commands.insert_resource(save.dice);
+    commands.insert_resource(save.factions);
+    commands.insert_resource(save.encounter);
+    // recording starts over from the loaded game
+    commands.insert_resource(Recorder::default());
+    next_state.set(save.state);
+    map
+}
+
+fn save_system(mut reader: EventReader<PersistenceEvent>, snapshot: Snapshot) {
+    for ev in reader.iter() {
+        let PersistenceEvent::Save { path } = ev else {
+            continue;
+        };
+        let result = snapshot
+            .save_game()
+            .to_json()
+            .and_then(|json| std::fs::write(path, json).map_err(|err| err.to_string()));
+        match result {
+            Ok(_) => info!("saved game to {}", path),
+            Err(err) => error!("failed to save game to {}: {}", path, err),
+        }
+    }
+}
+
+fn load_system(
+    mut commands: Commands,
+    mut reader: EventReader<PersistenceEvent>,
+    players: Query<Entity, With<Player>>,
+    tokens: GameEntities,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    for ev in reader.iter() {
+        let PersistenceEvent::Load { path } = ev else {
+            continue;
+        };
+        let save = std::fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|json| SaveGame::from_json(&json));
+        let save = match save {
+            Ok(save) => save,
+            Err(err) => {
+                error!("failed to load game from {}: {}", path, err);
+                continue;
+            }
+        };
+
+        load_game(
+            &mut commands,
+            players.iter().chain(tokens.iter()),
+            &mut next_state,
+            save,
+        );
+        commands.remove_resource::<Replay>();
+        info!("loaded game from {}", path);
+    }
+}
+
+pub fn add_systems(app: &mut App) {
+    app.add_systems(PreUpdate, (save_system, load_system));
+}
@@ -1,299 +1,107 @@
-use bevy::prelude::*;
-use common::{CommonAssets, GameEvent, Grid, Player, Round, RoundCommand, Statblock, Token};
-use mapgen::{AreaStartingPosition, BspRooms, MapBuilder, SimpleRooms, XStart, YStart};
-use rand::{rngs::StdRng, SeedableRng};
+use bevy::{ecs::system::SystemParam, prelude::*, utils::HashMap};
+use common::{
+    Attitude, CHARACTERS_FOLDER, CharacterSheet, Condition, Dice, DoorState, Effect, Encounter,
+    Factions, FeatureCatalog, FeatureDef, GameEvent, GameState, Grid, Hidden, Interactable,
+    Inventory, Item, ItemCatalog, Recorder, Replay, Round, RoundCommand, Statblock, StoredToken,
+    Token, UndoSnapshot,
+};
 
-fn startup_system(
-    mut commands: Commands,
-    sa: Res<CommonAssets>,
-    _round: ResMut<Round>,
-    asset_server: Res<AssetServer>,
-) {
-    let mut rng: StdRng = SeedableRng::seed_from_u64(0);
-    let map_size = 64;
-    let mapbuffer = MapBuilder::new(map_size, map_size)
-        .with(BspRooms::new())
-        .with(SimpleRooms::new())
-        .with(mapgen::filter::rooms_corridors_nearest::NearestCorridors::new())
-        .with(AreaStartingPosition::new(XStart::LEFT, YStart::TOP))
-        .build_with_rng(&mut rng);
-
-    let mut grid = Grid::new(map_size);
-    for y in 0..map_size {
-        for x in 0..map_size {
-            let i = IVec2 {
-                x: x as i32,
-                y: y as i32,
-            };
-            let blocked = mapbuffer.is_blocked(x, y);
-            let walkable = mapbuffer.is_walkable(x, y);
-
-            let x = x as f32 + 0.5;
-            let y = y as f32 + 0.5;
-
-            if blocked {
-                commands.spawn(PbrBundle {
-                    mesh: sa.mesh("cube"),
-                    material: sa.material("brick"),
-                    transform: Transform::from_xyz(x, y, 0.0),
-                    ..default()
-                });
-                commands.spawn(PbrBundle {
-                    transform: Transform::from_xyz(x, y, 1.01),
-                    mesh: sa.mesh("cell"),
-                    material: sa.material("black"),
-                    ..Default::default()
-                });
-            }
-            if walkable {
-                commands.spawn(PbrBundle {
-                    transform: Transform::from_xyz(x, y, 0.0),
-                    mesh: sa.mesh("cell"),
-                    material: sa.material("cell"),
-                    ..Default::default()
-                });
-            }
-
-            grid.get_mut(i).unwrap().blocked = blocked;
-            grid.get_mut(i).unwrap().walkable = walkable;
-        }
-    }
-
-    commands.insert_resource(grid);
+use crate::{
+    characters::award_xp_system,
+    checks::check_outcome_system,
+    floors::change_floor_system,
+    replay::{record_draws_system, replay_feed_system, start_recording_system},
+    vision::{sight_changed, update_hidden_system, update_light_system, update_vision_system},
+};
 
-    // spawn ambient lighting
-    commands.insert_resource(AmbientLight {
-        color: Color::WHITE,
-        brightness: 0.00,
-    });
-
-    // spawn player one
-    let p = mapbuffer.starting_point.expect("no starting point found");
-    let player = commands
-        .spawn(Player {
-            name: "Player One".into(),
-        })
-        .id();
-
-    let _e = commands
-        .spawn(Token {
-            name: "William".into(),
-            color: Color::BLUE,
-            grid_pos: IVec2 {
-                x: p.x as i32,
-                y: p.y as i32,
-            },
-            image: "token_william".into(),
-            statblock: "william".into(),
-            player: Some(player),
-            ..Default::default()
-        })
-        .id();
-
-    let _e = commands
-        .spawn(Token {
-            name: "Viktor".into(),
-            color: Color::BLUE,
-            grid_pos: IVec2 {
-                x: p.x as i32 + 1,
-                y: p.y as i32,
-            },
-            image: "token_viktor".into(),
-            player: Some(player),
-            ..Default::default()
-        })
-        .id();
-
-    // spawn a goblins
-    commands.spawn(Token {
-        name: "Goblin 1".into(),
-        color: Color::RED,
-        grid_pos: IVec2 {
-            x: p.x as i32 + 2,
-            y: p.y as i32 + 2,
-        },
-        image: "token_goblin".into(),
-        ..Default::default()
-    });
-
-    commands.spawn(Token {
-        name: "Goblin 2".into(),
-        color: Color::RED,
-        grid_pos: IVec2 {
-            x: p.x as i32 + 4,
-            y: p.y as i32 + 3,
-        },
-        image: "token_goblin".into(),
-        ..Default::default()
-    });
-}
-
-fn on_spawn_token_system(
+/// Every token gets its statblock loaded, the rules need it even without a window. Player
+/// characters get their sheet, which the statblock is resolved from.
+fn load_statblock_system(
     mut commands: Commands,
     q: Query<(Entity, &Token), Added<Token>>,
-    sa: Res<CommonAssets>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    asset_server: Res<AssetServer>,
     ass: Res<AssetServer>,
 ) {
     for (e, token) in q.iter() {
+        if let Some(character) = token.character.as_ref() {
+            let handle: Handle<CharacterSheet> =
+                ass.load(format!("{}/{}.toml", CHARACTERS_FOLDER, character));
+            commands.entity(e).insert(handle);
+            continue;
+        }
         let handle: Handle<Statblock> = ass.load(format!("statblocks/{}.toml", token.statblock));
-        commands
-            .entity(e)
-            .insert(PbrBundle {
-                transform: Transform::from_translation(Token::pos(token.grid_pos)),
-                mesh: sa.mesh("token"),
-                material: materials.add(StandardMaterial {
-                    base_color_texture: Some(
-                        asset_server.load(format!("images/{}.png", &token.image)),
-                    ),
-                    ..Default::default()
-                }),
-                ..Default::default()
-            })
-            .insert(handle)
-            .with_children(|child_builder| {
-                if token.player.is_some() {
-                    child_builder.spawn(PointLightBundle {
-                        point_light:PointLight {
-                            intensity:30.0,
-                            range:6.0,
-                            shadows_enabled:true,
-                            ..Default::default()
-                        },
-                        transform:Transform::from_translation(Vec3::new(0.0, 0.0, 1.0)),
-                        ..Default::default()
-                    });
-                }
-            });
+        commands.entity(e).insert(handle);
     }
 }
 
-fn update_round_command(
-    command: &mut RoundCommand,
-    _round: &mut ResMut<Round>,
-    _time: &Res<Time>,
-    tokens: &mut Query<&mut Token>,
-    transforms: &mut Query<&mut Transform>,
+/// Features are loaded as statblocks that have them come in.
+fn load_features_system(
+    mut events: EventReader<AssetEvent<Statblock>>,
+    statblocks: Res<Assets<Statblock>>,
+    mut catalog: ResMut<FeatureCatalog>,
+    ass: Res<AssetServer>,
 ) {
-    match command.variant {
-        common::Variant::Nop => {}
-        common::Variant::MoveTo { who, to } => {
-            let a = command.alpha();
-            if let Ok(token) = tokens.get(who) {
-                if let Ok(mut transform) = transforms.get_mut(who) {
-                    let s = Token::pos(token.grid_pos);
-                    let e = Token::pos(to);
-                    let v = e - s;
-                    let v = v * common::math::smootherstep(0.0, 1.0, a);
-                    let mut z = 0.0;
-                    if a <= 0.5 {
-                        z = a * 2.0;
-                    } else {
-                        z = 1.0 - (a - 0.5) * 2.0;
-                    }
-                    z *= 0.2;
-                    transform.translation = s + v + Vec3::new(0.0, 0.0, z);
-                }
+    for ev in events.iter() {
+        let (AssetEvent::Created { handle } | AssetEvent::Modified { handle }) = ev else {
+            continue;
+        };
+        let Some(statblock) = statblocks.get(handle) else {
+            continue;
+        };
+        for id in statblock.features.iter() {
+            if !catalog.handles.contains_key(id) {
+                let handle: Handle<FeatureDef> = ass.load(format!("features/{}.toml", id));
+                catalog.handles.insert(id.clone(), handle);
             }
         }
-        common::Variant::MoveFar { who: _, to: _ } => {}
-        common::Variant::EndTurn { who: _ } => {}
-        common::Variant::EndRound {} => {}
-        common::Variant::RecvTurn { who: _ } => {}
     }
 }
 
-fn finish_round_command(
-    command: RoundCommand,
-    round: &mut ResMut<Round>,
-    _time: &Res<Time>,
-    tokens: &mut Query<&mut Token>,
-    transforms: &mut Query<&mut Transform>,
-    grid: &mut Grid,
+/// Items are loaded as they show up in someone's inventory.
+fn load_items_system(
+    inventories: Query<&Inventory, Changed<Inventory>>,
+    mut catalog: ResMut<ItemCatalog>,
+    ass: Res<AssetServer>,
 ) {
-    match command.variant {
-        common::Variant::Nop => {}
-        common::Variant::MoveTo { who, to } => {
-            if let Ok(mut token) = tokens.get_mut(who) {
-                token.grid_pos = to;
-                if let Ok(mut transform) = transforms.get_mut(who) {
-                    transform.translation = Token::pos(token.grid_pos);
-                }
+    for inventory in inventories.iter() {
+        for stack in inventory.items.iter() {
+            if !catalog.handles.contains_key(&stack.item) {
+                let handle: Handle<Item> = ass.load(format!("items/{}.toml", stack.item));
+                catalog.handles.insert(stack.item.clone(), handle);
             }
         }
-        common::Variant::MoveFar { who, to } => {
-            if let Ok(token) = tokens.get(who) {
-                let path = rules::get_path(token, grid, to);
-                if !path.is_empty() {
-                    for p in path.iter().rev() {
-                        round.push_front(RoundCommand::move_to(who, p.to));
-                    }
-                }
-            }
-        }
-        common::Variant::EndTurn { who } => {
-            if round.active_entity == Some(who) {
-                round.active_entity = None;
-                round.has_taken_turn.insert(who, ());
-            }
-        }
-        common::Variant::EndRound {} => {
-            round.has_taken_turn.clear();
-            round.active_entity = None;
-            round.round_num += 1;
-        }
-        common::Variant::RecvTurn { who } => {
-            round.active_entity = Some(who);
-        }
     }
 }
 
+/// Advances the timer of the command at the front, the presentation animates it.
 fn update_round_command_system(
     mut round: ResMut<Round>,
     time: Res<Time>,
-    mut transforms: Query<&mut Transform>,
-    tokens: Query<&mut Token>,
+    replay: Option<Res<Replay>>,
 ) {
     let Some(command) = round.front_mut() else {
         return;
     };
-    command.timer_elapsed_sec += time.delta_seconds();
+    let speed = replay.map_or(1.0, |replay| replay.speed);
+    command.timer_elapsed_sec += time.delta_seconds() * speed;
     command.timer_elapsed_sec = command.timer_elapsed_sec.min(command.timer);
-    match command.variant {
-        common::Variant::Nop => {}
-        common::Variant::MoveTo { who, to } => {
-            let a = command.alpha();
-            if let Ok(token) = tokens.get(who) {
-                if let Ok(mut transform) = transforms.get_mut(who) {
-                    let s = Token::pos(token.grid_pos);
-                    let e = Token::pos(to);
-                    let v = e - s;
-                    let v = v * common::math::smootherstep(0.0, 1.0, a);
-                    let mut z = 0.0;
-                    if a <= 0.5 {
-                        z = a * 2.0;
-                    } else {
-                        z = 1.0 - (a - 0.5) * 2.0;
-                    }
-                    z *= 0.2;
-                    transform.translation = s + v + Vec3::new(0.0, 0.0, z);
-                }
-            }
-        }
-        common::Variant::MoveFar { who: _, to: _ } => {}
-        common::Variant::EndTurn { who: _ } => {}
-        common::Variant::EndRound {} => {}
-        common::Variant::RecvTurn { who } => {}
-    }
 }
 
 fn finish_round_command_system(
     mut round: ResMut<Round>,
     mut tokens: Query<&mut Token>,
+    token_entities: Query<Entity, With<Token>>,
     mut statblock_handles: Query<&Handle<Statblock>>,
-    grid: Res<Grid>,
+    mut grid: ResMut<Grid>,
     mut statblocks: Res<Assets<Statblock>>,
+    mut dice: ResMut<Dice>,
+    state: Res<State<GameState>>,
+    mut next_state: ResMut<NextState<GameState>>,
+    factions: Res<Factions>,
+    encounter: Res<Encounter>,
+    mut ge: EventWriter<GameEvent>,
+    mut recorder: ResMut<Recorder>,
+    mut inventories: Query<&mut Inventory>,
+    defs: Definitions,
 ) {
     let Some(command) = round.front_mut() else {
         return;
@@ -304,10 +112,60 @@ fn finish_round_command_system(
     let Some(command) = round.pop_front() else {
         return;
     };
+    recorder.push(*state.get(), &command);
+    ge.send(GameEvent::CommandFinished);
+    // moves can be taken back until something irreversible happens
+    if !command.variant.is_reversible() {
+        round.undo.clear();
+    }
 
     match command.variant {
         common::Variant::Nop => {}
         common::Variant::MoveTo { who, to } => {
+            let Ok(token) = tokens.get(who) else {
+                return;
+            };
+            if rules::is_defeated(token) {
+                return;
+            }
+
+            // leaving the reach of an enemy provokes an opportunity attack before the step
+            if *state.get() == GameState::Combat {
+                for other in sorted_tokens(&token_entities, &tokens) {
+                    if other == who || !round.can_react(other) {
+                        continue;
+                    }
+                    let Ok(other_token) = tokens.get(other) else { continue };
+                    if rules::is_defeated(other_token)
+                        || !other_token.reaction
+                        || !rules::is_hostile(&factions, other_token, token)
+                    {
+                        continue;
+                    }
+                    let Some(statblock) =
+                        statblock_handles.get(other).ok().and_then(|h| statblocks.get(h))
+                    else {
+                        continue;
+                    };
+                    // only melee attacks can be made as opportunity attacks
+                    let attacks =
+                        rules::attacks(statblock, &defs.equipped(other, &inventories));
+                    let Some(index) = attacks.iter().position(|attack| attack.range_ft == 0) else {
+                        continue;
+                    };
+                    let attack = &attacks[index];
+                    if rules::in_reach(other_token.grid_pos, attack, token.grid_pos)
+                        && !rules::in_reach(other_token.grid_pos, attack, to)
+                    {
+                        round.push_front(RoundCommand::move_to(who, to).derived());
+                        round.push_front(
+                            RoundCommand::opportunity_attack(other, who, index).derived(),
+                        );
+                        return;
+                    }
+                }
+            }
+
             let Ok(mut token) = tokens.get_mut(who) else {
                 return;
             };
@@ -317,23 +175,208 @@ fn finish_round_command_system(
             if m < 0.0 {
                 return;
             };
+            if !command.derived {
+                round.undo.push(UndoSnapshot {
+                    who,
+                    grid_pos: token.grid_pos,
+                    movement_ft: token.movement_ft,
+                });
+            }
             token.movement_ft = m;
             token.grid_pos = to;
+
+            // stairs take the party along when a move ends on them, outside of combat
+            let moving_on = matches!(
+                round.front().map(|command| &command.variant),
+                Some(common::Variant::MoveTo { who: next, .. }) if *next == who
+            );
+            if let Some(stairs) = grid.stairs(to) {
+                if !moving_on
+                    && *state.get() != GameState::Combat
+                    && rules::faction(&token) == encounter.faction
+                {
+                    ge.send(GameEvent::TookStairs { who, stairs });
+                }
+            }
+
+            // armed traps go off under whoever steps on them, hidden ones nearby may be found
+            let Some(statblock) = statblock_handles.get(who).ok().and_then(|h| statblocks.get(h))
+            else {
+                return;
+            };
+            if let Some(Interactable::Trap(mut trap)) = grid.object(to) {
+                if trap.armed {
+                    let features = defs.features(statblock);
+                    let damage =
+                        rules::spring_trap(&mut trap, &token, statblock, &features, &mut dice);
+                    token.hit_points = (token.hit_points - damage).max(0);
+                    if let Some(cell) = grid.get_mut(to) {
+                        cell.object = Some(Interactable::Trap(trap));
+                    }
+                    ge.send(GameEvent::TrapSprung {
+                        who,
+                        cell: to,
+                        damage,
+                    });
+                    // the damage is done, the move can't be taken back anymore
+                    round.undo.clear();
+                }
+            }
+            for y in -1..=1 {
+                for x in -1..=1 {
+                    let p = to + IVec2::new(x, y);
+                    let Some(Interactable::Trap(mut trap)) = grid.object(p) else {
+                        continue;
+                    };
+                    if trap.hidden && trap.armed && rules::finds_trap(
+                        statblock,
+                        &trap,
+                        rules::perceived_light(&grid, statblock, to, p),
+                    ) {
+                        trap.hidden = false;
+                        if let Some(cell) = grid.get_mut(p) {
+                            cell.object = Some(Interactable::Trap(trap));
+                        }
+                        // nor can what was learned by finding the trap
+                        round.undo.clear();
+                    }
+                }
+            }
         }
         common::Variant::MoveFar { who, to } => {
             if let Ok(token) = tokens.get(who) {
                 let path = rules::get_path(token, &grid, to);
                 if !path.is_empty() {
+                    round.undo.push(UndoSnapshot {
+                        who,
+                        grid_pos: token.grid_pos,
+                        movement_ft: token.movement_ft,
+                    });
                     for p in path.iter().rev() {
-                        round.push_front(RoundCommand::move_to(who, p.to));
+                        round.push_front(RoundCommand::move_to(who, p.to).derived());
                     }
                 }
             }
         }
+        common::Variant::Attack {
+            who,
+            target,
+            attack,
+            reaction,
+        } => {
+            if *state.get() != GameState::Combat {
+                return;
+            }
+            let (Ok(attacker), Ok(defender)) = (tokens.get(who), tokens.get(target)) else {
+                return;
+            };
+            if rules::is_defeated(attacker)
+                || rules::is_defeated(defender)
+                || rules::attitude(&factions, attacker, defender) == Attitude::Ally
+            {
+                return;
+            }
+            if reaction {
+                if !attacker.reaction || !round.can_react(who) {
+                    return;
+                }
+            } else if attacker.actions == 0 {
+                return;
+            }
+            let Some(statblock) = statblock_handles.get(who).ok().and_then(|h| statblocks.get(h))
+            else {
+                return;
+            };
+            let attacks = rules::attacks(statblock, &defs.equipped(who, &inventories));
+            let Some(attack) = attacks.get(attack) else {
+                return;
+            };
+            if !rules::can_attack(&grid, attacker.grid_pos, attack, defender.grid_pos) {
+                return;
+            }
+            // ammunition and thrown weapons are used up
+            if let Some(item) = attack.uses_item.as_ref() {
+                let Ok(mut inventory) = inventories.get_mut(who) else {
+                    return;
+                };
+                if !inventory.take(item) {
+                    return;
+                }
+            }
+            let Some(target_statblock) =
+                statblock_handles.get(target).ok().and_then(|h| statblocks.get(h))
+            else {
+                return;
+            };
+            let armor_class = rules::armor_class(
+                target_statblock,
+                &defs.features(target_statblock),
+                &defs.equipped(target, &inventories),
+            );
+
+            let roll = rules::attack_roll(
+                &grid,
+                who,
+                attacker,
+                statblock,
+                target,
+                defender,
+                target_statblock,
+            );
+            let features = defs.features(statblock);
+            let result =
+                rules::roll_attack(attacker, &features, attack, armor_class, roll, &mut dice);
+            // an ally of the attacker next to the target, for features such as Sneak Attack
+            let ally_adjacent = token_entities
+                .iter()
+                .filter(|e| *e != who)
+                .filter_map(|e| tokens.get(e).ok())
+                .any(|other| {
+                    !rules::is_defeated(other)
+                        && rules::attitude(&factions, attacker, other) == Attitude::Ally
+                        && (other.grid_pos - defender.grid_pos).abs().max_element() <= 1
+                });
+            let mut damage_roll = None;
+            if let Ok(mut attacker) = tokens.get_mut(who) {
+                if result.hit {
+                    damage_roll = Some(rules::roll_damage(
+                        &mut attacker,
+                        statblock,
+                        &features,
+                        attack,
+                        &result,
+                        ally_adjacent,
+                        &mut dice,
+                    ));
+                }
+                // attacking gives away where the attacker is
+                attacker.hidden = None;
+                if reaction {
+                    attacker.reaction = false;
+                } else {
+                    attacker.actions -= 1;
+                }
+            }
+            let mut damage = damage_roll.as_ref().map_or(0, |roll| roll.total.max(0));
+            if let Ok(mut defender) = tokens.get_mut(target) {
+                let target_features = defs.features(target_statblock);
+                damage = rules::damage_taken(&defender, &target_features, damage);
+                defender.hit_points = (defender.hit_points - damage).max(0);
+            }
+            ge.send(GameEvent::Attacked {
+                attacker: who,
+                target,
+                hit: result.hit,
+                damage,
+                roll: result.roll,
+                damage_roll,
+            });
+        }
         common::Variant::EndTurn { who: turn_giver } => {
             if round.active_entity == Some(turn_giver) {
                 round.active_entity = None;
                 round.has_taken_turn.insert(turn_giver, ());
+                round.surprised.remove(&turn_giver);
             }
         }
         common::Variant::EndRound {} => {
@@ -342,6 +385,11 @@ fn finish_round_command_system(
             round.round_num += 1;
         }
         common::Variant::RecvTurn { who } => {
+            // Once-per-turn features are once per anyone's turn, so they can be used again on
+            // reactions outside of the creature's own turn.
+            for mut token in tokens.iter_mut().filter(|token| !token.features_used.is_empty()) {
+                token.features_used.clear();
+            }
             let Ok(mut token) = tokens.get_mut(who) else {
                 return;
             };
@@ -353,23 +401,673 @@ fn finish_round_command_system(
                 return;
             };
 
+            rules::start_turn(&mut token, statblock);
+            let features = defs.features(statblock);
+            rules::start_turn_features(&mut token, statblock, &features, &mut dice);
+        }
+        common::Variant::BeginCombat {} => {
+            round.active_entity = None;
+            round.has_taken_turn.clear();
+            round.initiative_order.clear();
+            round.initiative.clear();
+            round.surprised.clear();
+            round.round_num = 1;
+            next_state.set(GameState::Combat);
+
+            // sneaking creatures roll stealth once against everyone on the other side
+            let order = sorted_tokens(&token_entities, &tokens);
+            let mut stealth = HashMap::new();
+            for &e in order.iter() {
+                let Ok(token) = tokens.get(e) else { continue };
+                if !token.sneaking || rules::is_defeated(token) {
+                    continue;
+                }
+                let Some(statblock) = statblock_handles.get(e).ok().and_then(|h| statblocks.get(h))
+                else {
+                    continue;
+                };
+                let features = defs.features(statblock);
+                let roll = rules::roll_stealth(token, statblock, &features, &mut dice);
+                stealth.insert(e, roll.total);
+            }
+            for &e in order.iter() {
+                let Ok(token) = tokens.get(e) else { continue };
+                let Some(statblock) = statblock_handles.get(e).ok().and_then(|h| statblocks.get(h))
+                else {
+                    continue;
+                };
+                let enemies = token_entities
+                    .iter()
+                    .filter_map(|other| {
+                        let other_token = tokens.get(other).ok()?;
+                        if rules::is_defeated(other_token)
+                            || !rules::is_hostile(&factions, token, other_token)
+                        {
+                            return None;
+                        }
+                        let perception = rules::can_see(
+                            &grid,
+                            statblock,
+                            token.grid_pos,
+                            other_token.grid_pos,
+                        )
+                        .then(|| {
+                            let light = rules::perceived_light(
+                                &grid,
+                                statblock,
+                                token.grid_pos,
+                                other_token.grid_pos,
+                            );
+                            rules::passive_perception_in(statblock, light)
+                        })
+                        .flatten();
+                        Some((stealth.get(&other).copied(), perception))
+                    })
+                    .collect::<Vec<_>>();
+                if rules::is_surprised(&enemies) {
+                    round.surprised.insert(e, ());
+                }
+            }
+            for &e in order.iter() {
+                let Ok(mut token) = tokens.get_mut(e) else {
+                    continue;
+                };
+                token.sneaking = false;
+                let Some(statblock) = statblock_handles.get(e).ok().and_then(|h| statblocks.get(h))
+                else {
+                    continue;
+                };
+                let features = defs.features(statblock);
+                let initiative = rules::roll_initiative(&token, statblock, &features, &mut dice);
+                round.initiative.insert(e, initiative.total);
+                round.initiative_order.push(e);
+            }
+        }
+        common::Variant::EndCombat {} => {
+            round.active_entity = None;
+            round.has_taken_turn.clear();
+            round.initiative_order.clear();
+            round.initiative.clear();
+            round.surprised.clear();
+            next_state.set(GameState::Exploration);
+            // features like Rage end with the fight, as do Bless and Bane
+            for mut token in tokens.iter_mut() {
+                token.active_features.clear();
+                token.conditions.retain(|c| !matches!(c, Condition::Blessed | Condition::Baned));
+            }
+        }
+        common::Variant::ShortRest { who, hit_dice } => {
+            if *state.get() == GameState::Combat {
+                return;
+            }
+            next_state.set(GameState::Resting);
+            let Ok(mut token) = tokens.get_mut(who) else {
+                return;
+            };
+            let Ok(statblock_handle) = statblock_handles.get(who) else {
+                return;
+            };
+            let Some(statblock) = statblocks.get(statblock_handle) else {
+                return;
+            };
+            rules::short_rest(&mut token, statblock, hit_dice, &mut dice);
+        }
+        common::Variant::LongRest { who } => {
+            if *state.get() == GameState::Combat {
+                return;
+            }
+            next_state.set(GameState::Resting);
+            let Ok(mut token) = tokens.get_mut(who) else {
+                return;
+            };
+            let Ok(statblock_handle) = statblock_handles.get(who) else {
+                return;
+            };
+            let Some(statblock) = statblocks.get(statblock_handle) else {
+                return;
+            };
+            rules::long_rest(&mut token, statblock);
+        }
+        common::Variant::Sneak { who, sneaking } => {
+            if *state.get() == GameState::Combat {
+                return;
+            }
+            if let Ok(mut token) = tokens.get_mut(who) {
+                token.sneaking = sneaking;
+            }
+        }
+        common::Variant::Hide { who } => {
+            // hiding takes the action in combat
+            let in_combat = *state.get() == GameState::Combat;
+            let Ok(token) = tokens.get(who) else {
+                return;
+            };
+            if rules::is_defeated(token) || (in_combat && token.actions == 0) {
+                return;
+            }
+            let Some(statblock) = statblock_handles.get(who).ok().and_then(|h| statblocks.get(h))
+            else {
+                return;
+            };
+
+            let features = defs.features(statblock);
+            let stealth = rules::roll_stealth(token, statblock, &features, &mut dice).total;
+            let mut from = Vec::new();
+            for observer in sorted_tokens(&token_entities, &tokens) {
+                let Ok(observer_token) = tokens.get(observer) else {
+                    continue;
+                };
+                let Some(observer_statblock) =
+                    statblock_handles.get(observer).ok().and_then(|h| statblocks.get(h))
+                else {
+                    continue;
+                };
+                if observer != who
+                    && !rules::is_defeated(observer_token)
+                    && rules::is_hostile(&factions, token, observer_token)
+                    && rules::hides_from(
+                        &grid,
+                        stealth,
+                        observer_statblock,
+                        observer_token.grid_pos,
+                        token.grid_pos,
+                    )
+                {
+                    from.push(observer);
+                }
+            }
+
+            let Ok(mut token) = tokens.get_mut(who) else {
+                return;
+            };
+            if in_combat {
+                token.actions -= 1;
+            }
+            ge.send(GameEvent::Hid {
+                who,
+                stealth,
+                observers: from.len(),
+            });
+            token.hidden = (!from.is_empty()).then_some(Hidden { stealth, from });
+        }
+        common::Variant::Search { who } => {
+            // searching takes the action in combat
+            let in_combat = *state.get() == GameState::Combat;
+            let Ok(token) = tokens.get(who) else {
+                return;
+            };
+            if rules::is_defeated(token) || (in_combat && token.actions == 0) {
+                return;
+            }
+            let Some(statblock) = statblock_handles.get(who).ok().and_then(|h| statblocks.get(h))
+            else {
+                return;
+            };
+
+            // one Perception check per hidden creature in sight
+            let from = token.grid_pos;
+            let mut found = Vec::new();
+            for other in sorted_tokens(&token_entities, &tokens) {
+                let Ok(other_token) = tokens.get(other) else {
+                    continue;
+                };
+                let Some(hidden) = other_token.hidden.as_ref() else {
+                    continue;
+                };
+                if !other_token.is_hidden_from(who)
+                    || !rules::can_see(&grid, statblock, from, other_token.grid_pos)
+                {
+                    continue;
+                }
+                let light = rules::perceived_light(&grid, statblock, from, other_token.grid_pos);
+                let features = defs.features(statblock);
+                let perception =
+                    rules::roll_perception(token, statblock, &features, light, &mut dice);
+                if perception.meets(hidden.stealth) {
+                    found.push(other);
+                }
+            }
+
+            if let Ok(mut token) = tokens.get_mut(who) {
+                if in_combat {
+                    token.actions -= 1;
+                }
+            }
+            for other in found {
+                let Ok(mut other_token) = tokens.get_mut(other) else {
+                    continue;
+                };
+                rules::reveal(&mut other_token, who);
+                ge.send(GameEvent::Found { who: other, by: who });
+            }
+        }
+        common::Variant::Check {
+            who,
+            check,
+            dc,
+            cell,
+        } => {
+            // in combat a check takes the action
+            let in_combat = *state.get() == GameState::Combat;
+            let Ok(token) = tokens.get(who) else {
+                return;
+            };
+            if rules::is_defeated(token) || (in_combat && token.actions == 0) {
+                return;
+            }
+            let Some(dc) = rules::check_dc(&grid, token.grid_pos, cell, dc) else {
+                return;
+            };
+            let Some(statblock) = statblock_handles.get(who).ok().and_then(|h| statblocks.get(h))
+            else {
+                return;
+            };
+
+            let light =
+                cell.map(|cell| rules::perceived_light(&grid, statblock, token.grid_pos, cell));
+            let features = defs.features(statblock);
+            let roll = rules::roll_check(token, statblock, &features, check, light, &mut dice);
+            let Ok(mut token) = tokens.get_mut(who) else {
+                return;
+            };
+            if in_combat {
+                token.actions -= 1;
+            }
+            // help only lasts for one check
+            token.conditions.retain(|c| *c != Condition::Helped);
+            ge.send(GameEvent::Checked {
+                who,
+                check,
+                dc,
+                success: roll.meets(dc),
+                roll,
+                cell,
+            });
+        }
+        common::Variant::UseItem { who, item } => {
+            // in combat using an item takes the action
+            let in_combat = *state.get() == GameState::Combat;
+            let Ok(token) = tokens.get(who) else {
+                return;
+            };
+            if rules::is_defeated(token) || (in_combat && token.actions == 0) {
+                return;
+            }
+            let Some(statblock) = statblock_handles.get(who).ok().and_then(|h| statblocks.get(h))
+            else {
+                return;
+            };
+            let Some(consumable) = defs
+                .item_catalog
+                .get(&item, &defs.items)
+                .filter(|item| item.consumable.is_some())
+            else {
+                return;
+            };
+            let Ok(mut inventory) = inventories.get_mut(who) else {
+                return;
+            };
+            if !inventory.take(&item) {
+                return;
+            }
+
+            let Ok(mut token) = tokens.get_mut(who) else {
+                return;
+            };
+            let healed = rules::use_consumable(&mut token, statblock, consumable, &mut dice);
+            if in_combat {
+                token.actions -= 1;
+            }
+            ge.send(GameEvent::UsedItem { who, item, healed });
+        }
+        common::Variant::UseFeature { who, feature } => {
+            let in_combat = *state.get() == GameState::Combat;
+            let Some(statblock) = statblock_handles.get(who).ok().and_then(|h| statblocks.get(h))
+            else {
+                return;
+            };
+            let Some(def) = defs.feature_catalog.get(&feature, &defs.features) else {
+                return;
+            };
+            let Ok(mut token) = tokens.get_mut(who) else {
+                return;
+            };
+            let Some(healed) =
+                rules::use_feature(&mut token, statblock, &feature, def, in_combat, &mut dice)
+            else {
+                return;
+            };
+            // e.g. Bless on the allies around
+            let user = token.clone();
+            for effect in def.effects.iter() {
+                let Effect::Condition {
+                    condition,
+                    range_ft,
+                    hostile,
+                } = effect
+                else {
+                    continue;
+                };
+                for mut other in tokens.iter_mut() {
+                    if rules::gains_condition(&factions, &user, &other, *range_ft, *hostile)
+                        && !other.conditions.contains(condition)
+                    {
+                        other.conditions.push(condition.clone());
+                    }
+                }
+            }
+            ge.send(GameEvent::UsedFeature {
+                who,
+                feature,
+                healed,
+            });
+        }
+        common::Variant::Undo {} => {
+            let Some(snapshot) = round.undo.pop() else {
+                return;
+            };
+            if let Ok(mut token) = tokens.get_mut(snapshot.who) {
+                token.grid_pos = snapshot.grid_pos;
+                token.movement_ft = snapshot.movement_ft;
+            }
+        }
+        common::Variant::Interact { who, cell } => {
+            let Ok(token) = tokens.get(who) else {
+                return;
+            };
+            if rules::is_defeated(token) || (token.grid_pos - cell).abs().max_element() > 1 {
+                return;
+            }
+            let Some(mut object) = grid.object(cell) else {
+                return;
+            };
+            // a door can't be closed on someone standing in the doorway
+            if object == Interactable::Door(DoorState::Open)
+                && tokens.iter().any(|token| token.grid_pos == cell)
+            {
+                return;
+            }
+            // in combat the free object interaction goes first, then an action
+            let in_combat = *state.get() == GameState::Combat;
+            if in_combat && !token.object_interaction && token.actions == 0 {
+                return;
+            }
+            let Some(statblock) = statblock_handles.get(who).ok().and_then(|h| statblocks.get(h))
+            else {
+                return;
+            };
+
+            let features = defs.features(statblock);
+            let interaction = rules::interact(&mut object, token, statblock, &features, &mut dice);
+            if interaction == rules::Interaction::Nothing {
+                return;
+            }
+            let Ok(mut token) = tokens.get_mut(who) else {
+                return;
+            };
+            if in_combat {
+                if token.object_interaction {
+                    token.object_interaction = false;
+                } else {
+                    token.actions -= 1;
+                }
+            }
+            if let (rules::Interaction::Sprung, Interactable::Trap(trap)) = (interaction, &mut object)
+            {
+                let damage = rules::spring_trap(trap, &token, statblock, &features, &mut dice);
+                token.hit_points = (token.hit_points - damage).max(0);
+                ge.send(GameEvent::TrapSprung { who, cell, damage });
+            }
+            if let Interactable::Lever {
+                door: Some(door), ..
+            } = object
+            {
+                if let Some(door) = grid.get_mut(door).and_then(|c| c.object.as_mut()) {
+                    rules::operate_door(door);
+                }
+            }
+            if let Some(c) = grid.get_mut(cell) {
+                c.object = Some(object);
+            }
+        }
+    }
+}
+
+/// Item and feature definitions, by id.
+#[derive(SystemParam)]
+pub(crate) struct Definitions<'w> {
+    item_catalog: Res<'w, ItemCatalog>,
+    items: Res<'w, Assets<Item>>,
+    feature_catalog: Res<'w, FeatureCatalog>,
+    features: Res<'w, Assets<FeatureDef>>,
+}
+
+impl<'w> Definitions<'w> {
+    /// The loaded features of the statblock, with their ids.
+    fn features<'s>(&self, statblock: &'s Statblock) -> Vec<(&'s str, &FeatureDef)> {
+        self.feature_catalog.of(statblock, &self.features)
+    }
+
+    /// The loaded items a creature has equipped, with their ids.
+    fn equipped<'a>(
+        &'a self,
+        e: Entity,
+        inventories: &'a Query<&mut Inventory>,
+    ) -> Vec<(&'a str, &'a Item)> {
+        inventories
+            .get(e)
+            .map(|inventory| inventory.equipped(&self.item_catalog, &self.items))
+            .unwrap_or_default()
+    }
+}
+
+/// Tokens in a stable order, so dice are drawn in the same order no matter how the entities
+/// were spawned, e.g. when replaying.
+fn sorted_tokens(
+    token_entities: &Query<Entity, With<Token>>,
+    tokens: &Query<&mut Token>,
+) -> Vec<Entity> {
+    let mut sorted = token_entities.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|e| {
+        tokens
+            .get(*e)
+            .map(|token| (token.name.clone(), token.grid_pos.x, token.grid_pos.y))
+            .ok()
+    });
+    sorted
+}
+
+/// Runs after `finish_round_command_system` and only on game events, which include every
+/// finished command, so the objectives are evaluated once whatever changed is applied.
+fn evaluate_encounter_system(
+    round: Res<Round>,
+    mut encounter: ResMut<Encounter>,
+    factions: Res<Factions>,
+    tokens: Query<(Entity, &Token)>,
+    stored_tokens: Query<(Entity, &StoredToken)>,
+    mut ge: EventWriter<GameEvent>,
+) {
+    if encounter.outcome.is_some() {
+        return;
+    }
+    // creatures on other floors count too, e.g. hostiles still waiting further down
+    let tokens = tokens
+        .iter()
+        .chain(stored_tokens.iter().map(|(e, stored)| (e, &stored.token)))
+        .collect::<Vec<_>>();
+    if let Some(outcome) = rules::evaluate_encounter(&encounter, &factions, &tokens, round.round_num)
+    {
+        encounter.outcome = Some(outcome);
+        ge.send(GameEvent::EncounterEnded { outcome });
+    }
+}
+
+/// Tokens just spawned or given another statblock.
+type NewStatblock = Or<(Added<Token>, Changed<Handle<Statblock>>)>;
+
+/// Statblocks are applied to tokens that just got one, or once a statblock has loaded.
+fn statblock_to_apply(
+    tokens: Query<(), NewStatblock>,
+    mut events: EventReader<AssetEvent<Statblock>>,
+) -> bool {
+    let loaded = events.iter().count() > 0;
+    loaded || !tokens.is_empty()
+}
+
+/// Also hands out the statblock's gear to tokens that don't carry any yet, saved ones keep theirs.
+fn apply_statblock_system(
+    mut commands: Commands,
+    mut tokens: Query<(Entity, &mut Token, &Handle<Statblock>, Option<&Inventory>)>,
+    statblocks: Res<Assets<Statblock>>,
+) {
+    for (e, mut token, statblock_handle, inventory) in tokens.iter_mut() {
+        let Some(statblock) = statblocks.get(statblock_handle) else {
+            continue;
+        };
+        if inventory.is_none() {
+            commands.entity(e).insert(Inventory {
+                items: statblock.inventory.clone(),
+            });
+        }
+        if !token.statblock_applied {
+            rules::apply_statblock(&mut token, statblock);
+        }
+    }
+}
+
+/// Tokens move freely outside of combat, so their movement is refilled whenever they are idle.
+fn refill_movement_system(
+    round: Res<Round>,
+    mut tokens: Query<(&mut Token, &Handle<Statblock>)>,
+    statblocks: Res<Assets<Statblock>>,
+) {
+    if round.is_executing() {
+        return;
+    }
+    for (mut token, statblock_handle) in tokens.iter_mut() {
+        let Some(statblock) = statblocks.get(statblock_handle) else {
+            continue;
+        };
+        if token.movement_ft != statblock.speed as f32 {
             token.movement_ft = statblock.speed as f32;
         }
     }
 }
 
-fn assign_initiative_system(mut round: ResMut<Round>, tokens: Query<(Entity, &Token)>) {
+fn notice_system(
+    mut round: ResMut<Round>,
+    tokens: Query<(Entity, &Token, &Handle<Statblock>)>,
+    statblocks: Res<Assets<Statblock>>,
+    grid: Res<Grid>,
+    factions: Res<Factions>,
+    feature_catalog: Res<FeatureCatalog>,
+    features: Res<Assets<FeatureDef>>,
+) {
+    if round.is_executing() {
+        return;
+    }
+    // combat waits until everyone involved has their statblock and features
+    let ready = |token: &Token, handle: &Handle<Statblock>| {
+        token.statblock_applied
+            && statblocks
+                .get(handle)
+                .is_some_and(|statblock| feature_catalog.is_loaded(statblock, &features))
+    };
+    for (e, observer, statblock_handle) in tokens.iter() {
+        if !ready(observer, statblock_handle) || rules::is_defeated(observer) {
+            continue;
+        }
+        let Some(statblock) = statblocks.get(statblock_handle) else {
+            continue;
+        };
+        for (_, target, target_handle) in tokens.iter() {
+            if !ready(target, target_handle)
+                || rules::is_defeated(target)
+                || target.is_hidden_from(e)
+                || !rules::is_hostile(&factions, observer, target)
+            {
+                continue;
+            }
+            if rules::notices(&grid, observer, statblock, target) {
+                round.push_back(RoundCommand::begin_combat());
+                return;
+            }
+        }
+    }
+}
+
+/// Ends combat between turns when no two hostile creatures left standing see each other,
+/// either because one side is defeated or because it fled.
+fn end_combat_system(
+    mut round: ResMut<Round>,
+    tokens: Query<(Entity, &Token, &Handle<Statblock>)>,
+    statblocks: Res<Assets<Statblock>>,
+    grid: Res<Grid>,
+    factions: Res<Factions>,
+) {
+    if round.is_executing() || round.active_entity.is_some() {
+        return;
+    }
+    for (e, a, statblock_handle) in tokens.iter() {
+        if rules::is_defeated(a) {
+            continue;
+        }
+        let Some(statblock) = statblocks.get(statblock_handle) else {
+            continue;
+        };
+        for (_, b, _) in tokens.iter() {
+            if rules::is_defeated(b) || b.is_hidden_from(e) || !rules::is_hostile(&factions, a, b)
+            {
+                continue;
+            }
+            if rules::notices(&grid, a, statblock, b) {
+                return;
+            }
+        }
+    }
+
+    round.push_back(RoundCommand::end_combat());
+}
+
+fn end_rest_system(round: Res<Round>, mut next_state: ResMut<NextState<GameState>>) {
+    if round.is_executing() {
+        return;
+    }
+    next_state.set(GameState::Exploration);
+}
+
+fn assign_initiative_system(
+    mut round: ResMut<Round>,
+    tokens: Query<(Entity, &Token, &Handle<Statblock>)>,
+    statblocks: Res<Assets<Statblock>>,
+    mut dice: ResMut<Dice>,
+    defs: Definitions,
+) {
     if round.is_executing() {
         return;
     }
 
+    // tokens joining an ongoing combat wait for the next round
+    let joining = !round.initiative_order.is_empty();
+
     // push missing to order
-    for (e, _token) in tokens.iter() {
+    for (e, token, statblock_handle) in tokens.iter() {
         if !round.initiative_order.contains(&e) {
+            let Some(statblock) = statblocks.get(statblock_handle) else {
+                continue;
+            };
+            let features = defs.features(statblock);
+            let initiative = rules::roll_initiative(token, statblock, &features, &mut dice);
+            round.initiative.insert(e, initiative.total);
             round.initiative_order.push(e);
-            round.has_taken_turn.insert(e, ());
+            if joining {
+                round.has_taken_turn.insert(e, ());
+            }
         }
     }
+    let mut initiative_order = std::mem::take(&mut round.initiative_order);
+    initiative_order.sort_by_key(|e| -round.initiative.get(e).copied().unwrap_or_default());
+    round.initiative_order = initiative_order;
 
     // cleanup deleted from the order
     let mut initiative_order = std::mem::take(&mut round.initiative_order);
@@ -382,7 +1080,7 @@ fn assign_initiative_system(mut round: ResMut<Round>, tokens: Query<(Entity, &To
 
 fn assign_active_entity_system(
     mut round: ResMut<Round>,
-    _tokens: Query<(Entity, &Token)>,
+    tokens: Query<(Entity, &Token)>,
     mut ge: EventWriter<GameEvent>,
 ) {
     if round.is_executing() {
@@ -394,30 +1092,67 @@ fn assign_active_entity_system(
 
     // no one has the turn, give it to someone or end the round
     let mut next = None;
-    for e in round.initiative_order.iter() {
-        if !round.has_taken_turn.contains_key(e) {
-            ge.send(GameEvent::NextActiveEntity { entity: *e });
-            next = Some(*e);
-            break;
+    for e in round.initiative_order.clone() {
+        if round.has_taken_turn.contains_key(&e) {
+            continue;
         }
+        if let Ok((_, token)) = tokens.get(e) {
+            if rules::is_defeated(token) {
+                round.has_taken_turn.insert(e, ());
+                continue;
+            }
+        }
+        ge.send(GameEvent::NextActiveEntity { entity: e });
+        next = Some(e);
+        break;
     }
     match next {
+        Some(next) if round.surprised.contains_key(&next) => {
+            // a surprised creature loses its first turn
+            round.push_front(RoundCommand::end_turn(next));
+            round.push_front(RoundCommand::recv_turn(next));
+        }
         Some(next) => round.push_front(RoundCommand::recv_turn(next)),
         None => round.push_back(RoundCommand::end_round()),
     }
 }
 
 pub fn add_systems(app: &mut App) {
-    app.add_systems(Startup, startup_system);
     app.add_systems(
         Update,
         (
+            start_recording_system,
+            apply_statblock_system.run_if(statblock_to_apply),
+            // while replaying, the log decides when combat and turns begin and end
+            notice_system
+                .run_if(in_state(GameState::Exploration))
+                .run_if(not(resource_exists::<Replay>())),
+            refill_movement_system.run_if(in_state(GameState::Exploration)),
+            end_combat_system
+                .run_if(in_state(GameState::Combat))
+                .run_if(not(resource_exists::<Replay>())),
+            end_rest_system.run_if(in_state(GameState::Resting)),
+            replay_feed_system,
             update_round_command_system,
             finish_round_command_system,
-            assign_initiative_system,
-            assign_active_entity_system,
+            change_floor_system,
+            check_outcome_system,
+            update_light_system.run_if(sight_changed),
+            update_hidden_system,
+            update_vision_system.run_if(sight_changed),
+            record_draws_system,
+            evaluate_encounter_system.run_if(on_event::<GameEvent>()),
+            // a replay plays out an encounter whose XP was already given
+            award_xp_system.run_if(not(resource_exists::<Replay>())),
+            assign_initiative_system.run_if(in_state(GameState::Combat)),
+            assign_active_entity_system
+                .run_if(in_state(GameState::Combat))
+                .run_if(not(resource_exists::<Replay>())),
         )
             .chain(),
     );
-    app.add_systems(PostUpdate, on_spawn_token_system);
+    app.add_systems(
+        PostUpdate,
+        (load_statblock_system, load_items_system, load_features_system),
+    );
 }
@@ -0,0 +1,174 @@
+use bevy::{prelude::*, utils::HashMap};
+use common::{
+    Attitude, Encounter, FactionVision, Factions, GameEvent, Grid, Interactable, LightLevel, Round,
+    Statblock, Token, Vision,
+};
+
+/// Light and sight only change when a creature moves or its state changes, the map changes,
+/// e.g. a door opens or a torch is lit, or statblocks with vision and lights are loaded.
+pub(crate) fn sight_changed(
+    tokens: Query<(), Changed<Token>>,
+    grid: Res<Grid>,
+    encounter: Res<Encounter>,
+    mut statblock_events: EventReader<AssetEvent<Statblock>>,
+) -> bool {
+    let statblocks_changed = statblock_events.iter().count() > 0;
+    statblocks_changed || grid.is_changed() || encounter.is_changed() || !tokens.is_empty()
+}
+
+/// Relights the map from the ambient light, lit torches and the lights creatures carry.
+/// Light doesn't change how the map looks, so the grid isn't marked as changed.
+pub(crate) fn update_light_system(
+    tokens: Query<(&Token, &Handle<Statblock>)>,
+    statblocks: Res<Assets<Statblock>>,
+    encounter: Res<Encounter>,
+    mut grid: ResMut<Grid>,
+) {
+    let mut sources = Vec::new();
+    for y in 0..grid.height() as i32 {
+        for x in 0..grid.width() as i32 {
+            let i = IVec2::new(x, y);
+            if let Some(Interactable::Torch { lit: true }) = grid.object(i) {
+                sources.push((i, rules::TORCH_LIGHT));
+            }
+        }
+    }
+    for (token, statblock_handle) in tokens.iter() {
+        if rules::is_defeated(token) {
+            continue;
+        }
+        let Some(light) = statblocks.get(statblock_handle).and_then(|s| s.light) else {
+            continue;
+        };
+        sources.push((token.grid_pos, light));
+    }
+    rules::light_grid(
+        grid.bypass_change_detection(),
+        encounter.ambient_light,
+        &sources,
+    );
+}
+
+/// Hidden creatures are found by observers they are no longer concealed from, e.g. once a
+/// light comes near or the observer walks around the cover, or whose passive Perception now
+/// beats their Stealth. A move that gives a creature away can't be undone.
+pub(crate) fn update_hidden_system(
+    mut tokens: Query<(Entity, &mut Token)>,
+    statblock_handles: Query<&Handle<Statblock>>,
+    statblocks: Res<Assets<Statblock>>,
+    grid: Res<Grid>,
+    mut round: ResMut<Round>,
+    mut ge: EventWriter<GameEvent>,
+) {
+    let mut found = Vec::new();
+    for (e, token) in tokens.iter() {
+        let Some(hidden) = token.hidden.as_ref() else {
+            continue;
+        };
+        for &observer in hidden.from.iter() {
+            let Ok((_, observer_token)) = tokens.get(observer) else {
+                found.push((e, observer, false));
+                continue;
+            };
+            if rules::is_defeated(observer_token) {
+                found.push((e, observer, false));
+                continue;
+            }
+            let Some(statblock) = statblock_handles.get(observer).ok().and_then(|h| statblocks.get(h))
+            else {
+                continue;
+            };
+            if !rules::hides_from(
+                &grid,
+                hidden.stealth,
+                statblock,
+                observer_token.grid_pos,
+                token.grid_pos,
+            ) {
+                found.push((e, observer, true));
+            }
+        }
+    }
+    for (e, observer, seen) in found {
+        if let Ok((_, mut token)) = tokens.get_mut(e) {
+            rules::reveal(&mut token, observer);
+        }
+        if seen {
+            round.undo.clear();
+            ge.send(GameEvent::Found { who: e, by: observer });
+        }
+    }
+}
+
+/// Recomputes what each faction sees from the tokens fighting for it, leaving out cells too
+/// dark to see. Cells seen once stay explored for as long as the party is on the floor.
+/// Once the party explores more of the map or sees a hostile creature, moves can't be undone.
+pub(crate) fn update_vision_system(
+    tokens: Query<(Entity, &Token, &Handle<Statblock>)>,
+    statblocks: Res<Assets<Statblock>>,
+    grid: Res<Grid>,
+    encounter: Res<Encounter>,
+    factions: Res<Factions>,
+    mut round: ResMut<Round>,
+    mut vision: ResMut<Vision>,
+) {
+    let mut next = Vision::default();
+    let mut members = HashMap::<&str, Vec<Entity>>::new();
+    for (e, token, statblock_handle) in tokens.iter() {
+        if rules::is_defeated(token) {
+            continue;
+        }
+        members.entry(rules::faction(token)).or_default().push(e);
+        let Some(statblock) = statblocks.get(statblock_handle) else {
+            continue;
+        };
+        let seen = rules::field_of_view(&grid, token.grid_pos, statblock.vision_ft as f32)
+            .into_iter()
+            .filter(|cell| {
+                rules::perceived_light(&grid, statblock, token.grid_pos, *cell) != LightLevel::Dark
+            });
+        next.factions
+            .entry(rules::faction(token).to_string())
+            .or_default()
+            .visible
+            .extend(seen);
+    }
+    for (faction, old) in vision.factions.iter() {
+        let next = next.factions.entry(faction.clone()).or_default();
+        next.explored.extend(old.explored.iter().copied());
+    }
+    for FactionVision { visible, explored, .. } in next.factions.values_mut() {
+        explored.extend(visible.iter().copied());
+    }
+    // a token is hidden from a faction once it hides from every creature of it
+    for (e, token, _) in tokens.iter() {
+        let Some(hidden) = token.hidden.as_ref() else {
+            continue;
+        };
+        for (faction, members) in members.iter() {
+            if members.iter().all(|member| hidden.from.contains(member)) {
+                next.factions
+                    .entry(faction.to_string())
+                    .or_default()
+                    .hidden
+                    .insert(e);
+            }
+        }
+    }
+
+    let hostiles = tokens
+        .iter()
+        .filter(|(_, token, _)| {
+            !rules::is_defeated(token)
+                && factions.attitude(&encounter.faction, rules::faction(token)) == Attitude::Hostile
+        })
+        .map(|(e, token, _)| (e, token.grid_pos))
+        .collect::<Vec<_>>();
+    let unseen = FactionVision::default();
+    let old = vision.factions.get(&encounter.faction).unwrap_or(&unseen);
+    let new = next.factions.get(&encounter.faction).unwrap_or(&unseen);
+    if rules::vision_reveals(old, new, &hostiles) {
+        round.undo.clear();
+    }
+    vision.set_if_neq(next);
+}
@@ -6,6 +6,10 @@ pub struct UIDebugFPS;
 #[derive(Component)]
 pub struct UITurnOwnerName;
 
+/// Shows the last roll and what went into it.
+#[derive(Component)]
+pub struct UILastRoll;
+
 
 #[derive(Resource, Default)]
 pub struct UI {
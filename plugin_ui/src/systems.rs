@@ -6,12 +6,14 @@ use bevy::{
     prelude::*,
 };
 use common::{
-    CommonAssets, GameEvent, Grid, Player, Round, RoundCommand, Selection, Settings, ShortLived,
-    Token,
+    CommonAssets, Encounter, FeatureCatalog, FeatureDef, GameEvent,
+    GameState, Grid, Inventory, Item, ItemCatalog, Outcome, PersistenceEvent, Player, Replay, Round,
+    RoundCommand, Selection, Settings, ShortLived, Statblock, Token, Trigger,
 };
 
 use crate::{
-    GridCursorEvent, HighlightedCell, UIDebugFPS, UITurnOwnerName, Waypoint, WorldCursor, UI, Cam,
+    GridCursorEvent, HighlightedCell, UIDebugFPS, UILastRoll, UITurnOwnerName, Waypoint,
+    WorldCursor, UI, Cam,
 };
 
 fn startup_system(mut commands: Commands, common_assets: ResMut<CommonAssets>) {
@@ -82,6 +84,26 @@ fn startup_system(mut commands: Commands, common_assets: ResMut<CommonAssets>) {
                 )
                 .insert(UITurnOwnerName);
         });
+
+    // spawn last roll
+    commands
+        .spawn(
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    font: font.clone(),
+                    font_size: 16.0,
+                    color: Color::WHITE,
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                bottom: Val::Px(5.0),
+                left: Val::Px(5.0),
+                ..default()
+            }),
+        )
+        .insert(UILastRoll);
 }
 
 fn camera_transform_system(
@@ -242,10 +264,17 @@ fn ray_plane_intersection(ray: Ray) -> Option<Vec3> {
 }
 
 fn grid_cursor_system(
-    ui: ResMut<UI>,
+    mut ui: ResMut<UI>,
     mut reader: EventReader<GridCursorEvent>,
-    _tokens: Query<(Entity, &Token)>,
+    tokens: Query<(Entity, &Token)>,
     mut round: ResMut<Round>,
+    state: Res<State<GameState>>,
+    grid: Res<Grid>,
+    statblock_handles: Query<&Handle<Statblock>>,
+    statblocks: Res<Assets<Statblock>>,
+    inventories: Query<&Inventory>,
+    catalog: Res<ItemCatalog>,
+    items: Res<Assets<Item>>,
 ) {
     if round.is_executing() {
         return;
@@ -253,10 +282,49 @@ fn grid_cursor_system(
     for ev in reader.iter() {
         let grid_pos = ev.grid_pos;
         if ev.left_just_pressed {
+            // outside of combat any of my tokens can be picked by clicking it
+            if *state.get() == GameState::Exploration {
+                let clicked = tokens.iter().find(|(_, token)| {
+                    token.grid_pos == grid_pos && token.player.is_some() && token.player == ui.player
+                });
+                if let Some((clicked_entity, _)) = clicked {
+                    ui.selected_token = Some(clicked_entity);
+                    continue;
+                }
+            }
             if let Some(selected_entity) = ui.selected_token {
                 round.push_front(RoundCommand::move_far(selected_entity, grid_pos))
             }
         }
+        if ev.right_just_pressed {
+            // attack whoever stands on the cell with the first attack that reaches, the command
+            // checks who may be targeted
+            if let Some(selected_entity) = ui.selected_token {
+                let target = tokens.iter().find(|(e, token)| {
+                    *e != selected_entity && token.grid_pos == grid_pos && !rules::is_defeated(token)
+                });
+                let Some((target, _)) = target else {
+                    continue;
+                };
+                let Ok((_, selected)) = tokens.get(selected_entity) else {
+                    continue;
+                };
+                let Some(statblock) =
+                    statblock_handles.get(selected_entity).ok().and_then(|h| statblocks.get(h))
+                else {
+                    continue;
+                };
+                let equipped = inventories
+                    .get(selected_entity)
+                    .map(|inventory| inventory.equipped(&catalog, &items))
+                    .unwrap_or_default();
+                let attack = rules::attacks(statblock, &equipped)
+                    .iter()
+                    .position(|attack| rules::can_attack(&grid, selected.grid_pos, attack, grid_pos))
+                    .unwrap_or(0);
+                round.push_back(RoundCommand::attack(selected_entity, target, attack));
+            }
+        }
     }
 }
 
@@ -385,47 +453,314 @@ fn waypoint_system(
     }
 }
 
-fn action_system(mut ui: ResMut<UI>, mut round: ResMut<Round>, keys: Res<Input<KeyCode>>) {
+fn action_system(
+    mut ui: ResMut<UI>,
+    mut round: ResMut<Round>,
+    keys: Res<Input<KeyCode>>,
+    settings: Res<Settings>,
+    tokens: Query<(Entity, &Token)>,
+    grid: Res<Grid>,
+    state: Res<State<GameState>>,
+    inventories: Query<&Inventory>,
+    catalog: Res<ItemCatalog>,
+    items: Res<Assets<Item>>,
+    statblock_handles: Query<&Handle<Statblock>>,
+    statblocks: Res<Assets<Statblock>>,
+    feature_catalog: Res<FeatureCatalog>,
+    features: Res<Assets<FeatureDef>>,
+) {
     if round.is_executing() {
         return;
     }
+    // in combat only my own moves during my turn can be taken back
+    let my_turn = round.active_entity.is_some() && round.active_entity == ui.selected_token;
+    if keys.just_pressed(settings.undo)
+        && !round.undo.is_empty()
+        && (*state.get() != GameState::Combat || my_turn)
+    {
+        round.push_back(RoundCommand::undo());
+        return;
+    }
     if let Some(entity) = ui.selected_token {
-        if keys.just_pressed(KeyCode::Space) {
-            round.push_back(RoundCommand::end_turn(entity));
-            ui.selected_token = None;
+        if keys.just_pressed(settings.interact) {
+            round.push_back(RoundCommand::interact(entity, ui.grid_cursor));
+            return;
+        }
+        if keys.just_pressed(settings.hide) {
+            round.push_back(RoundCommand::hide(entity));
+            return;
+        }
+        if keys.just_pressed(settings.search) {
+            round.push_back(RoundCommand::search(entity));
+            return;
+        }
+        // drinks the first potion or other consumable carried
+        if keys.just_pressed(settings.use_item) {
+            let consumable = inventories.get(entity).ok().and_then(|inventory| {
+                inventory.items.iter().find(|stack| {
+                    catalog
+                        .get(&stack.item, &items)
+                        .is_some_and(|item| item.consumable.is_some())
+                })
+            });
+            if let Some(stack) = consumable {
+                round.push_back(RoundCommand::use_item(entity, stack.item.clone()));
+                return;
+            }
+        }
+        // number keys use the activated features, e.g. Second Wind
+        let statblock = statblock_handles.get(entity).ok().and_then(|h| statblocks.get(h));
+        if let Some(statblock) = statblock {
+            let activated = feature_catalog
+                .of(statblock, &features)
+                .into_iter()
+                .filter(|(_, feature)| feature.trigger == Trigger::Activated);
+            for (key, (id, _)) in settings.feature_keys.iter().zip(activated) {
+                if keys.just_pressed(*key) {
+                    round.push_back(RoundCommand::use_feature(entity, id.to_string()));
+                    return;
+                }
+            }
+        }
+        // rolls whatever check the object under the cursor calls for
+        if keys.just_pressed(settings.check) {
+            let cell = ui.grid_cursor;
+            if let Some((check, dc)) = grid.object(cell).and_then(|o| rules::object_check(&o)) {
+                round.push_back(RoundCommand::check(entity, check, dc, Some(cell)));
+                return;
+            }
+        }
+    }
+    if *state.get() != GameState::Exploration {
+        if let Some(entity) = ui.selected_token {
+            if keys.just_pressed(KeyCode::Space) {
+                round.push_back(RoundCommand::end_turn(entity));
+                ui.selected_token = None;
+            }
+        }
+        return;
+    }
+
+    let short_rest = keys.just_pressed(settings.short_rest);
+    if short_rest {
+        if let Some(entity) = ui.selected_token {
+            round.push_back(RoundCommand::short_rest(entity, 1));
+            return;
+        }
+    }
+    let long_rest = keys.just_pressed(settings.long_rest);
+    let sneak = keys.just_pressed(settings.sneak);
+    for (entity, token) in tokens.iter() {
+        if token.player.is_none() || token.player != ui.player {
+            continue;
+        }
+        if sneak {
+            round.push_back(RoundCommand::sneak(entity, !token.sneaking));
+        }
+        if short_rest {
+            round.push_back(RoundCommand::short_rest(entity, 0));
+        } else if long_rest {
+            round.push_back(RoundCommand::long_rest(entity));
         }
     }
 }
 
+fn quick_save_system(
+    keys: Res<Input<KeyCode>>,
+    settings: Res<Settings>,
+    mut writer: EventWriter<PersistenceEvent>,
+) {
+    let path = "quicksave.json".to_string();
+    if keys.just_pressed(settings.quick_save) {
+        writer.send(PersistenceEvent::Save { path });
+    } else if keys.just_pressed(settings.quick_load) {
+        writer.send(PersistenceEvent::Load { path });
+    }
+
+    if keys.just_pressed(settings.save_characters) {
+        writer.send(PersistenceEvent::SaveCharacters);
+    }
+
+    let path = "replay.json".to_string();
+    if keys.just_pressed(settings.save_replay) {
+        writer.send(PersistenceEvent::SaveReplay { path });
+    } else if keys.just_pressed(settings.load_replay) {
+        writer.send(PersistenceEvent::LoadReplay { path });
+    }
+}
+
+/// The selected token is gone once a game or replay is loaded, its entity was despawned.
+fn clear_selection_on_load_system(mut reader: EventReader<PersistenceEvent>, mut ui: ResMut<UI>) {
+    if reader
+        .iter()
+        .any(|ev| matches!(ev, PersistenceEvent::Load { .. } | PersistenceEvent::LoadReplay { .. }))
+    {
+        ui.selected_token = None;
+    }
+}
+
+/// Characters are written back to disk as soon as they gain XP.
+fn save_characters_system(
+    mut reader: EventReader<GameEvent>,
+    mut writer: EventWriter<PersistenceEvent>,
+) {
+    if reader
+        .iter()
+        .any(|ev| matches!(ev, GameEvent::GainedXp { .. }))
+    {
+        writer.send(PersistenceEvent::SaveCharacters);
+    }
+}
+
+fn replay_control_system(
+    keys: Res<Input<KeyCode>>,
+    settings: Res<Settings>,
+    replay: Option<ResMut<Replay>>,
+) {
+    let Some(mut replay) = replay else {
+        return;
+    };
+    if keys.just_pressed(settings.replay_pause) {
+        replay.paused = !replay.paused;
+    }
+    if keys.just_pressed(settings.replay_step) {
+        replay.paused = true;
+        replay.step = true;
+    }
+    if keys.just_pressed(settings.replay_faster) {
+        replay.speed = (replay.speed * 2.0).min(8.0);
+    }
+    if keys.just_pressed(settings.replay_slower) {
+        replay.speed = (replay.speed / 2.0).max(0.25);
+    }
+}
+
 fn update_active_entity_name_system(
     round: Res<Round>,
     tokens: Query<&Token>,
     mut turn_owner_name: Query<&mut Text, With<UITurnOwnerName>>,
+    state: Res<State<GameState>>,
+    encounter: Res<Encounter>,
+    replay: Option<Res<Replay>>,
 ) {
     let mut turn_owner_name = turn_owner_name.single_mut();
-    turn_owner_name.sections[0].value = format!("Round {}", round.round_num);
-    if let Some(turn_owner) = round.active_entity {
-        if let Ok(turn_owner) = tokens.get(turn_owner) {
-            turn_owner_name.sections[0].value = turn_owner.name.clone();
+    let mut value: String = match encounter.outcome {
+        Some(Outcome::Victory) => "Victory".into(),
+        Some(Outcome::Defeat) => "Defeat".into(),
+        None => match state.get() {
+            GameState::Exploration => "Exploration".into(),
+            GameState::Resting => "Resting".into(),
+            GameState::Combat => format!("Round {}", round.round_num),
+        },
+    };
+    if encounter.outcome.is_none() {
+        if let Some(turn_owner) = round.active_entity {
+            if let Ok(turn_owner) = tokens.get(turn_owner) {
+                value = turn_owner.name.clone();
+            }
         }
     }
+    if let Some(replay) = replay {
+        value = format!(
+            "{} [replay {}/{} x{}{}{}]",
+            value,
+            replay.cursor,
+            replay.entries.len(),
+            replay.speed,
+            if replay.paused { " paused" } else { "" },
+            if replay.desynced { " desynced" } else { "" },
+        );
+    }
+    turn_owner_name.sections[0].value = value;
+}
+
+fn update_last_roll_system(
+    mut reader: EventReader<GameEvent>,
+    tokens: Query<&Token>,
+    mut last_roll: Query<&mut Text, With<UILastRoll>>,
+) {
+    let name = |e: Entity| tokens.get(e).map(|token| token.name.clone()).unwrap_or_default();
+    for ev in reader.iter() {
+        let value = match ev {
+            GameEvent::Attacked {
+                attacker,
+                target,
+                hit,
+                roll,
+                damage_roll,
+                ..
+            } => {
+                let mut value = format!(
+                    "{} attacks {}: {} {}",
+                    name(*attacker),
+                    name(*target),
+                    roll,
+                    if *hit { "hits" } else { "misses" }
+                );
+                if let Some(damage_roll) = damage_roll {
+                    value += &format!(", damage {}", damage_roll);
+                }
+                value
+            }
+            GameEvent::Checked {
+                who,
+                check,
+                dc,
+                success,
+                roll,
+                ..
+            } => format!(
+                "{} {:?} check DC {}: {} {}",
+                name(*who),
+                check,
+                dc,
+                roll,
+                if *success { "succeeds" } else { "fails" }
+            ),
+            _ => continue,
+        };
+        last_roll.single_mut().sections[0].value = value;
+    }
 }
 
 fn ensure_player_system(q: Query<Entity, With<Player>>, mut ui: ResMut<UI>) {
-    let e = q.single();
-    ui.player = Some(e);
+    // there is no player until the encounter has been spawned
+    ui.player = q.get_single().ok();
 }
 
-fn select_my_active_token_system(round: Res<Round>, mut ui: ResMut<UI>, tokens: Query<&Token>) {
+fn select_my_active_token_system(
+    round: Res<Round>,
+    mut ui: ResMut<UI>,
+    tokens: Query<(Entity, &Token)>,
+    state: Res<State<GameState>>,
+) {
     if round.is_executing() {
         return;
     }
 
+    if *state.get() == GameState::Exploration {
+        // keep the current selection if it is still one of my tokens, otherwise pick one
+        let player = ui.player;
+        let is_mine = |token: &Token| token.player.is_some() && token.player == player;
+        if let Some(selected) = ui.selected_token {
+            if let Ok((_, token)) = tokens.get(selected) {
+                if is_mine(token) && !rules::is_defeated(token) {
+                    return;
+                }
+            }
+        }
+        ui.selected_token = tokens
+            .iter()
+            .find(|(_, token)| is_mine(token) && !rules::is_defeated(token))
+            .map(|(e, _)| e);
+        return;
+    }
+
     ui.selected_token = None;
     let Some(active_token) = round.active_entity else {
         return;
     };
-    let Ok(token) = tokens.get(active_token) else {
+    let Ok((_, token)) = tokens.get(active_token) else {
         return;
     };
     if token.player == ui.player {
@@ -495,12 +830,17 @@ pub fn add_systems(app: &mut App) {
             camera_transform_system,
             pan_to_active_entity_system,
             cursor_changed_system,
-            grid_cursor_system,
+            grid_cursor_system.run_if(not(resource_exists::<Replay>())),
             token_selected_system,
             highlight_system,
             waypoint_system,
-            action_system,
+            action_system.run_if(not(resource_exists::<Replay>())),
+            quick_save_system,
+            clear_selection_on_load_system,
+            save_characters_system,
+            replay_control_system,
             update_active_entity_name_system,
+            update_last_roll_system,
             token_faces_camera_system
         )
             .chain(),
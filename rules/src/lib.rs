@@ -76,8 +76,17 @@ pub fn get_reachable_cells(f: &Facade, entity: EntityId) -> HashMap<IVec2, Reach
 }
 */
 
-use bevy::{prelude::*, utils::HashMap};
-use common::{Grid, Token};
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use common::{
+    Ability, ArmorCategory, Attack, Attitude, CharacterSheet, Check, ClassDef, Condition, Cost,
+    Dice, DiceExpr, Difficulty, DoorState, Effect, Encounter, FactionVision, Factions, FeatureDef,
+    Grid, Interactable, Item, LightLevel, LightSource, ModifierKind, Objective, Outcome, Recharge,
+    Requirement, Roll, RollKind, RollMode, Skill, Statblock, Terrain, Token, Tool, Trap, Trigger,
+    WeaponProperty,
+};
 
 
 #[derive(Clone, Copy)]
@@ -103,13 +112,11 @@ fn move_from(pos:IVec2, map:&mut HashMap<IVec2, ReachableCell>, grid:&Grid, move
     ];
     for (d, cost) in ds {
         let new_pos = pos + d;
-        if let Some(cell) = grid.get(IVec2::new(new_pos.x, new_pos.y)) {
-            if cell.blocked {
-                continue;
-            }
+        if !grid.is_walkable(new_pos) || grid.is_closed_door(new_pos) {
+            continue;
         }
 
-        let movement_cost_ft = movement_cost_ft + cost;
+        let movement_cost_ft = movement_cost_ft + cost * terrain_cost_multiplier(grid.terrain(new_pos));
         if movement_cost_ft > movement_total_ft {
             continue;
         }
@@ -134,6 +141,14 @@ fn move_from(pos:IVec2, map:&mut HashMap<IVec2, ReachableCell>, grid:&Grid, move
     }
 }
 
+/// Difficult terrain and water cost an extra foot for every foot moved into them.
+pub fn terrain_cost_multiplier(terrain: Terrain) -> f32 {
+    match terrain {
+        Terrain::Normal => 1.0,
+        Terrain::Difficult | Terrain::Water => 2.0,
+    }
+}
+
 pub fn get_reachable_cells(token:&Token, grid:&Grid) -> HashMap<IVec2, ReachableCell> {
     let mut map = HashMap::new();
     let start_pos = token.grid_pos;
@@ -153,4 +168,2426 @@ pub fn get_path(token:&Token, grid:&Grid, end:IVec2) -> Vec<ReachableCell> {
 
     vec.reverse();
     vec
-}
\ No newline at end of file
+}
+
+/// Distance within which a creature notices others it has line of sight to.
+pub const NOTICE_RANGE_FT: f32 = 60.0;
+
+pub fn distance_ft(from: IVec2, to: IVec2) -> f32 {
+    (to - from).as_vec2().length() * 5.0
+}
+
+/// Walks the cells between `from` and `to` and returns false if any of them is blocked or has a
+/// closed door. The end points themselves are not checked.
+pub fn has_line_of_sight(grid: &Grid, from: IVec2, to: IVec2) -> bool {
+    let d = (to - from).abs();
+    let s = IVec2::new((to.x - from.x).signum(), (to.y - from.y).signum());
+    let mut err = d.x - d.y;
+    let mut p = from;
+    while p != to {
+        let e2 = err * 2;
+        if e2 > -d.y {
+            err -= d.y;
+            p.x += s.x;
+        }
+        if e2 < d.x {
+            err += d.x;
+            p.y += s.y;
+        }
+        if p != to && (grid.is_blocked(p) || grid.is_closed_door(p)) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Transforms from octant coordinates to grid offsets, one column per octant.
+const OCTANTS: [[i32; 8]; 4] = [
+    [1, 0, 0, -1, -1, 0, 0, 1],
+    [0, 1, -1, 0, 0, -1, 1, 0],
+    [0, 1, 1, 0, 0, -1, -1, 0],
+    [1, 0, 0, 1, -1, 0, 0, -1],
+];
+
+fn is_opaque(grid: &Grid, cell: IVec2) -> bool {
+    grid.get(cell).is_none() || grid.is_blocked(cell) || grid.is_closed_door(cell)
+}
+
+/// Recursive shadowcasting of one octant, scanning rows outwards from `row` between the
+/// `start` and `end` slopes.
+#[allow(clippy::too_many_arguments)]
+fn cast_light(
+    grid: &Grid,
+    origin: IVec2,
+    radius: i32,
+    row: i32,
+    mut start: f32,
+    end: f32,
+    octant: usize,
+    visible: &mut HashSet<IVec2>,
+) {
+    if start < end {
+        return;
+    }
+    let [xx, xy, yx, yy] = OCTANTS.map(|m| m[octant]);
+    let mut new_start = 0.0;
+    for j in row..=radius {
+        let dy = -j;
+        let mut blocked = false;
+        for dx in -j..=0 {
+            let cell = origin + IVec2::new(dx * xx + dy * xy, dx * yx + dy * yy);
+            let left = (dx as f32 - 0.5) / (dy as f32 + 0.5);
+            let right = (dx as f32 + 0.5) / (dy as f32 - 0.5);
+            if start < right {
+                continue;
+            } else if end > left {
+                break;
+            }
+            if dx * dx + dy * dy <= radius * radius && grid.get(cell).is_some() {
+                visible.insert(cell);
+            }
+            let opaque = is_opaque(grid, cell);
+            if blocked {
+                if opaque {
+                    new_start = right;
+                } else {
+                    blocked = false;
+                    start = new_start;
+                }
+            } else if opaque && j < radius {
+                blocked = true;
+                cast_light(grid, origin, radius, j + 1, start, left, octant, visible);
+                new_start = right;
+            }
+        }
+        if blocked {
+            break;
+        }
+    }
+}
+
+/// The cells seen from `origin` out to `radius_ft`, walls and closed doors included. Anything
+/// behind them is in shadow.
+pub fn field_of_view(grid: &Grid, origin: IVec2, radius_ft: f32) -> HashSet<IVec2> {
+    let mut visible = HashSet::new();
+    if grid.get(origin).is_none() {
+        return visible;
+    }
+    visible.insert(origin);
+    let radius = (radius_ft / 5.0) as i32;
+    for octant in 0..OCTANTS[0].len() {
+        cast_light(grid, origin, radius, 1, 1.0, 0.0, octant, &mut visible);
+    }
+    visible
+}
+
+/// Whether a faction learned something as its vision changed: more of the map was explored, or
+/// one of the `hostiles` at their cell came into view. Moves that reveal something this way
+/// can't be undone.
+pub fn vision_reveals(
+    old: &FactionVision,
+    new: &FactionVision,
+    hostiles: &[(Entity, IVec2)],
+) -> bool {
+    let sees = |vision: &FactionVision, e: &Entity, cell: &IVec2| {
+        vision.visible.contains(cell) && !vision.hidden.contains(e)
+    };
+    new.explored.len() > old.explored.len()
+        || hostiles
+            .iter()
+            .any(|(e, cell)| sees(new, e, cell) && !sees(old, e, cell))
+}
+
+/// A lit torch, whether on a wall or carried.
+pub const TORCH_LIGHT: LightSource = LightSource {
+    bright_ft: 20,
+    dim_ft: 20,
+};
+
+/// Lights every cell with the ambient light and adds the light of each source, which walls and
+/// closed doors stop as they stop sight.
+pub fn light_grid(grid: &mut Grid, ambient: LightLevel, sources: &[(IVec2, LightSource)]) {
+    let mut lit = HashMap::new();
+    for (pos, source) in sources {
+        let range_ft = (source.bright_ft + source.dim_ft) as f32;
+        for cell in field_of_view(grid, *pos, range_ft) {
+            let light = if distance_ft(*pos, cell) <= source.bright_ft as f32 {
+                LightLevel::Bright
+            } else {
+                LightLevel::Dim
+            };
+            let level = lit.entry(cell).or_insert(light);
+            *level = (*level).max(light);
+        }
+    }
+    for y in 0..grid.height() as i32 {
+        for x in 0..grid.width() as i32 {
+            let i = IVec2::new(x, y);
+            let light = lit.get(&i).copied().unwrap_or(ambient).max(ambient);
+            if let Some(cell) = grid.get_mut(i) {
+                cell.light = light;
+            }
+        }
+    }
+}
+
+/// The light on `cell` as seen by a creature at `from`. Within range of its darkvision,
+/// darkness counts as dim light and dim light as bright.
+pub fn perceived_light(grid: &Grid, statblock: &Statblock, from: IVec2, cell: IVec2) -> LightLevel {
+    let light = grid.light(cell);
+    if distance_ft(from, cell) > statblock.darkvision_ft as f32 {
+        return light;
+    }
+    match light {
+        LightLevel::Dark => LightLevel::Dim,
+        LightLevel::Dim | LightLevel::Bright => LightLevel::Bright,
+    }
+}
+
+/// Whether a creature at `from` sees what is on `cell`, i.e. it is in range, in line of sight
+/// and not heavily obscured by darkness.
+pub fn can_see(grid: &Grid, statblock: &Statblock, from: IVec2, cell: IVec2) -> bool {
+    distance_ft(from, cell) <= statblock.vision_ft as f32 && in_sight(grid, statblock, from, cell)
+}
+
+/// Like `can_see` however far `cell` is. The vision range only limits what is noticed and
+/// searched, a creature still sees a target it shoots at from further away.
+pub fn in_sight(grid: &Grid, statblock: &Statblock, from: IVec2, cell: IVec2) -> bool {
+    has_line_of_sight(grid, from, cell)
+        && perceived_light(grid, statblock, from, cell) != LightLevel::Dark
+}
+
+/// A wall or closed door right next to `cell`, on the side facing `from`, gives cover.
+pub fn has_cover(grid: &Grid, from: IVec2, cell: IVec2) -> bool {
+    let d = (from - cell).signum();
+    d != IVec2::ZERO && (grid.is_blocked(cell + d) || grid.is_closed_door(cell + d))
+}
+
+/// Something on `cell` can be hidden from an observer at `from` when the observer has no line
+/// of sight to it, it is obscured by dim light or darkness, or it is behind cover.
+pub fn is_concealed(grid: &Grid, statblock: &Statblock, from: IVec2, cell: IVec2) -> bool {
+    !has_line_of_sight(grid, from, cell)
+        || perceived_light(grid, statblock, from, cell) != LightLevel::Bright
+        || has_cover(grid, from, cell)
+}
+
+/// Whether a Stealth result hides a creature on `cell` from an observer at `from`. It must be
+/// concealed and beat the observer's passive Perception, which relies on sight only when the
+/// observer has line of sight.
+pub fn hides_from(
+    grid: &Grid,
+    stealth: i32,
+    statblock: &Statblock,
+    from: IVec2,
+    cell: IVec2,
+) -> bool {
+    if !is_concealed(grid, statblock, from, cell) {
+        return false;
+    }
+    let perception = if has_line_of_sight(grid, from, cell) {
+        passive_perception_in(statblock, perceived_light(grid, statblock, from, cell))
+    } else {
+        Some(passive_perception(statblock))
+    };
+    perception.is_none_or(|perception| stealth >= perception)
+}
+
+/// Stops the token hiding from `observer`, and from everyone once nobody is left.
+pub fn reveal(token: &mut Token, observer: Entity) {
+    let Some(hidden) = token.hidden.as_mut() else {
+        return;
+    };
+    hidden.from.retain(|e| *e != observer);
+    if hidden.from.is_empty() {
+        token.hidden = None;
+    }
+}
+
+pub fn notices(grid: &Grid, observer: &Token, statblock: &Statblock, target: &Token) -> bool {
+    distance_ft(observer.grid_pos, target.grid_pos) <= NOTICE_RANGE_FT
+        && can_see(grid, statblock, observer.grid_pos, target.grid_pos)
+}
+
+pub fn is_defeated(token: &Token) -> bool {
+    token.statblock_applied && token.hit_points <= 0
+}
+
+/// The faction a token currently fights for, which a charm can override.
+pub fn faction(token: &Token) -> &str {
+    for condition in token.conditions.iter() {
+        match condition {
+            Condition::Charmed { faction } => return faction,
+            Condition::Poisoned | Condition::Helped | Condition::Blessed | Condition::Baned => {}
+        }
+    }
+    &token.faction
+}
+
+pub fn attitude(factions: &Factions, a: &Token, b: &Token) -> Attitude {
+    factions.attitude(faction(a), faction(b))
+}
+
+pub fn is_hostile(factions: &Factions, a: &Token, b: &Token) -> bool {
+    attitude(factions, a, b) == Attitude::Hostile
+}
+
+/// Reach and range are measured in squares, so diagonal neighbours are within 5 ft.
+pub fn in_reach(attacker_pos: IVec2, attack: &Attack, target_pos: IVec2) -> bool {
+    let d = (target_pos - attacker_pos).abs();
+    (d.x.max(d.y) * 5) as u32 <= attack.reach_ft.max(attack.range_ft)
+}
+
+/// In reach, and in line of sight for ranged attacks.
+pub fn can_attack(grid: &Grid, attacker_pos: IVec2, attack: &Attack, target_pos: IVec2) -> bool {
+    in_reach(attacker_pos, attack, target_pos)
+        && (attack.range_ft == 0 || has_line_of_sight(grid, attacker_pos, target_pos))
+}
+
+/// The attacks of the statblock followed by those of the equipped weapons, given with their
+/// item ids. Creatures are taken to be proficient with the weapons they carry. Two-handed
+/// weapons can't be used with a shield on.
+pub fn attacks(statblock: &Statblock, equipped: &[(&str, &Item)]) -> Vec<Attack> {
+    let mut attacks = statblock.attacks.clone();
+    let shield = equipped.iter().any(|(_, item)| item.shield.is_some());
+    let weapons = equipped
+        .iter()
+        .filter_map(|(id, item)| Some((*id, &item.name, item.weapon.as_ref()?)))
+        .filter(|(_, _, weapon)| !shield || !weapon.has(WeaponProperty::TwoHanded))
+        .collect::<Vec<_>>();
+    let free_hand = weapons.len() == 1 && !shield;
+    let strength = ability_modifier(statblock.abilities.strength);
+    let dexterity = ability_modifier(statblock.abilities.dexterity);
+    for (id, name, weapon) in weapons {
+        let modifier = if weapon.has(WeaponProperty::Ammunition) {
+            dexterity
+        } else if weapon.has(WeaponProperty::Finesse) {
+            strength.max(dexterity)
+        } else {
+            strength
+        };
+        let mut damage = match weapon.versatile_damage {
+            Some(versatile) if free_hand && weapon.has(WeaponProperty::Versatile) => versatile,
+            _ => weapon.damage,
+        };
+        damage.bonus += modifier;
+        let attack = Attack {
+            name: name.clone(),
+            to_hit: modifier + statblock.proficiency_bonus,
+            damage,
+            reach_ft: 5,
+            range_ft: 0,
+            uses_item: None,
+            properties: weapon.properties.clone(),
+        };
+        if weapon.has(WeaponProperty::Ammunition) {
+            attacks.push(Attack {
+                reach_ft: 0,
+                range_ft: weapon.range_ft,
+                uses_item: weapon.ammunition.clone(),
+                ..attack
+            });
+            continue;
+        }
+        let reach_ft = if weapon.has(WeaponProperty::Reach) { 10 } else { 5 };
+        attacks.push(Attack {
+            reach_ft,
+            ..attack.clone()
+        });
+        if weapon.has(WeaponProperty::Thrown) {
+            attacks.push(Attack {
+                name: format!("{} (thrown)", name),
+                reach_ft: 0,
+                range_ft: weapon.range_ft,
+                uses_item: Some(id.to_string()),
+                ..attack
+            });
+        }
+    }
+    attacks
+}
+
+/// AC from the equipped armor and shield, or the statblock's own, e.g. natural armor, when
+/// wearing neither. Without armor it is 10 plus the Dexterity modifier, and the modifier of
+/// a passive Unarmored Defense's ability.
+pub fn armor_class(
+    statblock: &Statblock,
+    features: &[(&str, &FeatureDef)],
+    equipped: &[(&str, &Item)],
+) -> i32 {
+    let armor = equipped.iter().find_map(|(_, item)| item.armor);
+    let shield = equipped.iter().filter_map(|(_, item)| item.shield).max();
+    let unarmored = features
+        .iter()
+        .filter(|(_, feature)| feature.trigger == Trigger::Passive)
+        .flat_map(|(_, feature)| feature.effects.iter())
+        .find_map(|effect| match effect {
+            Effect::UnarmoredDefense { ability } => Some(*ability),
+            _ => None,
+        });
+    if armor.is_none() && shield.is_none() && unarmored.is_none() {
+        return statblock.armor_class;
+    }
+    let dexterity = ability_modifier(statblock.abilities.dexterity);
+    let base = match armor {
+        None => {
+            10 + dexterity
+                + unarmored.map_or(0, |ability| ability_modifier(statblock.abilities.get(ability)))
+        }
+        Some(armor) => match armor.category {
+            ArmorCategory::Light => armor.base + dexterity,
+            ArmorCategory::Medium => armor.base + dexterity.min(2),
+            ArmorCategory::Heavy => armor.base,
+        },
+    };
+    base + shield.unwrap_or(0)
+}
+
+/// Uses up a consumable on the creature and returns the hit points it healed, which can't
+/// take it over its maximum.
+pub fn use_consumable(token: &mut Token, statblock: &Statblock, item: &Item, dice: &mut Dice) -> i32 {
+    let Some(consumable) = item.consumable else {
+        return 0;
+    };
+    let Some(healing) = consumable.healing else {
+        return 0;
+    };
+    let before = token.hit_points;
+    token.hit_points = (token.hit_points + Roll::damage(healing).roll(dice).max(0))
+        .min(statblock.hit_points as i32)
+        .max(before);
+    token.hit_points - before
+}
+
+/// Adds what the creature's conditions and features do to one of its rolls: poison and
+/// exhaustion, help, Bless and Bane, and the roll effects of its passive and active features.
+/// Features with requirements are left to the roll that knows the attack, see `roll_damage`.
+fn add_creature_modifiers(roll: &mut Roll, token: &Token, features: &[(&str, &FeatureDef)]) {
+    let d4 = DiceExpr {
+        count: 1,
+        sides: 4,
+        bonus: 0,
+    };
+    for condition in token.conditions.iter() {
+        let kind = match condition {
+            Condition::Poisoned if roll.is_one_of(&[RollKind::Attack, RollKind::Check], None) => {
+                ModifierKind::Disadvantage
+            }
+            Condition::Helped if roll.kind == RollKind::Check => ModifierKind::Advantage,
+            Condition::Blessed | Condition::Baned
+                if roll.is_one_of(&[RollKind::Attack, RollKind::SavingThrow], None) =>
+            {
+                ModifierKind::Dice {
+                    dice: d4,
+                    penalty: *condition == Condition::Baned,
+                }
+            }
+            _ => continue,
+        };
+        let source = match condition {
+            Condition::Helped => "help",
+            Condition::Blessed => "Bless",
+            Condition::Baned => "Bane",
+            _ => "poisoned",
+        };
+        roll.add(source, kind);
+    }
+    if token.exhaustion >= 1 && roll.kind == RollKind::Check {
+        roll.add("exhaustion", ModifierKind::Disadvantage);
+    }
+
+    for (id, feature) in features {
+        if !in_effect(token, id, feature) || !feature.requires.is_empty() {
+            continue;
+        }
+        for effect in feature.effects.iter() {
+            let kind = match effect {
+                Effect::Advantage { rolls, ability } if roll.is_one_of(rolls, *ability) => {
+                    ModifierKind::Advantage
+                }
+                Effect::Disadvantage { rolls, ability } if roll.is_one_of(rolls, *ability) => {
+                    ModifierKind::Disadvantage
+                }
+                Effect::RollBonus { bonus, rolls } if roll.is_one_of(rolls, None) => {
+                    ModifierKind::Bonus(*bonus)
+                }
+                Effect::RollDice {
+                    dice,
+                    penalty,
+                    rolls,
+                } if roll.is_one_of(rolls, None) => ModifierKind::Dice {
+                    dice: *dice,
+                    penalty: *penalty,
+                },
+                Effect::Reroll { at_most, rolls } if roll.is_one_of(rolls, None) => {
+                    ModifierKind::Reroll { at_most: *at_most }
+                }
+                _ => continue,
+            };
+            roll.add(feature.name.clone(), kind);
+        }
+    }
+}
+
+/// What the situation does to an attack roll. Attacking a creature the attacker can't see has
+/// disadvantage, and attacking one that can't see the attacker has advantage, e.g. out of the
+/// darkness or from hiding. A wall or closed door next to the target gives it half cover.
+pub fn attack_roll(
+    grid: &Grid,
+    who: Entity,
+    attacker: &Token,
+    attacker_statblock: &Statblock,
+    target: Entity,
+    defender: &Token,
+    target_statblock: &Statblock,
+) -> Roll {
+    let mut roll = Roll::new(RollKind::Attack, None);
+    if attacker.is_hidden_from(target) {
+        roll.add("hidden", ModifierKind::Advantage);
+    } else if !in_sight(grid, target_statblock, defender.grid_pos, attacker.grid_pos) {
+        roll.add("unseen", ModifierKind::Advantage);
+    }
+    if defender.is_hidden_from(who) {
+        roll.add("target hidden", ModifierKind::Disadvantage);
+    } else if !in_sight(grid, attacker_statblock, attacker.grid_pos, defender.grid_pos) {
+        roll.add("target unseen", ModifierKind::Disadvantage);
+    }
+    if has_cover(grid, attacker.grid_pos, defender.grid_pos) {
+        roll.add("half cover", ModifierKind::Target(2));
+    }
+    roll
+}
+
+pub struct AttackResult {
+    pub roll: Roll,
+    pub hit: bool,
+    pub critical: bool,
+}
+
+/// Rolls to hit against the armor class of the target, adding the attack's bonus and the
+/// attacker's modifiers to those of the situation from `attack_roll`. A natural 20 always hits
+/// and is a critical hit, a natural 1 always misses.
+pub fn roll_attack(
+    attacker: &Token,
+    features: &[(&str, &FeatureDef)],
+    attack: &Attack,
+    armor_class: i32,
+    mut roll: Roll,
+    dice: &mut Dice,
+) -> AttackResult {
+    roll.bonus(attack.name.clone(), attack.to_hit);
+    add_creature_modifiers(&mut roll, attacker, features);
+    roll.roll(dice);
+    let critical = roll.natural == 20;
+    let hit = critical || (roll.natural != 1 && roll.meets(armor_class));
+    AttackResult {
+        roll,
+        hit,
+        critical,
+    }
+}
+
+/// Initiative is a Dexterity check.
+pub fn roll_initiative(
+    token: &Token,
+    statblock: &Statblock,
+    features: &[(&str, &FeatureDef)],
+    dice: &mut Dice,
+) -> Roll {
+    roll_check(token, statblock, features, Check::Ability(Ability::Dexterity), None, dice)
+}
+
+pub fn ability_modifier(score: i32) -> i32 {
+    (score - 10).div_euclid(2)
+}
+
+pub fn skill_modifier(statblock: &Statblock, skill: Skill) -> i32 {
+    ability_modifier(statblock.abilities.get(skill.ability()))
+        + statblock
+            .skill_proficiency(skill)
+            .bonus(statblock.proficiency_bonus)
+}
+
+pub fn passive_perception(statblock: &Statblock) -> i32 {
+    10 + skill_modifier(statblock, Skill::Perception)
+}
+
+/// Passive Perception against something in the given light, as the creature perceives it.
+/// Dim light gives disadvantage, i.e. -5, and darkness leaves nothing to notice by sight.
+pub fn passive_perception_in(statblock: &Statblock, light: LightLevel) -> Option<i32> {
+    match light {
+        LightLevel::Bright => Some(passive_perception(statblock)),
+        LightLevel::Dim => Some(passive_perception(statblock) - 5),
+        LightLevel::Dark => None,
+    }
+}
+
+/// A Perception check relying on sight, which dim light gives disadvantage.
+pub fn roll_perception(
+    token: &Token,
+    statblock: &Statblock,
+    features: &[(&str, &FeatureDef)],
+    light: LightLevel,
+    dice: &mut Dice,
+) -> Roll {
+    let check = Check::Skill(Skill::Perception);
+    roll_check(token, statblock, features, check, Some(light), dice)
+}
+
+pub fn roll_stealth(
+    token: &Token,
+    statblock: &Statblock,
+    features: &[(&str, &FeatureDef)],
+    dice: &mut Dice,
+) -> Roll {
+    roll_check(token, statblock, features, Check::Skill(Skill::Stealth), None, dice)
+}
+
+pub fn tool_modifier(statblock: &Statblock, tool: Tool) -> i32 {
+    ability_modifier(statblock.abilities.get(tool.ability()))
+        + statblock
+            .tool_proficiency(tool)
+            .bonus(statblock.proficiency_bonus)
+}
+
+pub fn check_modifier(statblock: &Statblock, check: Check) -> i32 {
+    match check {
+        Check::Ability(ability) => ability_modifier(statblock.abilities.get(ability)),
+        Check::Skill(skill) => skill_modifier(statblock, skill),
+        Check::Tool(tool) => tool_modifier(statblock, tool),
+    }
+}
+
+/// Rolls an ability check with the creature's modifiers. Being helped gives advantage, while
+/// poison and exhaustion give disadvantage, as does dim light on a Perception check relying
+/// on sight.
+pub fn roll_check(
+    token: &Token,
+    statblock: &Statblock,
+    features: &[(&str, &FeatureDef)],
+    check: Check,
+    light: Option<LightLevel>,
+    dice: &mut Dice,
+) -> Roll {
+    let ability = check.ability();
+    let ability_bonus = ability_modifier(statblock.abilities.get(ability));
+    let mut roll = Roll::new(RollKind::Check, Some(ability));
+    roll.bonus(format!("{:?}", ability), ability_bonus);
+    roll.bonus("proficiency", check_modifier(statblock, check) - ability_bonus);
+    add_creature_modifiers(&mut roll, token, features);
+    if check == Check::Skill(Skill::Perception) && light == Some(LightLevel::Dim) {
+        roll.add("dim light", ModifierKind::Disadvantage);
+    }
+    roll.roll(dice);
+    roll
+}
+
+/// The check an object calls for and its DC: forcing a locked door open or spotting a
+/// hidden trap.
+pub fn object_check(object: &Interactable) -> Option<(Check, i32)> {
+    match object {
+        Interactable::Door(DoorState::Locked { dc }) => Some((Check::Skill(Skill::Athletics), *dc)),
+        Interactable::Trap(trap) if trap.hidden && trap.armed => {
+            Some((Check::Skill(Skill::Investigation), trap.find_dc))
+        }
+        _ => None,
+    }
+}
+
+/// The DC of a check about the object on `cell`, if any. Such checks are made next to the
+/// object, `None` from further away, and against the DC the object sets instead of `dc`.
+pub fn check_dc(grid: &Grid, from: IVec2, cell: Option<IVec2>, dc: i32) -> Option<i32> {
+    let Some(cell) = cell else {
+        return Some(dc);
+    };
+    if (from - cell).abs().max_element() > 1 {
+        return None;
+    }
+    let object_dc = grid.object(cell).and_then(|object| object_check(&object));
+    Some(object_dc.map_or(dc, |(_, dc)| dc))
+}
+
+/// What a successful check does to an object: Strength forces a locked door open, and
+/// Investigation or Perception reveals a hidden trap. Returns whether it changed anything.
+pub fn apply_check(object: &mut Interactable, check: Check) -> bool {
+    match object {
+        Interactable::Door(state @ DoorState::Locked { .. })
+            if check.ability() == Ability::Strength =>
+        {
+            *state = DoorState::Open;
+            true
+        }
+        Interactable::Trap(trap)
+            if trap.hidden
+                && matches!(check, Check::Skill(Skill::Investigation | Skill::Perception)) =>
+        {
+            trap.hidden = false;
+            true
+        }
+        _ => false,
+    }
+}
+
+pub fn roll_saving_throw(
+    token: &Token,
+    statblock: &Statblock,
+    features: &[(&str, &FeatureDef)],
+    ability: Ability,
+    dice: &mut Dice,
+) -> Roll {
+    let mut roll = Roll::new(RollKind::SavingThrow, Some(ability));
+    roll.bonus(format!("{:?}", ability), ability_modifier(statblock.abilities.get(ability)));
+    if statblock.saving_throws.contains(&ability) {
+        roll.bonus("proficiency", statblock.proficiency_bonus);
+    }
+    add_creature_modifiers(&mut roll, token, features);
+    roll.roll(dice);
+    roll
+}
+
+/// Hidden traps are found without a roll, by a passive Perception that meets their DC in the
+/// light the trap is in.
+pub fn finds_trap(statblock: &Statblock, trap: &Trap, light: LightLevel) -> bool {
+    passive_perception_in(statblock, light).is_some_and(|perception| perception >= trap.find_dc)
+}
+
+/// Sets off the trap on a creature, who saves for half damage. The trap is spent afterwards.
+/// Returns the damage taken.
+pub fn spring_trap(
+    trap: &mut Trap,
+    token: &Token,
+    statblock: &Statblock,
+    features: &[(&str, &FeatureDef)],
+    dice: &mut Dice,
+) -> i32 {
+    trap.hidden = false;
+    trap.armed = false;
+    let damage = Roll::damage(trap.damage).roll(dice).max(0);
+    if roll_saving_throw(token, statblock, features, trap.save, dice).meets(trap.save_dc) {
+        damage / 2
+    } else {
+        damage
+    }
+}
+
+/// What interacting with an object did.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Interaction {
+    /// There was nothing to do, e.g. the chest was already open or the trap isn't found yet.
+    Nothing,
+    /// The object was opened, closed, lit, pulled or disarmed.
+    Used,
+    /// Picking the lock or disarming the trap failed.
+    Failed,
+    /// Disarming failed by 5 or more and set the trap off, see `spring_trap`.
+    Sprung,
+}
+
+/// Interacts with an object, picking locks and disarming traps with a thieves' tools check. A
+/// lever only changes position here, operating its door is up to the caller with
+/// `operate_door`.
+pub fn interact(
+    object: &mut Interactable,
+    token: &Token,
+    statblock: &Statblock,
+    features: &[(&str, &FeatureDef)],
+    dice: &mut Dice,
+) -> Interaction {
+    let thieves_tools = Check::Tool(Tool::ThievesTools);
+    match object {
+        Interactable::Door(state) => match *state {
+            DoorState::Open => {
+                *state = DoorState::Closed;
+                Interaction::Used
+            }
+            DoorState::Closed => {
+                *state = DoorState::Open;
+                Interaction::Used
+            }
+            DoorState::Locked { dc } => {
+                let roll = roll_check(token, statblock, features, thieves_tools, None, dice);
+                if roll.meets(dc) {
+                    *state = DoorState::Open;
+                    Interaction::Used
+                } else {
+                    Interaction::Failed
+                }
+            }
+        },
+        Interactable::Trap(trap) => {
+            if trap.hidden || !trap.armed {
+                return Interaction::Nothing;
+            }
+            let roll = roll_check(token, statblock, features, thieves_tools, None, dice);
+            if roll.meets(trap.disarm_dc) {
+                trap.armed = false;
+                Interaction::Used
+            } else if roll.total <= trap.disarm_dc - 5 {
+                Interaction::Sprung
+            } else {
+                Interaction::Failed
+            }
+        }
+        Interactable::Lever { on, .. } => {
+            *on = !*on;
+            Interaction::Used
+        }
+        Interactable::Chest { open } => {
+            if *open {
+                return Interaction::Nothing;
+            }
+            *open = true;
+            Interaction::Used
+        }
+        Interactable::Torch { lit } => {
+            *lit = !*lit;
+            Interaction::Used
+        }
+    }
+}
+
+/// Opens a closed or locked door and closes an open one, as a lever does.
+pub fn operate_door(object: &mut Interactable) {
+    if let Interactable::Door(state) = object {
+        *state = match state {
+            DoorState::Open => DoorState::Closed,
+            _ => DoorState::Open,
+        };
+    }
+}
+
+/// A creature is surprised when it notices none of its enemies, i.e. every enemy
+/// rolled a Stealth check that meets or beats the creature's passive Perception.
+/// `enemies` holds the Stealth result of each enemy, `None` if it wasn't sneaking, and the
+/// creature's passive Perception against it, `None` if the enemy is in darkness it can't see.
+pub fn is_surprised(enemies: &[(Option<i32>, Option<i32>)]) -> bool {
+    !enemies.is_empty()
+        && enemies.iter().all(|(stealth, perception)| match (stealth, perception) {
+            (Some(stealth), Some(perception)) => stealth >= perception,
+            (Some(_), None) => true,
+            (None, _) => false,
+        })
+}
+
+pub fn proficiency_bonus(level: u32) -> i32 {
+    2 + (level.max(1) as i32 - 1) / 4
+}
+
+/// Average of a hit die rounded up, taken on level up instead of rolling.
+fn hit_die_average(hit_die: u32) -> u32 {
+    hit_die / 2 + 1
+}
+
+/// Maximum hit points of a character: the full hit die at 1st level and the recorded result,
+/// or the average, at every level after that, each plus the Constitution modifier and at
+/// least 1.
+pub fn character_hit_points(sheet: &CharacterSheet) -> u32 {
+    let con = ability_modifier(sheet.abilities.constitution);
+    let first = (sheet.hit_die as i32 + con).max(1);
+    let after: i32 = (1..sheet.level.max(1) as usize)
+        .map(|i| {
+            let roll = sheet
+                .hit_die_rolls
+                .get(i - 1)
+                .copied()
+                .unwrap_or(hit_die_average(sheet.hit_die));
+            (roll as i32 + con).max(1)
+        })
+        .sum();
+    (first + after) as u32
+}
+
+/// XP needed for each character level, starting at 1st.
+const LEVEL_XP: [u32; 20] = [
+    0, 300, 900, 2700, 6500, 14000, 23000, 34000, 48000, 64000, 85000, 100000, 120000, 140000,
+    165000, 195000, 225000, 265000, 305000, 355000,
+];
+
+/// The level a character with the XP has reached.
+pub fn level_for_xp(xp: u32) -> u32 {
+    LEVEL_XP.iter().filter(|needed| xp >= **needed).count() as u32
+}
+
+/// The XP of the defeated monsters, split evenly between the characters.
+pub fn xp_share(monster_xp: &[u32], characters: usize) -> u32 {
+    if characters == 0 {
+        return 0;
+    }
+    monster_xp.iter().sum::<u32>() / characters as u32
+}
+
+/// Advances a character one level: hit points from the hit die, rolled or the average, and
+/// whatever the class table grants at the new level. The token gains the new hit points,
+/// hit die, limited uses and spell slots right away. Returns the hit points gained.
+pub fn level_up(
+    sheet: &mut CharacterSheet,
+    class: &ClassDef,
+    token: &mut Token,
+    dice: &mut Dice,
+) -> u32 {
+    let roll = if sheet.roll_hit_points {
+        dice.roll(sheet.hit_die).max(1) as u32
+    } else {
+        hit_die_average(sheet.hit_die)
+    };
+    let gained = (roll as i32 + ability_modifier(sheet.abilities.constitution)).max(1);
+    sheet.hit_die_rolls.push(roll);
+    sheet.level += 1;
+    if !is_defeated(token) {
+        token.hit_points += gained;
+    }
+    token.hit_dice += 1;
+
+    let Some(granted) = class.level(sheet.level) else {
+        return gained as u32;
+    };
+    sheet.features.extend(granted.features.iter().cloned());
+    // a limited use granted again replaces the old one, e.g. with more uses
+    for limited_use in granted.limited_uses.iter() {
+        sheet.limited_uses.retain(|l| l.name != limited_use.name);
+        sheet.limited_uses.push(limited_use.clone());
+        token
+            .limited_uses
+            .insert(limited_use.name.clone(), limited_use.uses);
+    }
+    if !granted.spell_slots.is_empty() {
+        for (i, slots) in granted.spell_slots.iter().enumerate() {
+            let before = sheet.spell_slots.get(i).copied().unwrap_or(0);
+            if token.spell_slots.len() <= i {
+                token.spell_slots.resize(i + 1, 0);
+            }
+            token.spell_slots[i] += slots.saturating_sub(before);
+        }
+        sheet.spell_slots = granted.spell_slots.clone();
+    }
+    gained as u32
+}
+
+/// The statblock a character sheet plays as. Characters are worth no XP.
+pub fn character_statblock(sheet: &CharacterSheet) -> Statblock {
+    Statblock {
+        name: sheet.name.clone(),
+        speed: sheet.speed,
+        hit_points: character_hit_points(sheet),
+        abilities: sheet.abilities,
+        hit_die: sheet.hit_die,
+        hit_dice: sheet.level.max(1),
+        spell_slots: sheet.spell_slots.clone(),
+        limited_uses: sheet.limited_uses.clone(),
+        proficiency_bonus: proficiency_bonus(sheet.level),
+        saving_throws: sheet.saving_throws.clone(),
+        skills: sheet.skills.clone(),
+        tools: sheet.tools.clone(),
+        // unarmored, armor is worn from the inventory
+        armor_class: 10 + ability_modifier(sheet.abilities.dexterity),
+        actions: 1,
+        bonus_actions: 1,
+        attacks: Vec::new(),
+        vision_ft: 60,
+        darkvision_ft: sheet.darkvision_ft,
+        light: sheet.light,
+        inventory: sheet.inventory.clone(),
+        features: sheet.features.clone(),
+        challenge_rating: None,
+    }
+}
+
+/// The level features scale with, the number of hit dice of the creature.
+fn feature_level(statblock: &Statblock) -> u32 {
+    statblock.hit_dice.max(1)
+}
+
+/// The dice of an effect at the level, with the count added again every `every_levels`.
+fn scaled_dice(dice: DiceExpr, every_levels: u32, level: u32) -> DiceExpr {
+    if every_levels == 0 {
+        return dice;
+    }
+    DiceExpr {
+        count: dice.count * level.div_ceil(every_levels),
+        ..dice
+    }
+}
+
+/// Whether the effects of the feature apply right now, always for passive features.
+fn in_effect(token: &Token, id: &str, feature: &FeatureDef) -> bool {
+    feature.trigger == Trigger::Passive || token.active_features.contains_key(id)
+}
+
+/// Effects of the passive features and of those currently active on the token.
+fn active_effects<'a>(
+    token: &'a Token,
+    features: &'a [(&str, &FeatureDef)],
+) -> impl Iterator<Item = &'a Effect> {
+    features
+        .iter()
+        .filter(|(id, feature)| in_effect(token, id, feature))
+        .flat_map(|(_, feature)| feature.effects.iter())
+}
+
+/// Whether the feature isn't held back by its limits: uses left, once per turn, and in
+/// combat the action or bonus action it costs.
+fn feature_available(token: &Token, id: &str, feature: &FeatureDef, in_combat: bool) -> bool {
+    let uses_left = match feature.uses.as_ref() {
+        Some(uses) => token.limited_uses.get(uses).copied().unwrap_or(0) > 0,
+        None => true,
+    };
+    let cost_paid = !in_combat
+        || match feature.cost {
+            Cost::Free => true,
+            Cost::Action => token.actions > 0,
+            Cost::BonusAction => token.bonus_actions > 0,
+        };
+    uses_left
+        && cost_paid
+        && !(feature.once_per_turn && token.features_used.iter().any(|used| used == id))
+        && !token.active_features.contains_key(id)
+}
+
+/// Takes the use, turn resources and once-per-turn slot the feature costs.
+fn spend_feature(token: &mut Token, id: &str, feature: &FeatureDef, in_combat: bool) {
+    if let Some(uses) = feature.uses.as_ref() {
+        if let Some(left) = token.limited_uses.get_mut(uses) {
+            *left = left.saturating_sub(1);
+        }
+    }
+    if in_combat {
+        match feature.cost {
+            Cost::Free => {}
+            Cost::Action => token.actions -= 1,
+            Cost::BonusAction => token.bonus_actions -= 1,
+        }
+    }
+    if feature.once_per_turn {
+        token.features_used.push(id.to_string());
+    }
+}
+
+/// Applies the effects that happen at once, healing and extra actions. Returns the hit
+/// points healed.
+fn apply_effects(
+    token: &mut Token,
+    statblock: &Statblock,
+    effects: &[Effect],
+    dice: &mut Dice,
+) -> i32 {
+    let mut healed = 0;
+    for effect in effects {
+        match *effect {
+            Effect::Heal { dice: healing, add_level } => {
+                let level = if add_level { feature_level(statblock) as i32 } else { 0 };
+                let before = token.hit_points;
+                let rolled = Roll::damage(healing).roll(dice).max(0);
+                token.hit_points = (token.hit_points + rolled + level)
+                    .min(statblock.hit_points as i32)
+                    .max(before);
+                healed += token.hit_points - before;
+            }
+            Effect::GainActions { actions } => token.actions += actions,
+            _ => {}
+        }
+    }
+    healed
+}
+
+pub fn can_use_feature(token: &Token, id: &str, feature: &FeatureDef, in_combat: bool) -> bool {
+    feature.trigger == Trigger::Activated
+        && !is_defeated(token)
+        && feature_available(token, id, feature, in_combat)
+}
+
+/// Uses an activated feature, which either becomes active for its duration or takes effect
+/// at once. Returns the hit points healed, or None if it can't be used.
+pub fn use_feature(
+    token: &mut Token,
+    statblock: &Statblock,
+    id: &str,
+    feature: &FeatureDef,
+    in_combat: bool,
+    dice: &mut Dice,
+) -> Option<i32> {
+    if !can_use_feature(token, id, feature, in_combat) {
+        return None;
+    }
+    spend_feature(token, id, feature, in_combat);
+    if let Some(rounds) = feature.duration_rounds {
+        token.active_features.insert(id.to_string(), rounds);
+        return Some(0);
+    }
+    Some(apply_effects(token, statblock, &feature.effects, dice))
+}
+
+/// Whether a creature gets the condition a feature gives when used: the user and its allies,
+/// or hostile creatures with `hostile`, within range and still standing.
+pub fn gains_condition(
+    factions: &Factions,
+    user: &Token,
+    other: &Token,
+    range_ft: u32,
+    hostile: bool,
+) -> bool {
+    !is_defeated(other)
+        && distance_ft(user.grid_pos, other.grid_pos) <= range_ft as f32
+        && is_hostile(factions, user, other) == hostile
+}
+
+fn meets(requirement: Requirement, attack: &Attack, mode: RollMode, ally_adjacent: bool) -> bool {
+    match requirement {
+        Requirement::FinesseOrRanged => {
+            attack.range_ft > 0 || attack.properties.contains(&WeaponProperty::Finesse)
+        }
+        Requirement::AdvantageOrAllyAdjacent => {
+            mode == RollMode::Advantage || (ally_adjacent && mode != RollMode::Disadvantage)
+        }
+        Requirement::Melee => attack.range_ft == 0,
+    }
+}
+
+/// Rolls the damage of a hit: the weapon's dice, with the attacker's modifiers, extra dice
+/// from active features and on-hit features whose requirements the attack meets, and damage
+/// bonuses. `ally_adjacent` is whether an ally of the attacker stands next to the target. All
+/// dice are doubled on a critical hit. Rerolls, e.g. Great Weapon Fighting, only apply to the
+/// weapon's dice, not to extra dice such as Sneak Attack's.
+pub fn roll_damage(
+    token: &mut Token,
+    statblock: &Statblock,
+    features: &[(&str, &FeatureDef)],
+    attack: &Attack,
+    result: &AttackResult,
+    ally_adjacent: bool,
+    dice: &mut Dice,
+) -> Roll {
+    let doubled = |mut expr: DiceExpr| {
+        if result.critical {
+            expr.count *= 2;
+        }
+        expr
+    };
+    let mut roll = Roll::damage(doubled(attack.damage));
+    add_creature_modifiers(&mut roll, token, features);
+
+    let level = feature_level(statblock);
+    let mode = result.roll.mode();
+    for (id, feature) in features {
+        let requirements_met = feature
+            .requires
+            .iter()
+            .all(|requirement| meets(*requirement, attack, mode, ally_adjacent));
+        let active = in_effect(token, id, feature) && requirements_met;
+        let triggered = feature.trigger == Trigger::OnHit
+            && requirements_met
+            && feature_available(token, id, feature, false);
+        if triggered {
+            spend_feature(token, id, feature, false);
+        }
+        if !active && !triggered {
+            continue;
+        }
+        for effect in feature.effects.iter() {
+            match *effect {
+                Effect::ExtraDamage {
+                    dice: extra,
+                    every_levels,
+                } => {
+                    let extra = doubled(scaled_dice(extra, every_levels, level));
+                    let kind = ModifierKind::Dice {
+                        dice: extra,
+                        penalty: false,
+                    };
+                    roll.add(feature.name.clone(), kind);
+                }
+                Effect::DamageBonus { bonus, melee } if !melee || attack.range_ft == 0 => {
+                    roll.bonus(feature.name.clone(), bonus);
+                }
+                // those without requirements are among the creature's modifiers already
+                Effect::Reroll { at_most, ref rolls }
+                    if !feature.requires.is_empty() && roll.is_one_of(rolls, None) =>
+                {
+                    roll.add(feature.name.clone(), ModifierKind::Reroll { at_most });
+                }
+                _ => {}
+            }
+        }
+    }
+    roll.roll(dice);
+    roll
+}
+
+/// Damage after the target's active features, e.g. halved while raging.
+pub fn damage_taken(token: &Token, features: &[(&str, &FeatureDef)], damage: i32) -> i32 {
+    if active_effects(token, features).any(|effect| *effect == Effect::Resistance) {
+        damage / 2
+    } else {
+        damage
+    }
+}
+
+/// Runs down active features at the start of the creature's turn, then applies its
+/// on-turn-start features. Returns the hit points healed.
+pub fn start_turn_features(
+    token: &mut Token,
+    statblock: &Statblock,
+    features: &[(&str, &FeatureDef)],
+    dice: &mut Dice,
+) -> i32 {
+    for rounds in token.active_features.values_mut() {
+        *rounds = rounds.saturating_sub(1);
+    }
+    token.active_features.retain(|_, rounds| *rounds > 0);
+
+    let mut healed = 0;
+    for (id, feature) in features {
+        if feature.trigger != Trigger::OnTurnStart || !feature_available(token, id, feature, false)
+        {
+            continue;
+        }
+        spend_feature(token, id, feature, false);
+        healed += apply_effects(token, statblock, &feature.effects, dice);
+    }
+    healed
+}
+
+/// Initializes the runtime state of a freshly spawned token from its statblock.
+pub fn apply_statblock(token: &mut Token, statblock: &Statblock) {
+    token.hit_points = statblock.hit_points as i32;
+    token.hit_dice = statblock.hit_dice;
+    token.spell_slots = statblock.spell_slots.clone();
+    token.limited_uses = statblock
+        .limited_uses
+        .iter()
+        .map(|limited_use| (limited_use.name.clone(), limited_use.uses))
+        .collect();
+    token.exhaustion = 0;
+    token.reaction = true;
+    token.object_interaction = true;
+    token.statblock_applied = true;
+}
+
+/// Refills movement, actions, reaction and the free object interaction at the start of the
+/// token's turn.
+pub fn start_turn(token: &mut Token, statblock: &Statblock) {
+    token.movement_ft = statblock.speed as f32;
+    token.actions = statblock.actions;
+    token.bonus_actions = statblock.bonus_actions;
+    token.reaction = true;
+    token.object_interaction = true;
+}
+
+fn recharge_limited_uses(token: &mut Token, statblock: &Statblock, short_rest: bool) {
+    for limited_use in statblock.limited_uses.iter() {
+        if short_rest && limited_use.recharge != Recharge::ShortRest {
+            continue;
+        }
+        token
+            .limited_uses
+            .insert(limited_use.name.clone(), limited_use.uses);
+    }
+}
+
+/// Spends up to `hit_dice` of the token's hit dice, as many as the player chose, stopping
+/// early at full health. Each hit die heals the roll plus the Constitution modifier,
+/// minimum 0. Returns the number of hit points regained.
+pub fn short_rest(token: &mut Token, statblock: &Statblock, hit_dice: u32, dice: &mut Dice) -> i32 {
+    let max_hit_points = statblock.hit_points as i32;
+    let con = ability_modifier(statblock.abilities.constitution);
+    let mut healed = 0;
+    for _ in 0..hit_dice {
+        if token.hit_dice == 0 || token.hit_points >= max_hit_points {
+            break;
+        }
+        token.hit_dice -= 1;
+        let heal = (dice.roll(statblock.hit_die) + con).max(0);
+        let hit_points = (token.hit_points + heal).min(max_hit_points);
+        healed += hit_points - token.hit_points;
+        token.hit_points = hit_points;
+    }
+
+    recharge_limited_uses(token, statblock, true);
+    healed
+}
+
+/// Restores hit points, spell slots and limited uses, regains half of the total
+/// hit dice (minimum one) and removes one level of exhaustion.
+pub fn long_rest(token: &mut Token, statblock: &Statblock) {
+    token.hit_points = statblock.hit_points as i32;
+    let regained = (statblock.hit_dice / 2).max(1);
+    token.hit_dice = (token.hit_dice + regained).min(statblock.hit_dice);
+    token.spell_slots = statblock.spell_slots.clone();
+    token.exhaustion = token.exhaustion.saturating_sub(1);
+    recharge_limited_uses(token, statblock, false);
+}
+
+/// XP of a monster for each challenge rating from 1 to 30 (DMG p. 275).
+const CHALLENGE_XP: [u32; 30] = [
+    200, 450, 700, 1100, 1800, 2300, 2900, 3900, 5000, 5900, 7200, 8400, 10000, 11500, 13000,
+    15000, 18000, 20000, 22000, 25000, 33000, 41000, 50000, 62000, 75000, 90000, 105000, 120000,
+    135000, 155000,
+];
+
+/// The XP a creature is worth for its challenge rating, none without one.
+pub fn monster_xp(statblock: &Statblock) -> u32 {
+    let Some(challenge_rating) = statblock.challenge_rating else {
+        return 0;
+    };
+    match challenge_rating {
+        cr if cr < 0.125 => 10,
+        cr if cr < 0.25 => 25,
+        cr if cr < 0.5 => 50,
+        cr if cr < 1.0 => 100,
+        cr => CHALLENGE_XP[(cr as usize).min(30) - 1],
+    }
+}
+
+/// XP thresholds per character level for easy, medium, hard and deadly encounters (DMG p. 82).
+const XP_THRESHOLDS: [[u32; 4]; 20] = [
+    [25, 50, 75, 100],
+    [50, 100, 150, 200],
+    [75, 150, 225, 400],
+    [125, 250, 375, 500],
+    [250, 500, 750, 1100],
+    [300, 600, 900, 1400],
+    [350, 750, 1100, 1700],
+    [450, 900, 1400, 2100],
+    [550, 1100, 1600, 2400],
+    [600, 1200, 1900, 2800],
+    [800, 1600, 2400, 3600],
+    [1000, 2000, 3000, 4500],
+    [1100, 2200, 3400, 5100],
+    [1250, 2500, 3800, 5700],
+    [1400, 2800, 4300, 6400],
+    [1600, 3200, 4800, 7200],
+    [2000, 3900, 5900, 8800],
+    [2100, 4200, 6300, 9500],
+    [2400, 4900, 7300, 10900],
+    [2800, 5700, 8500, 12700],
+];
+
+/// Encounter multipliers, with a step below x1 and two above x4 for small and large parties.
+const ENCOUNTER_MULTIPLIERS: [f32; 8] = [0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 4.0, 5.0];
+
+pub fn xp_threshold(level: u32, difficulty: Difficulty) -> u32 {
+    let level = level.clamp(1, 20) as usize;
+    XP_THRESHOLDS[level - 1][difficulty as usize]
+}
+
+/// The XP a party of characters with the given levels can face at the difficulty.
+pub fn xp_budget(levels: &[u32], difficulty: Difficulty) -> u32 {
+    levels
+        .iter()
+        .map(|level| xp_threshold(*level, difficulty))
+        .sum()
+}
+
+/// Multiplies the XP of monsters fighting together, as more of them are more dangerous.
+/// Parties of fewer than three use the next multiplier up, parties of six or more the next one
+/// down.
+pub fn encounter_multiplier(monsters: usize, party_size: usize) -> f32 {
+    let i: usize = match monsters {
+        0 | 1 => 1,
+        2 => 2,
+        3..=6 => 3,
+        7..=10 => 4,
+        11..=14 => 5,
+        _ => 6,
+    };
+    let i = match party_size {
+        0..=2 => i + 1,
+        3..=5 => i,
+        _ => i - 1,
+    };
+    ENCOUNTER_MULTIPLIERS[i]
+}
+
+/// The XP of a group of monsters, adjusted for their number, to compare against the budget.
+pub fn adjusted_xp(monster_xp: &[u32], party_size: usize) -> u32 {
+    let total: u32 = monster_xp.iter().sum();
+    (total as f32 * encounter_multiplier(monster_xp.len(), party_size)) as u32
+}
+
+/// Checks the encounter objectives against the tokens, returning the outcome once decided.
+pub fn evaluate_encounter(
+    encounter: &Encounter,
+    factions: &Factions,
+    tokens: &[(Entity, &Token)],
+    round_num: u64,
+) -> Option<Outcome> {
+    let members = || {
+        tokens
+            .iter()
+            .filter(|(_, token)| faction(token) == encounter.faction)
+    };
+    if members().next().is_some() && members().all(|(_, token)| is_defeated(token)) {
+        return Some(Outcome::Defeat);
+    }
+
+    let mut victory = false;
+    for objective in encounter.objectives.iter() {
+        match objective {
+            Objective::DefeatAllHostiles => {
+                victory |= !tokens.iter().any(|(_, token)| {
+                    !is_defeated(token)
+                        && factions.attitude(&encounter.faction, faction(token)) == Attitude::Hostile
+                });
+            }
+            Objective::SurviveRounds { rounds } => {
+                victory |= round_num > *rounds;
+            }
+            Objective::ReachCell { cell } => {
+                victory |= members().any(|(_, token)| !is_defeated(token) && token.grid_pos == *cell);
+            }
+            Objective::Protect { who } => {
+                let protected = tokens.iter().find(|(e, _)| e == who);
+                match protected {
+                    Some((_, token)) if !is_defeated(token) => {}
+                    _ => return Some(Outcome::Defeat),
+                }
+            }
+        }
+    }
+
+    if victory {
+        return Some(Outcome::Victory);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use common::AuthoredMap;
+
+    use super::*;
+
+    fn grid(ascii: &str) -> Grid {
+        AuthoredMap::from_ascii(ascii).unwrap().grid
+    }
+
+    fn statblock(toml: &str) -> Statblock {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn feature(toml: &str) -> FeatureDef {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn token() -> Token {
+        Token {
+            hit_points: 10,
+            actions: 1,
+            bonus_actions: 1,
+            statblock_applied: true,
+            ..default()
+        }
+    }
+
+    fn attack(damage: &str, range_ft: u32, properties: Vec<WeaponProperty>) -> Attack {
+        Attack {
+            name: "Dagger".into(),
+            to_hit: 5,
+            damage: damage.parse().unwrap(),
+            reach_ft: 5,
+            range_ft,
+            uses_item: None,
+            properties,
+        }
+    }
+
+    /// The damage of a hit with the attack, with an ally next to the target if `ally_adjacent`.
+    fn damage(
+        token: &mut Token,
+        statblock: &Statblock,
+        features: &[(&str, &FeatureDef)],
+        attack: &Attack,
+        critical: bool,
+        ally_adjacent: bool,
+    ) -> Roll {
+        let result = AttackResult {
+            roll: Roll::new(RollKind::Attack, None),
+            hit: true,
+            critical,
+        };
+        roll_damage(
+            token,
+            statblock,
+            features,
+            attack,
+            &result,
+            ally_adjacent,
+            &mut Dice::new(1),
+        )
+    }
+
+    fn has_modifier(roll: &Roll, source: &str, kind: ModifierKind) -> bool {
+        roll.modifiers
+            .iter()
+            .any(|modifier| modifier.source == source && modifier.kind == kind)
+    }
+
+    fn rester() -> Statblock {
+        statblock(
+            r#"
+            hit_points = 30
+            hit_die = 8
+            hit_dice = 4
+            limited_uses = [
+                { name = "Second Wind", uses = 1, recharge = "short_rest" },
+                { name = "Rage", uses = 2, recharge = "long_rest" },
+            ]
+
+            [abilities]
+            constitution = 14
+            "#,
+        )
+    }
+
+    #[test]
+    fn short_rest_spends_the_chosen_hit_dice_until_healed() {
+        let statblock = rester();
+        let mut token = token();
+        token.hit_points = 5;
+        token.hit_dice = 4;
+
+        let mut expected = Dice::new(3);
+        let heal = expected.roll(8) + 2 + expected.roll(8) + 2;
+        assert_eq!(
+            short_rest(&mut token, &statblock, 2, &mut Dice::new(3)),
+            heal
+        );
+        assert_eq!(token.hit_points, 5 + heal);
+        assert_eq!(token.hit_dice, 2);
+
+        // healing stops at the maximum, and so does spending
+        token.hit_points = 28;
+        assert_eq!(short_rest(&mut token, &statblock, 2, &mut Dice::new(3)), 2);
+        assert_eq!(token.hit_points, 30);
+        assert_eq!(token.hit_dice, 1);
+
+        token.hit_points = 10;
+        token.hit_dice = 0;
+        assert_eq!(short_rest(&mut token, &statblock, 2, &mut Dice::new(3)), 0);
+        assert_eq!(token.hit_points, 10);
+    }
+
+    #[test]
+    fn long_rest_heals_and_recovers_half_the_hit_dice() {
+        let rester = rester();
+        let mut token = token();
+        token.hit_points = 1;
+        token.exhaustion = 2;
+
+        long_rest(&mut token, &rester);
+        assert_eq!(token.hit_points, 30);
+        assert_eq!(token.hit_dice, 2);
+        assert_eq!(token.exhaustion, 1);
+        long_rest(&mut token, &rester);
+        long_rest(&mut token, &rester);
+        assert_eq!(token.hit_dice, 4);
+        assert_eq!(token.exhaustion, 0);
+
+        // at least one
+        let mut token = Token::default();
+        long_rest(&mut token, &statblock("hit_dice = 1"));
+        assert_eq!(token.hit_dice, 1);
+    }
+
+    #[test]
+    fn rests_refresh_the_limited_uses_they_recharge() {
+        let statblock = rester();
+        let mut token = token();
+        token.limited_uses.insert("Second Wind".into(), 0);
+        token.limited_uses.insert("Rage".into(), 0);
+
+        short_rest(&mut token, &statblock, 0, &mut Dice::new(3));
+        assert_eq!(token.limited_uses["Second Wind"], 1);
+        assert_eq!(token.limited_uses["Rage"], 0);
+        long_rest(&mut token, &statblock);
+        assert_eq!(token.limited_uses["Rage"], 2);
+    }
+
+    #[test]
+    fn creatures_are_surprised_when_every_enemy_beats_their_passive_perception() {
+        let guard = statblock("[abilities]\nwisdom = 14\n[skills]\nperception = \"proficient\"");
+        let perception = passive_perception(&guard);
+        assert_eq!(perception, 14);
+        let bright = passive_perception_in(&guard, LightLevel::Bright);
+        let dim = passive_perception_in(&guard, LightLevel::Dim);
+        let dark = passive_perception_in(&guard, LightLevel::Dark);
+
+        // surprised: ties go to the sneaking creature
+        assert!(is_surprised(&[(Some(14), bright)]));
+        assert!(is_surprised(&[(Some(15), bright), (Some(9), dim)]));
+        assert!(is_surprised(&[(Some(3), dark)]));
+
+        // noticed: one enemy is enough, and one who didn't sneak is always noticed
+        assert!(!is_surprised(&[(Some(13), bright)]));
+        assert!(!is_surprised(&[(Some(20), bright), (Some(8), dim)]));
+        assert!(!is_surprised(&[(Some(20), bright), (None, dark)]));
+        assert!(!is_surprised(&[]));
+    }
+
+    fn combatant(faction: &str, hit_points: i32, cell: IVec2) -> Token {
+        Token {
+            faction: faction.into(),
+            hit_points,
+            grid_pos: cell,
+            ..token()
+        }
+    }
+
+    /// The party fights goblins, and wolves stay out of it.
+    fn objectives(objectives: Vec<Objective>) -> (Encounter, Factions) {
+        let encounter = Encounter {
+            faction: "party".into(),
+            objectives,
+            ..default()
+        };
+        let mut factions = Factions::default();
+        factions.set("party", "goblins", Attitude::Hostile);
+        (encounter, factions)
+    }
+
+    #[test]
+    fn defeat_all_hostiles_is_won_once_no_hostile_stands() {
+        let (encounter, factions) = objectives(vec![Objective::DefeatAllHostiles]);
+        let fighter = combatant("party", 10, IVec2::ZERO);
+        let wolf = combatant("wolves", 10, IVec2::ZERO);
+        let evaluate = |goblin: &Token| {
+            let tokens = [
+                (Entity::from_raw(1), &fighter),
+                (Entity::from_raw(2), goblin),
+                (Entity::from_raw(3), &wolf),
+            ];
+            evaluate_encounter(&encounter, &factions, &tokens, 1)
+        };
+
+        assert_eq!(evaluate(&combatant("goblins", 7, IVec2::ZERO)), None);
+        // the wolf isn't hostile, it needn't be defeated
+        assert_eq!(
+            evaluate(&combatant("goblins", 0, IVec2::ZERO)),
+            Some(Outcome::Victory)
+        );
+    }
+
+    #[test]
+    fn survive_rounds_is_won_after_the_rounds() {
+        let (encounter, factions) = objectives(vec![Objective::SurviveRounds { rounds: 3 }]);
+        let fighter = combatant("party", 10, IVec2::ZERO);
+        let tokens = [(Entity::from_raw(1), &fighter)];
+
+        assert_eq!(evaluate_encounter(&encounter, &factions, &tokens, 3), None);
+        assert_eq!(
+            evaluate_encounter(&encounter, &factions, &tokens, 4),
+            Some(Outcome::Victory)
+        );
+    }
+
+    #[test]
+    fn reach_cell_is_won_by_a_member_standing_on_it() {
+        let cell = IVec2::new(5, 5);
+        let (encounter, factions) = objectives(vec![Objective::ReachCell { cell }]);
+        let fighter = combatant("party", 10, IVec2::ZERO);
+        let evaluate = |other: &Token| {
+            let tokens = [
+                (Entity::from_raw(1), &fighter),
+                (Entity::from_raw(2), other),
+            ];
+            evaluate_encounter(&encounter, &factions, &tokens, 1)
+        };
+
+        assert_eq!(
+            evaluate(&combatant("party", 10, cell)),
+            Some(Outcome::Victory)
+        );
+        assert_eq!(evaluate(&combatant("party", 10, IVec2::new(4, 5))), None);
+        // the goblins' own, or a fallen member's, don't count
+        assert_eq!(evaluate(&combatant("goblins", 10, cell)), None);
+        assert_eq!(evaluate(&combatant("party", 0, cell)), None);
+    }
+
+    #[test]
+    fn protect_is_lost_with_the_protected_token_even_if_otherwise_won() {
+        let (prince, goblin) = (Entity::from_raw(1), Entity::from_raw(2));
+        let (encounter, factions) = objectives(vec![
+            Objective::DefeatAllHostiles,
+            Objective::Protect { who: prince },
+        ]);
+        let fighter = combatant("party", 10, IVec2::ZERO);
+        let defeated_goblin = combatant("goblins", 0, IVec2::ZERO);
+        let evaluate = |prince_token: Option<&Token>| {
+            let mut tokens = vec![(Entity::from_raw(3), &fighter), (goblin, &defeated_goblin)];
+            tokens.extend(prince_token.map(|token| (prince, token)));
+            evaluate_encounter(&encounter, &factions, &tokens, 1)
+        };
+
+        let prince_token = combatant("villagers", 4, IVec2::ZERO);
+        assert_eq!(evaluate(Some(&prince_token)), Some(Outcome::Victory));
+        let prince_token = combatant("villagers", 0, IVec2::ZERO);
+        assert_eq!(evaluate(Some(&prince_token)), Some(Outcome::Defeat));
+        assert_eq!(evaluate(None), Some(Outcome::Defeat));
+    }
+
+    #[test]
+    fn the_encounter_is_lost_once_the_whole_party_is_defeated() {
+        let (encounter, factions) = objectives(vec![Objective::SurviveRounds { rounds: 1 }]);
+        let fallen = combatant("party", 0, IVec2::ZERO);
+        let standing = combatant("party", 3, IVec2::ZERO);
+
+        let tokens = [
+            (Entity::from_raw(1), &fallen),
+            (Entity::from_raw(2), &fallen),
+        ];
+        assert_eq!(
+            evaluate_encounter(&encounter, &factions, &tokens, 5),
+            Some(Outcome::Defeat)
+        );
+        let tokens = [
+            (Entity::from_raw(1), &fallen),
+            (Entity::from_raw(2), &standing),
+        ];
+        assert_eq!(
+            evaluate_encounter(&encounter, &factions, &tokens, 5),
+            Some(Outcome::Victory)
+        );
+        // without any members there is no one to lose
+        assert_eq!(evaluate_encounter(&encounter, &factions, &[], 1), None);
+    }
+
+    #[test]
+    fn tmx_reads_the_layers_and_objects_of_the_map() {
+        let map = AuthoredMap::from_tmx(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <map width="3" height="2" tilewidth="32" tileheight="32">
+             <tileset firstgid="1" name="dungeon" tilewidth="32" tileheight="32" tilecount="2">
+              <tile id="0">
+               <objectgroup>
+                <object id="9" class="trap" x="0" y="0"/>
+               </objectgroup>
+              </tile>
+             </tileset>
+             <layer id="1" name="Floor" width="3" height="2">
+              <data encoding="csv">
+            1,1,1,
+            1,1,1
+            </data>
+             </layer>
+             <group id="2" name="Structure">
+              <layer id="3" name="Walls" width="3" height="2">
+               <data>
+                <tile gid="0"/><tile gid="0"/><tile gid="2"/>
+                <tile gid="0"/><tile gid="0"/><tile gid="2"/>
+               </data>
+              </layer>
+             </group>
+             <objectgroup id="4" name="Objects">
+              <object id="1" name="start" type="spawn" x="16" y="48"/>
+              <object id="2" name="gate" class="door" x="32" y="0">
+               <properties>
+                <property name="state" value="locked"/>
+                <property name="dc" type="int" value="12"/>
+               </properties>
+              </object>
+             </objectgroup>
+            </map>"#,
+        )
+        .unwrap();
+
+        assert!(map.grid.get(IVec2::new(0, 0)).unwrap().walkable);
+        assert!(map.grid.is_blocked(IVec2::new(2, 0)));
+        assert!(map.grid.is_blocked(IVec2::new(2, 1)));
+        // the object of the tileset's tile is left out
+        assert_eq!(map.objects.len(), 2);
+        assert_eq!(map.objects[0].kind, "spawn");
+        assert_eq!(map.objects[0].cell, IVec2::new(0, 1));
+        assert_eq!(map.objects[1].cell, IVec2::new(1, 0));
+        assert_eq!(map.objects[1].properties["state"], "locked");
+        assert_eq!(map.objects[1].properties["dc"], "12");
+    }
+
+    #[test]
+    fn tmx_rejects_layers_of_the_wrong_size() {
+        let error = AuthoredMap::from_tmx(
+            r#"<map width="2" height="2" tilewidth="32" tileheight="32">
+             <layer name="Floor"><data encoding="csv">1,1,1</data></layer>
+            </map>"#,
+        )
+        .err();
+        assert_eq!(
+            error.as_deref(),
+            Some("layer Floor has 3 tiles, expected 4")
+        );
+    }
+
+    #[test]
+    fn tmj_reads_nested_layers_and_objects() {
+        let map = AuthoredMap::from_tmj(
+            r#"{
+                "width": 3, "height": 2, "tilewidth": 32, "tileheight": 32,
+                "layers": [
+                    { "type": "tilelayer", "name": "Floor", "data": [1, 1, 1, 1, 1, 1] },
+                    { "type": "group", "name": "Structure", "layers": [
+                        { "type": "tilelayer", "name": "Water", "data": [0, 0, 0, 2, 0, 0] }
+                    ] },
+                    { "type": "objectgroup", "name": "Objects", "objects": [
+                        { "name": "gate", "class": "door", "x": 32, "y": 0 },
+                        { "name": "", "type": "lever", "x": 64, "y": 64, "gid": 3,
+                          "properties": [{ "name": "door", "type": "string", "value": "gate" }] }
+                    ] }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            map.grid.get(IVec2::new(0, 1)).unwrap().terrain,
+            Terrain::Water
+        );
+        assert_eq!(
+            map.grid.get(IVec2::new(1, 1)).unwrap().terrain,
+            Terrain::Normal
+        );
+        assert_eq!(map.objects[1].kind, "lever");
+        assert_eq!(map.objects[1].properties["door"], "gate");
+        // tile objects are anchored at their bottom
+        assert_eq!(map.objects[1].cell, IVec2::new(2, 1));
+        // the lever operates the door it names
+        assert_eq!(
+            map.grid.object(IVec2::new(2, 1)),
+            Some(Interactable::Lever {
+                on: false,
+                door: Some(IVec2::new(1, 0)),
+            })
+        );
+    }
+
+    #[test]
+    fn ascii_reads_cells_and_markers_without_the_shared_indentation() {
+        let map = AuthoredMap::from_ascii(
+            "
+                #####
+                #@.G#
+                  ~^+
+            ",
+        )
+        .unwrap();
+
+        assert_eq!((map.grid.width(), map.grid.height()), (5, 3));
+        assert!(map.grid.is_blocked(IVec2::new(0, 0)));
+        assert!(map.grid.is_walkable(IVec2::new(2, 1)));
+        // deeper indentation is empty space
+        let empty = map.grid.get(IVec2::new(0, 2)).unwrap();
+        assert!(!empty.walkable && !empty.blocked);
+        assert_eq!(map.grid.terrain(IVec2::new(2, 2)), Terrain::Water);
+        assert_eq!(map.grid.terrain(IVec2::new(3, 2)), Terrain::Difficult);
+
+        assert_eq!(map.objects[0].name, "start");
+        assert_eq!(map.objects[0].cell, IVec2::new(1, 1));
+        assert_eq!(map.objects[1].kind, "spawn");
+        assert_eq!(map.objects[1].name, "G");
+        assert_eq!(map.objects[2].kind, "door");
+        assert_eq!(
+            map.grid.object(IVec2::new(4, 2)),
+            Some(Interactable::Door(DoorState::Closed))
+        );
+    }
+
+    #[test]
+    fn ascii_rejects_unknown_characters() {
+        let error = AuthoredMap::from_ascii("\n  #?#\n").err();
+        assert_eq!(
+            error.as_deref(),
+            Some("unknown character '?' at line 1, column 4")
+        );
+    }
+
+    #[test]
+    fn reachable_cells_go_around_walls_and_pay_for_terrain() {
+        let grid = grid(
+            "
+            #######
+            #@.#..#
+            #..#^~#
+            #.....#
+            #######
+            ",
+        );
+        let mut token = token();
+        token.grid_pos = IVec2::new(1, 1);
+        token.movement_ft = 30.0;
+        let cost = |cells: &HashMap<IVec2, ReachableCell>, x, y| {
+            cells.get(&IVec2::new(x, y)).map(|cell| cell.cost_ft)
+        };
+
+        let cells = get_reachable_cells(&token, &grid);
+        assert_eq!(cost(&cells, 1, 1), None);
+        assert_eq!(cost(&cells, 2, 1), Some(5.0));
+        assert_eq!(cost(&cells, 3, 1), None);
+        // two diagonals, then a diagonal into difficult terrain
+        assert!((cost(&cells, 4, 2).unwrap() - 7.07 * 4.0).abs() < 0.01);
+        assert_eq!(cells[&IVec2::new(4, 2)].from, IVec2::new(3, 3));
+        // out of the movement budget
+        assert_eq!(cost(&cells, 4, 1), None);
+        assert_eq!(cost(&cells, 5, 2), None);
+
+        token.movement_ft = 35.0;
+        let cells = get_reachable_cells(&token, &grid);
+        assert!((cost(&cells, 4, 1).unwrap() - (7.07 * 4.0 + 5.0)).abs() < 0.01);
+        assert!((cost(&cells, 5, 2).unwrap() - (7.07 * 2.0 + 5.0 + 7.07 * 2.0)).abs() < 0.01);
+    }
+
+    #[test]
+    fn line_of_sight_is_blocked_by_walls_and_closed_doors() {
+        let mut grid = grid(
+            "
+            #########
+            #...#...#
+            #.......#
+            #...+...#
+            #########
+            ",
+        );
+        assert!(!has_line_of_sight(
+            &grid,
+            IVec2::new(1, 1),
+            IVec2::new(7, 1)
+        ));
+        assert!(has_line_of_sight(&grid, IVec2::new(1, 2), IVec2::new(7, 2)));
+        assert!(has_line_of_sight(&grid, IVec2::new(1, 1), IVec2::new(7, 3)));
+        // the end points themselves aren't checked
+        assert!(has_line_of_sight(&grid, IVec2::new(1, 1), IVec2::new(4, 1)));
+
+        assert!(!has_line_of_sight(
+            &grid,
+            IVec2::new(1, 3),
+            IVec2::new(7, 3)
+        ));
+        grid.get_mut(IVec2::new(4, 3)).unwrap().object = Some(Interactable::Door(DoorState::Open));
+        assert!(has_line_of_sight(&grid, IVec2::new(1, 3), IVec2::new(7, 3)));
+    }
+
+    fn trap() -> Trap {
+        Trap {
+            hidden: true,
+            armed: true,
+            find_dc: 14,
+            disarm_dc: 15,
+            save: Ability::Dexterity,
+            save_dc: 30,
+            damage: "2d10".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn locked_doors_open_only_for_a_check_that_meets_their_dc() {
+        let statblock = statblock("");
+        let thief = token();
+        let mut door = Interactable::Door(DoorState::Locked { dc: 30 });
+        for seed in 0..10 {
+            let interaction = interact(&mut door, &thief, &statblock, &[], &mut Dice::new(seed));
+            assert_eq!(interaction, Interaction::Failed);
+        }
+        assert_eq!(door, Interactable::Door(DoorState::Locked { dc: 30 }));
+
+        let mut door = Interactable::Door(DoorState::Locked { dc: 1 });
+        let interaction = interact(&mut door, &thief, &statblock, &[], &mut Dice::new(0));
+        assert_eq!(interaction, Interaction::Used);
+        assert_eq!(door, Interactable::Door(DoorState::Open));
+    }
+
+    #[test]
+    fn sprung_traps_deal_their_damage_and_disarm_themselves() {
+        let statblock = statblock("");
+        let victim = token();
+        let mut trap = trap();
+
+        let mut expected = Dice::new(4);
+        let damage = expected.roll(10) + expected.roll(10);
+        let taken = spring_trap(&mut trap, &victim, &statblock, &[], &mut Dice::new(4));
+        assert_eq!(taken, damage);
+        assert!(!trap.armed);
+        assert!(!trap.hidden);
+
+        // nothing left to disarm
+        let mut object = Interactable::Trap(trap);
+        let interaction = interact(&mut object, &victim, &statblock, &[], &mut Dice::new(4));
+        assert_eq!(interaction, Interaction::Nothing);
+
+        // a successful save halves the damage
+        let mut trap = Trap {
+            save_dc: -10,
+            ..trap
+        };
+        let taken = spring_trap(&mut trap, &victim, &statblock, &[], &mut Dice::new(4));
+        assert_eq!(taken, damage / 2);
+    }
+
+    #[test]
+    fn hidden_traps_are_found_by_a_passive_perception_meeting_their_dc() {
+        let scout = statblock("[abilities]\nwisdom = 14\n[skills]\nperception = \"proficient\"");
+        let trap = trap();
+        assert!(finds_trap(&scout, &trap, LightLevel::Bright));
+        assert!(!finds_trap(&scout, &trap, LightLevel::Dim));
+        assert!(!finds_trap(&scout, &trap, LightLevel::Dark));
+        assert!(!finds_trap(
+            &scout,
+            &Trap {
+                find_dc: 15,
+                ..trap
+            },
+            LightLevel::Bright
+        ));
+        assert!(!finds_trap(&statblock(""), &trap, LightLevel::Bright));
+    }
+
+    #[test]
+    fn field_of_view_stops_at_walls_and_closed_doors() {
+        let mut grid = grid(
+            "
+            #######
+            #.@.#.#
+            ###+###
+            #.....#
+            #######
+            ",
+        );
+        let origin = IVec2::new(2, 1);
+        let visible = field_of_view(&grid, origin, 60.0);
+        assert!(visible.contains(&origin));
+        // walls and doors are seen, what is behind them isn't
+        assert!(visible.contains(&IVec2::new(4, 1)));
+        assert!(!visible.contains(&IVec2::new(5, 1)));
+        assert!(visible.contains(&IVec2::new(3, 2)));
+        assert!(!visible.contains(&IVec2::new(3, 3)));
+
+        grid.get_mut(IVec2::new(3, 2)).unwrap().object = Some(Interactable::Door(DoorState::Open));
+        let visible = field_of_view(&grid, origin, 60.0);
+        assert!(visible.contains(&IVec2::new(3, 3)));
+        assert!(!visible.contains(&IVec2::new(5, 1)));
+    }
+
+    #[test]
+    fn field_of_view_ends_at_the_radius() {
+        let grid = grid(
+            "
+            .......
+            .......
+            .......
+            ",
+        );
+        let visible = field_of_view(&grid, IVec2::new(0, 1), 10.0);
+        assert!(visible.contains(&IVec2::new(2, 1)));
+        assert!(!visible.contains(&IVec2::new(3, 1)));
+        assert!(field_of_view(&grid, IVec2::new(-1, 0), 10.0).is_empty());
+    }
+
+    #[test]
+    fn vision_reveals_newly_explored_cells_and_hostiles_coming_into_view() {
+        let vision = |visible: &[(i32, i32)], explored: usize| FactionVision {
+            visible: visible.iter().map(|&(x, y)| IVec2::new(x, y)).collect(),
+            explored: (0..explored as i32).map(|x| IVec2::new(x, 9)).collect(),
+            hidden: HashSet::new(),
+        };
+        let goblin = Entity::from_raw(1);
+        let old = vision(&[(0, 0), (1, 0)], 2);
+
+        assert!(!vision_reveals(&old, &old, &[(goblin, IVec2::new(1, 0))]));
+        assert!(vision_reveals(&old, &vision(&[(0, 0), (1, 0)], 3), &[]));
+        // the goblin steps into view, or the party steps around the corner
+        let hostiles = [(goblin, IVec2::new(2, 0))];
+        assert!(!vision_reveals(&old, &old, &hostiles));
+        assert!(vision_reveals(
+            &old,
+            &vision(&[(0, 0), (1, 0), (2, 0)], 2),
+            &hostiles
+        ));
+
+        // unless the goblin is hidden
+        let mut hiding = vision(&[(0, 0), (1, 0), (2, 0)], 2);
+        hiding.hidden.insert(goblin);
+        assert!(!vision_reveals(&old, &hiding, &hostiles));
+        assert!(vision_reveals(&hiding, &vision(&[(2, 0)], 2), &hostiles));
+    }
+
+    #[test]
+    fn hiding_needs_concealment_and_a_stealth_meeting_passive_perception() {
+        let mut grid = grid(
+            "
+            .......
+            .....#.
+            .......
+            ",
+        );
+        grid.get_mut(IVec2::new(3, 0)).unwrap().light = LightLevel::Dim;
+        grid.get_mut(IVec2::new(3, 2)).unwrap().light = LightLevel::Dark;
+        let guard = statblock("[abilities]\nwisdom = 14\n[skills]\nperception = \"proficient\"");
+        let guard_at = IVec2::new(0, 1);
+        let hides = |stealth, cell| hides_from(&grid, stealth, &guard, guard_at, cell);
+
+        // in plain sight, however stealthy
+        assert!(!hides(30, IVec2::new(4, 2)));
+        // behind cover, out of sight, in dim light or in darkness
+        for cell in [IVec2::new(6, 2), IVec2::new(6, 1)] {
+            assert!(hides(14, cell));
+            assert!(!hides(13, cell));
+        }
+        assert!(hides(9, IVec2::new(3, 0)));
+        assert!(!hides(8, IVec2::new(3, 0)));
+        assert!(hides(1, IVec2::new(3, 2)));
+    }
+
+    #[test]
+    fn checks_add_the_ability_and_proficiency_bonuses() {
+        let rogue = statblock(
+            "[abilities]\ndexterity = 16\nwisdom = 14\n[skills]\nstealth = \"expertise\"\nperception = \"proficient\"",
+        );
+        let check =
+            |check, light| roll_check(&token(), &rogue, &[], check, light, &mut Dice::new(7));
+
+        let stealth = check(Check::Skill(Skill::Stealth), None);
+        assert!(has_modifier(&stealth, "Dexterity", ModifierKind::Bonus(3)));
+        assert!(has_modifier(
+            &stealth,
+            "proficiency",
+            ModifierKind::Bonus(4)
+        ));
+        assert_eq!(stealth.total, stealth.natural + 7);
+        assert!(stealth.meets(stealth.total));
+        assert!(!stealth.meets(stealth.total + 1));
+
+        // no proficiency, no bonus for it
+        let athletics = check(Check::Skill(Skill::Athletics), None);
+        assert!(athletics.modifiers.is_empty());
+        assert_eq!(athletics.total, athletics.natural);
+
+        // looking around in dim light
+        let perception = check(Check::Skill(Skill::Perception), Some(LightLevel::Dim));
+        assert!(has_modifier(
+            &perception,
+            "proficiency",
+            ModifierKind::Bonus(2)
+        ));
+        assert!(has_modifier(
+            &perception,
+            "dim light",
+            ModifierKind::Disadvantage
+        ));
+        let perception = check(Check::Skill(Skill::Perception), Some(LightLevel::Bright));
+        assert!(!has_modifier(
+            &perception,
+            "dim light",
+            ModifierKind::Disadvantage
+        ));
+    }
+
+    #[test]
+    fn checks_about_objects_are_made_next_to_them_against_their_dc() {
+        let locked = Interactable::Door(DoorState::Locked { dc: 18 });
+        assert_eq!(
+            object_check(&locked),
+            Some((Check::Skill(Skill::Athletics), 18))
+        );
+        assert_eq!(
+            object_check(&Interactable::Trap(trap())),
+            Some((Check::Skill(Skill::Investigation), 14))
+        );
+        let found = Trap {
+            hidden: false,
+            ..trap()
+        };
+        assert_eq!(object_check(&Interactable::Trap(found)), None);
+        assert_eq!(object_check(&Interactable::Door(DoorState::Closed)), None);
+
+        let mut grid = grid(
+            "
+            .....
+            .....
+            ",
+        );
+        grid.get_mut(IVec2::new(2, 0)).unwrap().object = Some(locked);
+        let door = Some(IVec2::new(2, 0));
+        assert_eq!(check_dc(&grid, IVec2::new(1, 1), door, 10), Some(18));
+        assert_eq!(check_dc(&grid, IVec2::new(4, 1), door, 10), None);
+        // nothing there, or no object at all, keeps the DC
+        assert_eq!(
+            check_dc(&grid, IVec2::new(1, 1), Some(IVec2::new(1, 0)), 10),
+            Some(10)
+        );
+        assert_eq!(check_dc(&grid, IVec2::new(4, 1), None, 10), Some(10));
+    }
+
+    #[test]
+    fn successful_checks_open_doors_and_reveal_traps() {
+        let mut door = Interactable::Door(DoorState::Locked { dc: 18 });
+        assert!(!apply_check(&mut door, Check::Skill(Skill::Stealth)));
+        assert_eq!(door, Interactable::Door(DoorState::Locked { dc: 18 }));
+        assert!(apply_check(&mut door, Check::Ability(Ability::Strength)));
+        assert_eq!(door, Interactable::Door(DoorState::Open));
+        assert!(!apply_check(&mut door, Check::Skill(Skill::Athletics)));
+
+        let mut object = Interactable::Trap(trap());
+        assert!(!apply_check(&mut object, Check::Skill(Skill::Athletics)));
+        assert!(apply_check(&mut object, Check::Skill(Skill::Perception)));
+        let Interactable::Trap(trap) = object else {
+            unreachable!();
+        };
+        assert!(!trap.hidden);
+        assert!(trap.armed);
+    }
+
+    #[test]
+    fn xp_budget_sums_the_thresholds_of_the_party() {
+        assert_eq!(xp_budget(&[1, 1, 1, 1], Difficulty::Easy), 100);
+        assert_eq!(xp_budget(&[1, 1, 1, 1], Difficulty::Medium), 200);
+        assert_eq!(xp_budget(&[3, 3, 3, 3], Difficulty::Hard), 900);
+        assert_eq!(xp_budget(&[5, 5, 4], Difficulty::Deadly), 2700);
+        assert_eq!(xp_budget(&[20], Difficulty::Deadly), 12700);
+        // levels out of the table count as the nearest level in it
+        assert_eq!(xp_budget(&[0, 25], Difficulty::Medium), 50 + 5700);
+        assert_eq!(xp_budget(&[], Difficulty::Medium), 0);
+    }
+
+    #[test]
+    fn adjusted_xp_multiplies_by_the_number_of_monsters_and_the_party_size() {
+        for (monsters, multiplier) in [
+            (1, 1.0),
+            (2, 1.5),
+            (3, 2.0),
+            (6, 2.0),
+            (7, 2.5),
+            (10, 2.5),
+            (11, 3.0),
+            (14, 3.0),
+            (15, 4.0),
+        ] {
+            let xp = vec![100; monsters];
+            let expected = (100.0 * monsters as f32 * multiplier) as u32;
+            assert_eq!(adjusted_xp(&xp, 4), expected, "{} monsters", monsters);
+        }
+        // small parties use the next multiplier up, large ones the next one down
+        assert_eq!(adjusted_xp(&[100], 2), 150);
+        assert_eq!(adjusted_xp(&[100; 15], 1), 7500);
+        assert_eq!(adjusted_xp(&[100], 6), 50);
+        assert_eq!(adjusted_xp(&[100, 100], 6), 200);
+        assert_eq!(adjusted_xp(&[], 4), 0);
+    }
+
+    #[test]
+    fn monster_xp_follows_the_challenge_rating() {
+        let xp = |challenge_rating: &str| {
+            monster_xp(&statblock(&format!(
+                "challenge_rating = {}",
+                challenge_rating
+            )))
+        };
+        assert_eq!(monster_xp(&statblock("")), 0);
+        assert_eq!(xp("0.0"), 10);
+        assert_eq!(xp("0.125"), 25);
+        assert_eq!(xp("0.25"), 50);
+        assert_eq!(xp("0.5"), 100);
+        assert_eq!(xp("1.0"), 200);
+        assert_eq!(xp("5.0"), 1800);
+        assert_eq!(xp("13.0"), 10000);
+        assert_eq!(xp("30.0"), 155000);
+    }
+
+    #[test]
+    fn level_for_xp_follows_the_advancement_table() {
+        assert_eq!(level_for_xp(0), 1);
+        assert_eq!(level_for_xp(299), 1);
+        assert_eq!(level_for_xp(300), 2);
+        assert_eq!(level_for_xp(6499), 4);
+        assert_eq!(level_for_xp(6500), 5);
+        assert_eq!(level_for_xp(u32::MAX), 20);
+    }
+
+    #[test]
+    fn xp_share_splits_the_xp_evenly() {
+        assert_eq!(xp_share(&[50, 50, 100], 3), 66);
+        assert_eq!(xp_share(&[50, 50, 100], 0), 0);
+        assert_eq!(xp_share(&[], 2), 0);
+    }
+
+    #[test]
+    fn level_up_grants_hit_points_and_the_class_table() {
+        let class: ClassDef =
+            toml::from_str(include_str!("../../fivee/assets/classes/fighter.toml")).unwrap();
+        let mut sheet: CharacterSheet = toml::from_str(
+            r#"
+            name = "William"
+            class = "fighter"
+            hit_die = 10
+            features = ["second_wind"]
+
+            [abilities]
+            constitution = 14
+            "#,
+        )
+        .unwrap();
+        let mut token = token();
+        let mut dice = Dice::new(5);
+
+        // the average of a d10 and the Constitution modifier
+        assert_eq!(level_up(&mut sheet, &class, &mut token, &mut dice), 8);
+        assert_eq!(sheet.level, 2);
+        assert_eq!(sheet.hit_die_rolls, vec![6]);
+        assert_eq!(token.hit_points, 18);
+        assert_eq!(token.hit_dice, 1);
+        assert!(sheet.features.iter().any(|id| id == "action_surge"));
+        assert_eq!(token.limited_uses.get("Action Surge"), Some(&1));
+
+        sheet.roll_hit_points = true;
+        let rolled = Dice::new(5).roll(10);
+        let gained = level_up(&mut sheet, &class, &mut token, &mut Dice::new(5));
+        assert_eq!(gained, (rolled + 2) as u32);
+        assert_eq!(sheet.hit_die_rolls, vec![6, rolled as u32]);
+        assert_eq!(character_hit_points(&sheet), 12 + 8 + gained);
+    }
+
+    #[test]
+    fn sneak_attack_scales_with_level_and_needs_its_requirements() {
+        let sneak_attack = feature(include_str!(
+            "../../fivee/assets/features/sneak_attack.toml"
+        ));
+        let features = [("sneak_attack", &sneak_attack)];
+        let statblock = statblock("hit_dice = 3");
+        let dagger = attack("1d4", 0, vec![WeaponProperty::Finesse]);
+        let extra = |dice: &str| ModifierKind::Dice {
+            dice: dice.parse().unwrap(),
+            penalty: false,
+        };
+
+        let mut rogue = token();
+        let roll = damage(&mut rogue, &statblock, &features, &dagger, false, true);
+        assert!(has_modifier(&roll, "Sneak Attack", extra("2d6")));
+        // once per turn
+        let roll = damage(&mut rogue, &statblock, &features, &dagger, false, true);
+        assert!(roll.modifiers.is_empty());
+
+        let mut rogue = token();
+        let roll = damage(&mut rogue, &statblock, &features, &dagger, true, true);
+        assert_eq!(roll.dice, "2d4".parse().unwrap());
+        assert!(has_modifier(&roll, "Sneak Attack", extra("4d6")));
+
+        // no finesse, and no ally next to the target
+        let club = attack("1d4", 0, Vec::new());
+        let mut rogue = token();
+        let roll = damage(&mut rogue, &statblock, &features, &club, false, true);
+        assert!(roll.modifiers.is_empty());
+        let roll = damage(&mut rogue, &statblock, &features, &dagger, false, false);
+        assert!(roll.modifiers.is_empty());
+    }
+
+    #[test]
+    fn rage_lasts_its_rounds_and_halves_damage() {
+        let rage = feature(include_str!("../../fivee/assets/features/rage.toml"));
+        let features = [("rage", &rage)];
+        let statblock = statblock("");
+        let mut barbarian = token();
+        barbarian.limited_uses.insert("Rage".into(), 1);
+        let mut dice = Dice::new(1);
+
+        assert_eq!(damage_taken(&barbarian, &features, 9), 9);
+        assert_eq!(
+            use_feature(&mut barbarian, &statblock, "rage", &rage, true, &mut dice),
+            Some(0)
+        );
+        assert_eq!(barbarian.bonus_actions, 0);
+        assert_eq!(barbarian.limited_uses.get("Rage"), Some(&0));
+        assert_eq!(damage_taken(&barbarian, &features, 9), 4);
+        let axe = attack("1d12", 0, Vec::new());
+        let roll = damage(&mut barbarian, &statblock, &features, &axe, false, false);
+        assert!(has_modifier(&roll, "Rage", ModifierKind::Bonus(2)));
+        // no uses left
+        assert_eq!(
+            use_feature(&mut barbarian, &statblock, "rage", &rage, false, &mut dice),
+            None
+        );
+
+        for _ in 0..10 {
+            start_turn_features(&mut barbarian, &statblock, &features, &mut dice);
+        }
+        assert!(barbarian.active_features.is_empty());
+        assert_eq!(damage_taken(&barbarian, &features, 9), 9);
+    }
+
+    #[test]
+    fn second_wind_heals_its_dice_and_the_level() {
+        let second_wind = feature(include_str!("../../fivee/assets/features/second_wind.toml"));
+        let statblock = statblock("hit_points = 30\nhit_dice = 4");
+        let mut fighter = token();
+        fighter.hit_points = 5;
+        fighter.limited_uses.insert("Second Wind".into(), 1);
+
+        let expected = Dice::new(9).roll(10) + 4;
+        let healed = use_feature(
+            &mut fighter,
+            &statblock,
+            "second_wind",
+            &second_wind,
+            false,
+            &mut Dice::new(9),
+        );
+        assert_eq!(healed, Some(expected));
+        assert_eq!(fighter.hit_points, 5 + expected);
+    }
+
+    #[test]
+    fn unarmored_defense_adds_its_ability_without_armor() {
+        let unarmored_defense = feature(include_str!(
+            "../../fivee/assets/features/unarmored_defense.toml"
+        ));
+        let features = [("unarmored_defense", &unarmored_defense)];
+        let statblock =
+            statblock("armor_class = 12\n[abilities]\ndexterity = 14\nconstitution = 16");
+        let shield: Item =
+            toml::from_str(include_str!("../../fivee/assets/items/shield.toml")).unwrap();
+        let chain_mail: Item =
+            toml::from_str(include_str!("../../fivee/assets/items/chain_mail.toml")).unwrap();
+
+        assert_eq!(armor_class(&statblock, &[], &[]), 12);
+        assert_eq!(armor_class(&statblock, &features, &[]), 15);
+        assert_eq!(
+            armor_class(&statblock, &features, &[("shield", &shield)]),
+            17
+        );
+        assert_eq!(
+            armor_class(&statblock, &features, &[("chain_mail", &chain_mail)]),
+            16
+        );
+    }
+
+    #[test]
+    fn rerolls_apply_to_the_rolls_their_features_name() {
+        let great_weapon_fighting = feature(include_str!(
+            "../../fivee/assets/features/great_weapon_fighting.toml"
+        ));
+        let lucky = feature(include_str!("../../fivee/assets/features/lucky.toml"));
+        let features = [
+            ("great_weapon_fighting", &great_weapon_fighting),
+            ("lucky", &lucky),
+        ];
+        let statblock = statblock("");
+        let mut fighter = token();
+        let mut dice = Dice::new(1);
+        let great_weapon = ModifierKind::Reroll { at_most: 2 };
+        let luck = ModifierKind::Reroll { at_most: 1 };
+
+        let greatsword = attack("2d6", 0, Vec::new());
+        let roll = damage(
+            &mut fighter,
+            &statblock,
+            &features,
+            &greatsword,
+            false,
+            false,
+        );
+        assert!(has_modifier(&roll, "Great Weapon Fighting", great_weapon));
+        assert!(!has_modifier(&roll, "Lucky", luck));
+
+        // only melee damage
+        let longbow = attack("1d8", 150, Vec::new());
+        let roll = damage(&mut fighter, &statblock, &features, &longbow, false, false);
+        assert!(roll.modifiers.is_empty());
+
+        let check = Check::Skill(Skill::Athletics);
+        let roll = roll_check(&fighter, &statblock, &features, check, None, &mut dice);
+        assert!(has_modifier(&roll, "Lucky", luck));
+        assert!(!has_modifier(&roll, "Great Weapon Fighting", great_weapon));
+    }
+}
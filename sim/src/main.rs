@@ -0,0 +1,160 @@
+use std::time::Duration;
+
+use bevy::{prelude::*, time::TimeUpdateStrategy, utils::HashMap};
+use common::{Dice, Encounter, GameEvent, Outcome, Round, SelectedEncounter, Token};
+use serde::Deserialize;
+
+/// How many encounters to simulate and when to give up on one.
+#[derive(Deserialize)]
+#[serde(default)]
+struct SimConfig {
+    /// File in `encounters/` to simulate.
+    encounter: String,
+    /// Overrides the seed of a generated map.
+    map_seed: Option<u64>,
+    runs: u64,
+    /// Seed of the first run, each following run uses the next one.
+    seed: u64,
+    max_rounds: u64,
+    max_updates: u64,
+    /// Relative to this crate when started with `cargo run`, else to the executable.
+    asset_folder: String,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            encounter: "goblin_ambush".into(),
+            map_seed: None,
+            runs: 100,
+            seed: 1,
+            max_rounds: 20,
+            max_updates: 20000,
+            asset_folder: "../fivee/assets".into(),
+        }
+    }
+}
+
+/// Damage dealt during a run, by the faction of the attacker.
+#[derive(Resource, Default)]
+struct DamageDealt(HashMap<String, i64>);
+
+struct RunResult {
+    outcome: Option<Outcome>,
+    rounds: u64,
+    damage: HashMap<String, i64>,
+}
+
+/// No one is at the controls, so every token is left to the AI.
+fn autopilot_system(mut tokens: Query<&mut Token, Added<Token>>) {
+    for mut token in tokens.iter_mut() {
+        token.player = None;
+    }
+}
+
+fn tally_damage_system(
+    mut reader: EventReader<GameEvent>,
+    tokens: Query<&Token>,
+    mut damage: ResMut<DamageDealt>,
+) {
+    for ev in reader.iter() {
+        let GameEvent::Attacked {
+            attacker, damage: dealt, ..
+        } = ev
+        else {
+            continue;
+        };
+        let Ok(attacker) = tokens.get(*attacker) else {
+            continue;
+        };
+        *damage.0.entry(rules::faction(attacker).to_string()).or_default() += *dealt as i64;
+    }
+}
+
+fn run(config: &SimConfig, seed: u64) -> RunResult {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        .add_plugins(AssetPlugin {
+            asset_folder: config.asset_folder.clone(),
+            watch_for_changes: None,
+        })
+        .add_plugins(common::CommonPlugin)
+        .add_plugins(plugin_game::PluginGame)
+        .add_plugins(plugin_ai::PluginAI)
+        // every update plays out at least a whole command
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs(1)))
+        .insert_resource(Dice::new(seed))
+        .insert_resource(SelectedEncounter {
+            id: config.encounter.clone(),
+            map_seed: config.map_seed,
+        })
+        .init_resource::<DamageDealt>()
+        .add_systems(PreUpdate, autopilot_system)
+        .add_systems(PostUpdate, tally_damage_system);
+    app.finish();
+    app.cleanup();
+
+    for _ in 0..config.max_updates {
+        app.update();
+        let encounter = app.world.resource::<Encounter>();
+        let round = app.world.resource::<Round>();
+        if encounter.outcome.is_some() || round.round_num > config.max_rounds {
+            break;
+        }
+    }
+
+    RunResult {
+        outcome: app.world.resource::<Encounter>().outcome,
+        rounds: app.world.resource::<Round>().round_num,
+        damage: std::mem::take(&mut app.world.resource_mut::<DamageDealt>().0),
+    }
+}
+
+fn main() {
+    let config = match std::env::args().nth(1) {
+        Some(path) => {
+            let config = std::fs::read_to_string(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|s| toml::from_str::<SimConfig>(&s).map_err(|err| err.to_string()));
+            match config {
+                Ok(config) => config,
+                Err(err) => {
+                    eprintln!("failed to read config {}: {}", path, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => SimConfig::default(),
+    };
+
+    let mut victories = 0;
+    let mut defeats = 0;
+    let mut rounds = 0;
+    let mut damage: HashMap<String, i64> = HashMap::new();
+    for i in 0..config.runs {
+        let result = run(&config, config.seed + i);
+        match result.outcome {
+            Some(Outcome::Victory) => victories += 1,
+            Some(Outcome::Defeat) => defeats += 1,
+            None => {}
+        }
+        rounds += result.rounds;
+        for (faction, dealt) in result.damage {
+            *damage.entry(faction).or_default() += dealt;
+        }
+    }
+
+    let runs = config.runs.max(1) as f32;
+    let undecided = config.runs - victories - defeats;
+    println!("runs:       {}", config.runs);
+    println!("victories:  {} ({:.1}%)", victories, victories as f32 / runs * 100.0);
+    println!("defeats:    {} ({:.1}%)", defeats, defeats as f32 / runs * 100.0);
+    println!("undecided:  {} ({:.1}%)", undecided, undecided as f32 / runs * 100.0);
+    println!("avg rounds: {:.2}", rounds as f32 / runs);
+    println!("avg damage dealt:");
+    let mut damage = damage.into_iter().collect::<Vec<_>>();
+    damage.sort();
+    for (faction, dealt) in damage {
+        println!("  {:<10} {:.2}", faction, dealt as f32 / runs);
+    }
+}